@@ -0,0 +1,90 @@
+//! Compares scanning a generated tree on one thread with scanning it on
+//! several, and checks that both give the same tree.
+//!
+//! Run with `cargo bench`. The tree is made in the system's temporary
+//! directory unless a directory to scan is given as an argument, and the
+//! thread count can be set with `FILE_TREE_THREADS`.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs, io, process, thread};
+
+use file_tree::{FileTree, TreeBuilder};
+
+const FAN_OUT: usize = 8;
+const LEVELS: usize = 4;
+const FILES_PER_DIR: usize = 16;
+const RUNS: usize = 5;
+
+fn main() -> io::Result<()> {
+    // Skip the `--bench` flag cargo passes.
+    let dir = env::args_os().skip(1).find(|arg| arg != "--bench");
+    let (root, generated) = match dir {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => {
+            let root = env::temp_dir().join(format!("file_tree_bench_{}", process::id()));
+            let start = Instant::now();
+            let count = generate(&root, LEVELS)?;
+            println!("generated {} entries in {:?}", count, start.elapsed());
+            (root, true)
+        }
+    };
+    let threads = env::var("FILE_TREE_THREADS")
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |count| count.get()));
+
+    let (serial, serial_time) = scan(&root, 1);
+    let (parallel, parallel_time) = scan(&root, threads);
+    println!("threads: 1, time: {:?}", serial_time);
+    println!("threads: {}, time: {:?}", threads, parallel_time);
+    println!(
+        "speedup: {:.2}x",
+        serial_time.as_secs_f64() / parallel_time.as_secs_f64()
+    );
+
+    if generated {
+        fs::remove_dir_all(&root)?;
+    }
+    if serial.to_json() != parallel.to_json() {
+        eprintln!("the parallel scan gave a different tree");
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Fills `dir` with `FAN_OUT` directories nested `levels` deep, each holding
+/// files of varied sizes and one hard link, returning how many entries were
+/// made.
+fn generate(dir: &Path, levels: usize) -> io::Result<usize> {
+    fs::create_dir_all(dir)?;
+    let mut count = 0;
+    for i in 0..FILES_PER_DIR {
+        fs::write(dir.join(format!("file{}", i)), vec![b'x'; i * 100 + levels])?;
+        count += 1;
+    }
+    fs::hard_link(dir.join("file0"), dir.join("link"))?;
+    count += 1;
+    if levels > 0 {
+        for i in 0..FAN_OUT {
+            count += 1 + generate(&dir.join(format!("dir{}", i)), levels - 1)?;
+        }
+    }
+    Ok(count)
+}
+
+/// Scans `root` `RUNS` times, returning the tree and the fastest time.
+fn scan(root: &Path, threads: usize) -> (FileTree, Duration) {
+    let mut best = Duration::MAX;
+    let mut tree = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let scanned = TreeBuilder::new(root)
+            .threads(threads)
+            .build()
+            .unwrap_or_else(|error| panic!("scan failed: {}", error));
+        best = best.min(start.elapsed());
+        tree = Some(scanned);
+    }
+    (tree.unwrap(), best)
+}
@@ -0,0 +1,611 @@
+use std::collections::{btree_map, BTreeMap};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fmt, mem};
+
+use crate::entry::{Entry, EntryKind, SizeKind};
+use crate::json::kind_name;
+use crate::moves::{self, Moves};
+use crate::render::{Branches, RenderOptions, SizeFormat, SizeUnits};
+use crate::tree::FileTree;
+use crate::visit;
+
+/// The differences between two scans of a tree, from
+/// [`FileTree::diff`](crate::FileTree::diff).
+pub struct TreeDiff {
+    old_root: PathBuf,
+    new_root: PathBuf,
+    root: DiffEntry,
+}
+
+/// An entry present in either tree, matched up by name.
+pub struct DiffEntry {
+    name: OsString,
+    before: Option<EntryState>,
+    after: Option<EntryState>,
+    change: Change,
+    changes: Changes,
+    moved: Option<Move>,
+    children: Vec<DiffEntry>,
+}
+
+/// A [`DiffEntry`] whose children are still being compared.
+struct Pending<'a> {
+    name: OsString,
+    before: Option<EntryState>,
+    after: Option<EntryState>,
+    moved: Option<Move>,
+    /// The entries below it in either tree, by name, still to compare.
+    matched: btree_map::IntoIter<&'a OsStr, (Option<&'a Entry>, Option<&'a Entry>)>,
+    children: Vec<DiffEntry>,
+    /// The lengths of the two paths without its name.
+    lens: (usize, usize),
+    /// The old path it replaced, if it moved from somewhere else.
+    old_path: Option<PathBuf>,
+}
+
+/// How to compare two trees.
+#[derive(Clone, Debug)]
+pub struct DiffOptions {
+    /// Whether to pair removed entries with added ones that hold the same
+    /// content, reporting them as [`Change::Moved`] instead.
+    pub detect_moves: bool,
+    /// Whether to read files that were scanned without
+    /// [`WalkOptions::hash_contents`](crate::WalkOptions::hash_contents) to
+    /// hash them, from below each tree's root path. Only files with the same
+    /// size as one on the other side are read.
+    pub read_files: bool,
+    /// How alike two directories must be to count as one moved, from 0 to 1:
+    /// the share of entries below either, by relative path, kind, size and
+    /// content, that both have.
+    pub min_similarity: f64,
+}
+
+/// Where an entry moved to or from, see [`DiffEntry::moved`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Move {
+    path: PathBuf,
+    similarity: f64,
+}
+
+/// An entry as one of the two trees has it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryState {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub disk_usage: u64,
+    pub modified: Option<SystemTime>,
+    pub symlink_target: Option<PathBuf>,
+}
+
+/// How an entry differs between the two trees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Change {
+    Unchanged,
+    /// Only in the new tree.
+    Added,
+    /// Only in the old tree.
+    Removed,
+    /// In both, but the entry itself or something below it changed.
+    Modified,
+    /// Only in the new tree, but paired with an entry removed from the old
+    /// one. The entry it moved from is also listed, as removed.
+    Moved,
+}
+
+/// What changed about an entry that is in both trees. Directories are
+/// compared by their contents only, not their own size or times.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Changes {
+    pub kind: bool,
+    pub size: bool,
+    pub modified: bool,
+    pub symlink_target: bool,
+}
+
+/// How many entries changed, counting every entry below added and removed
+/// directories, and only entries that changed themselves as modified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    /// Moved entries, counting neither their old place as removed nor the
+    /// entries below them.
+    pub moved: usize,
+}
+
+/// A diff drawn with [`TreeDiff::render`].
+pub struct DiffRender<'a> {
+    diff: &'a TreeDiff,
+    options: &'a RenderOptions,
+    show_unchanged: bool,
+}
+
+impl TreeDiff {
+    pub(crate) fn new(old: &FileTree, new: &FileTree, options: &DiffOptions) -> Self {
+        let moves = if options.detect_moves {
+            moves::find_moves(old, new, options)
+        } else {
+            Moves::default()
+        };
+        TreeDiff {
+            old_root: old.root_path().to_path_buf(),
+            new_root: new.root_path().to_path_buf(),
+            root: DiffEntry::new(old.root(), new.root(), &moves),
+        }
+    }
+
+    pub fn old_root(&self) -> &Path {
+        &self.old_root
+    }
+
+    pub fn new_root(&self) -> &Path {
+        &self.new_root
+    }
+
+    /// The entry comparing the two scanned directories themselves.
+    pub fn root(&self) -> &DiffEntry {
+        &self.root
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.change == Change::Unchanged
+    }
+
+    pub fn summary(&self) -> DiffSummary {
+        let mut summary = DiffSummary::default();
+        self.root.count(&mut summary);
+        summary
+    }
+
+    /// Draws the diff as a tree, with a column of size changes using
+    /// `options.sizes` (or IEC units if unset) and `options.size_kind`.
+    /// Unchanged entries are collapsed into a count.
+    pub fn render<'a>(&'a self, options: &'a RenderOptions) -> DiffRender<'a> {
+        DiffRender {
+            diff: self,
+            options,
+            show_unchanged: false,
+        }
+    }
+}
+
+impl DiffEntry {
+    /// Compares `old` with `new`, the roots of the two trees. Entries being
+    /// compared are kept on a stack rather than the call stack, so that
+    /// trees of any depth can be compared.
+    fn new<'a>(old: &'a Entry, new: &'a Entry, moves: &Moves<'a>) -> Self {
+        // The paths of the entries being compared, relative to the roots.
+        let mut old_path = PathBuf::new();
+        let mut new_path = PathBuf::new();
+        let mut stack = vec![Pending::new(
+            Some(old),
+            Some(new),
+            &mut old_path,
+            &new_path,
+            moves,
+        )];
+        loop {
+            let top = stack.last_mut().unwrap();
+            if let Some((name, (old, new))) = top.matched.next() {
+                let old_len = visit::push(&mut old_path, name);
+                let new_len = visit::push(&mut new_path, name);
+                let mut child = Pending::new(old, new, &mut old_path, &new_path, moves);
+                child.lens = (old_len, new_len);
+                stack.push(child);
+                continue;
+            }
+            let mut pending = stack.pop().unwrap();
+            if let Some(path) = pending.old_path.take() {
+                old_path = path;
+            }
+            visit::truncate(&mut old_path, pending.lens.0);
+            visit::truncate(&mut new_path, pending.lens.1);
+            let entry = pending.finish();
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(entry),
+                None => return entry,
+            }
+        }
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// The entry in the old tree, unless it was added.
+    pub fn before(&self) -> Option<&EntryState> {
+        self.before.as_ref()
+    }
+
+    /// The entry in the new tree, unless it was removed.
+    pub fn after(&self) -> Option<&EntryState> {
+        self.after.as_ref()
+    }
+
+    pub fn change(&self) -> Change {
+        self.change
+    }
+
+    /// What changed about the entry itself, rather than below it.
+    pub fn changes(&self) -> Changes {
+        self.changes
+    }
+
+    /// For a [`Change::Moved`] entry, where it moved from. For a
+    /// [`Change::Removed`] one, where it moved to if it did. Paths are
+    /// relative to the roots.
+    pub fn moved(&self) -> Option<&Move> {
+        self.moved.as_ref()
+    }
+
+    /// Every entry below this one in either tree, in name order. Entries
+    /// that moved away have none, being compared where they moved to.
+    pub fn children(&self) -> &[DiffEntry] {
+        &self.children
+    }
+
+    /// How much the size of the entry grew, or shrank if negative.
+    pub fn size_delta(&self, kind: SizeKind) -> i64 {
+        let size = |state: &Option<EntryState>| {
+            state.as_ref().map_or(0, |state| match kind {
+                SizeKind::Apparent => state.size,
+                SizeKind::Allocated => state.disk_usage,
+            }) as i64
+        };
+        size(&self.after) - size(&self.before)
+    }
+
+    fn count(&self, summary: &mut DiffSummary) {
+        let mut stack = vec![self];
+        while let Some(entry) = stack.pop() {
+            match entry.change {
+                Change::Added => summary.added += 1,
+                Change::Removed if entry.moved.is_none() => summary.removed += 1,
+                Change::Moved => summary.moved += 1,
+                Change::Modified if !entry.changes.is_empty() => summary.modified += 1,
+                _ => {}
+            }
+            stack.extend(&entry.children);
+        }
+    }
+}
+
+impl<'a> Pending<'a> {
+    /// Starts comparing `old` at `old_path` with `new` at `new_path`. An
+    /// added entry that `moves` pairs with a removed one is compared with
+    /// that instead, and `old_path` is changed to where it moved from.
+    fn new(
+        old: Option<&'a Entry>,
+        new: Option<&'a Entry>,
+        old_path: &mut PathBuf,
+        new_path: &Path,
+        moves: &Moves<'a>,
+    ) -> Self {
+        let name = old.or(new).map(|entry| entry.name.clone()).unwrap();
+        let (old, moved) = match (old, new) {
+            (None, Some(_)) => match moves.to.get(new_path) {
+                Some((entry, moved)) => (Some(*entry), Some(moved.clone())),
+                None => (None, None),
+            },
+            (Some(_), None) => (old, moves.from.get(old_path.as_path()).cloned()),
+            _ => (old, None),
+        };
+        let replaced = match (&moved, new) {
+            (Some(moved), Some(_)) => Some(mem::replace(old_path, moved.path().to_path_buf())),
+            _ => None,
+        };
+
+        // What moved away is listed where it went.
+        let mut matched: BTreeMap<&OsStr, (Option<&Entry>, Option<&Entry>)> = BTreeMap::new();
+        if new.is_some() || moved.is_none() {
+            for child in old.and_then(Entry::children).unwrap_or(&[]) {
+                matched.entry(child.name()).or_default().0 = Some(child);
+            }
+            for child in new.and_then(Entry::children).unwrap_or(&[]) {
+                matched.entry(child.name()).or_default().1 = Some(child);
+            }
+        }
+
+        Pending {
+            name,
+            before: old.map(EntryState::of),
+            after: new.map(EntryState::of),
+            moved,
+            matched: matched.into_iter(),
+            children: vec![],
+            lens: (0, 0),
+            old_path: replaced,
+        }
+    }
+
+    /// Compares the entry itself once everything below it has been.
+    fn finish(self) -> DiffEntry {
+        let changes = match (&self.before, &self.after) {
+            (Some(before), Some(after)) => Changes::between(before, after),
+            _ => Changes::default(),
+        };
+        let change = match (&self.before, &self.after) {
+            (Some(_), Some(_)) if self.moved.is_some() => Change::Moved,
+            (None, _) => Change::Added,
+            (_, None) => Change::Removed,
+            _ if !changes.is_empty()
+                || self
+                    .children
+                    .iter()
+                    .any(|child| child.change != Change::Unchanged) =>
+            {
+                Change::Modified
+            }
+            _ => Change::Unchanged,
+        };
+        DiffEntry {
+            name: self.name,
+            before: self.before,
+            after: self.after,
+            change,
+            changes,
+            moved: self.moved,
+            children: self.children,
+        }
+    }
+}
+
+impl Drop for DiffEntry {
+    fn drop(&mut self) {
+        // As with entries, the diff is taken apart here rather than each
+        // level being dropped from inside the one above it.
+        let mut stack = mem::take(&mut self.children);
+        while let Some(mut entry) = stack.pop() {
+            stack.append(&mut entry.children);
+        }
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            detect_moves: false,
+            read_files: true,
+            min_similarity: 0.5,
+        }
+    }
+}
+
+impl Move {
+    pub(crate) fn new(path: PathBuf, similarity: f64) -> Self {
+        Move { path, similarity }
+    }
+
+    /// The other path of the entry, relative to the root of the other tree.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How alike the two entries are, from 0 to 1. Files are only paired
+    /// when their contents are the same, giving 1.
+    pub fn similarity(&self) -> f64 {
+        self.similarity
+    }
+}
+
+impl EntryState {
+    fn of(entry: &Entry) -> Self {
+        EntryState {
+            kind: entry.kind(),
+            size: entry.size(),
+            disk_usage: entry.disk_usage(),
+            modified: entry.modified(),
+            symlink_target: entry.symlink_target().map(Path::to_path_buf),
+        }
+    }
+}
+
+impl Changes {
+    fn between(before: &EntryState, after: &EntryState) -> Self {
+        let is_dir = |state: &EntryState| state.kind == EntryKind::Directory;
+        if is_dir(before) && is_dir(after) {
+            return Changes::default();
+        }
+        Changes {
+            kind: before.kind != after.kind,
+            size: before.size != after.size,
+            modified: before.modified.is_some()
+                && after.modified.is_some()
+                && before.modified != after.modified,
+            symlink_target: before.symlink_target != after.symlink_target,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} modified",
+            self.added, self.removed, self.modified
+        )?;
+        if self.moved > 0 {
+            write!(f, ", {} moved", self.moved)?;
+        }
+        Ok(())
+    }
+}
+
+impl Change {
+    /// The character marking entries with this change in a rendered diff.
+    fn mark(self) -> char {
+        match self {
+            Change::Unchanged => ' ',
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Modified => '~',
+            Change::Moved => '>',
+        }
+    }
+}
+
+impl<'a> DiffRender<'a> {
+    /// Lists unchanged entries too, instead of collapsing them.
+    pub fn show_unchanged(mut self, yes: bool) -> Self {
+        self.show_unchanged = yes;
+        self
+    }
+
+    fn format(&self) -> SizeFormat {
+        self.options
+            .sizes
+            .unwrap_or_else(|| SizeFormat::new(SizeUnits::Iec))
+    }
+
+    fn delta(&self, entry: &DiffEntry) -> String {
+        let delta = entry.size_delta(self.options.size_kind);
+        let format = self.format();
+        match delta {
+            0 => String::new(),
+            delta if delta > 0 => format!("+{}", format.format(delta as u64)),
+            delta => format!("-{}", format.format(delta.unsigned_abs())),
+        }
+    }
+
+    /// Whether `entry` is drawn, rather than counted among the unchanged.
+    fn shows(&self, entry: &DiffEntry) -> bool {
+        self.show_unchanged || entry.change != Change::Unchanged
+    }
+
+    fn column_width(&self, root: &DiffEntry) -> usize {
+        let mut width = 0;
+        let mut stack = vec![root];
+        while let Some(entry) = stack.pop() {
+            width = width.max(self.delta(entry).len());
+            stack.extend(entry.children.iter().filter(|child| self.shows(child)));
+        }
+        width
+    }
+
+    /// Draws the diff a line per entry. Directories being drawn are kept on
+    /// a stack rather than the call stack, so that a diff of any depth can
+    /// be drawn.
+    fn fmt_tree(&self, f: &mut fmt::Formatter, width: usize) -> fmt::Result {
+        let root = &self.diff.root;
+        let mut branches = Branches::default();
+        self.fmt_entry(root, f, width, &mut branches, 0, true)?;
+        // Each directory being drawn, with the index of its next child.
+        let mut stack = vec![(&root.children[..], 0)];
+        while let Some(&(children, i)) = stack.last() {
+            if i == children.len() {
+                stack.pop();
+                continue;
+            }
+            let depth = stack.len();
+            // Each run of unchanged entries is replaced with a count, unless
+            // they are shown.
+            let unchanged = children[i..]
+                .iter()
+                .take_while(|child| !self.shows(child))
+                .count();
+            let next = i + unchanged.max(1);
+            stack.last_mut().unwrap().1 = next;
+            let is_last = next == children.len();
+            if unchanged > 0 {
+                write!(f, "  {:width$}  ", "", width = width)?;
+                branches.write(f, depth, is_last)?;
+                writeln!(f, "\u{2026} {} unchanged", unchanged)?;
+            } else {
+                let child = &children[i];
+                self.fmt_entry(child, f, width, &mut branches, depth, is_last)?;
+                if self.shows(child) {
+                    stack.push((&child.children, 0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the line of one entry.
+    fn fmt_entry(
+        &self,
+        entry: &DiffEntry,
+        f: &mut fmt::Formatter,
+        width: usize,
+        branches: &mut Branches,
+        depth: usize,
+        is_last: bool,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>width$}  ",
+            entry.change.mark(),
+            self.delta(entry),
+            width = width
+        )?;
+        branches.write(f, depth, is_last)?;
+        write!(f, "{}", entry.name.to_string_lossy())?;
+
+        let mut notes = vec![];
+        match (&entry.moved, &entry.after) {
+            (Some(moved), Some(_)) => notes.push(format!(
+                "moved from {}, {:.0}%",
+                moved.path.display(),
+                moved.similarity * 100.0
+            )),
+            (Some(moved), None) => notes.push(format!("moved to {}", moved.path.display())),
+            _ => {}
+        }
+        if let (Some(before), Some(after)) = (&entry.before, &entry.after) {
+            let changes = entry.changes;
+            if changes.kind {
+                notes.push(format!(
+                    "{} -> {}",
+                    kind_name(before.kind),
+                    kind_name(after.kind)
+                ));
+            }
+            if changes.size && !changes.kind {
+                notes.push("size".to_string());
+            }
+            if changes.modified {
+                notes.push("mtime".to_string());
+            }
+            if changes.symlink_target && !changes.kind {
+                let target = |state: &EntryState| {
+                    state
+                        .symlink_target
+                        .as_ref()
+                        .map_or(String::new(), |target| target.display().to_string())
+                };
+                notes.push(format!("target {} -> {}", target(before), target(after)));
+            }
+        }
+        if !notes.is_empty() {
+            write!(f, " [{}]", notes.join(", "))?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for DiffRender<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let root = &self.diff.root;
+        let width = self.column_width(root).max(1);
+        self.fmt_tree(f, width)?;
+
+        let total = match self.delta(root) {
+            delta if delta.is_empty() => "0".to_string(),
+            delta => delta,
+        };
+        writeln!(
+            f,
+            "  {:>width$}  total: {}",
+            total,
+            self.diff.summary(),
+            width = width
+        )
+    }
+}
@@ -0,0 +1,775 @@
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+use crate::entry::Device;
+use crate::entry::EntryKind;
+use crate::metadata::timestamp;
+
+/// How a scan reaches the entries it reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Open, list and stat every entry by its full path, through the
+    /// standard library.
+    #[default]
+    Paths,
+    /// Hold a file descriptor for each directory being read and reach its
+    /// entries relative to it, with `openat`, `fstatat` and `readlinkat`.
+    /// Paths can then be longer than `PATH_MAX`, and a directory that is
+    /// swapped for a symlink or another directory during the scan is not
+    /// read in its place. Birth times are not recorded. Where `openat` and
+    /// `fdopendir` are not known to be available, that is other than on
+    /// Linux, Android, macOS, iOS, FreeBSD, NetBSD and OpenBSD, the scan
+    /// falls back to `Paths`.
+    Openat,
+    /// Like `Openat`, but list directories with `getdents64` into a large
+    /// buffer and read metadata with `statx`, asking only for the fields the
+    /// scan records. Birth times are recorded along with
+    /// [`WalkOptions::metadata`](crate::WalkOptions::metadata). Where the
+    /// kernel does not allow these calls, and on systems other than Linux,
+    /// the scan falls back to `Paths`.
+    Getdents,
+}
+
+/// What a scan reads about a file, from whichever call the backend makes.
+#[derive(Clone, Debug)]
+pub(crate) struct Stat {
+    pub(crate) mode: u32,
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+    pub(crate) nlink: u64,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: u64,
+    pub(crate) blocks: u64,
+    pub(crate) rdev: u64,
+    pub(crate) accessed: Option<SystemTime>,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) changed: Option<SystemTime>,
+    pub(crate) created: Option<SystemTime>,
+}
+
+/// A directory being read, through which its entries are reached.
+#[derive(Clone)]
+pub(crate) enum Dir {
+    /// Entries are reached by their full path.
+    Path,
+    /// Entries are reached relative to a descriptor for the directory, which
+    /// the tasks of a parallel scan share.
+    Fd(Arc<Handle>),
+    /// No descriptor is held, because none is needed any more or to keep
+    /// within [`WalkOptions::max_open_dirs`](crate::WalkOptions::max_open_dirs).
+    /// It has to be opened again before use.
+    Closed,
+}
+
+/// An open directory, and the calls to read through it with.
+pub(crate) struct Handle {
+    fd: OwnedFd,
+    calls: Calls,
+}
+
+/// Which system calls a [`Handle`] is read with.
+#[derive(Clone, Copy)]
+enum Calls {
+    /// `readdir` and `fstatat`, through the C library.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    Libc,
+    /// `getdents64`, and `statx` asking for the fields in the mask.
+    #[cfg(target_os = "linux")]
+    Raw(libc::c_uint),
+}
+
+impl Calls {
+    /// Whether these are calls that the kernel, or a sandbox around the
+    /// process, may not allow.
+    fn may_be_unsupported(self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Calls::Raw(_) => true,
+            _ => false,
+        }
+    }
+
+    /// These calls, reading no more than the type of a file.
+    fn for_kind(self) -> Calls {
+        match self {
+            #[cfg(target_os = "linux")]
+            Calls::Raw(_) => Calls::Raw(libc::STATX_TYPE),
+            calls => calls,
+        }
+    }
+
+    /// These calls, reading no more than the device and inode of a file.
+    fn for_id(self) -> Calls {
+        match self {
+            #[cfg(target_os = "linux")]
+            Calls::Raw(_) => Calls::Raw(libc::STATX_INO),
+            calls => calls,
+        }
+    }
+}
+
+/// An entry listed in a directory.
+pub(crate) struct DirEntry {
+    pub(crate) name: OsString,
+    /// The entry's type, if the listing gave it, as most file systems do.
+    pub(crate) kind: Option<EntryKind>,
+}
+
+/// A `DIR` stream, closed when dropped.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+struct Stream(*mut libc::DIR);
+
+const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+#[cfg(test)]
+thread_local! {
+    /// How many times this thread has read the metadata of an entry.
+    pub(crate) static STATS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// How many bytes of entries each `getdents64` call may return.
+#[cfg(target_os = "linux")]
+const GETDENTS_BUFFER: usize = 128 * 1024;
+
+/// The `statx` fields every scan records.
+#[cfg(target_os = "linux")]
+const STATX_BASIC: libc::c_uint = libc::STATX_TYPE
+    | libc::STATX_INO
+    | libc::STATX_NLINK
+    | libc::STATX_SIZE
+    | libc::STATX_BLOCKS
+    | libc::STATX_MTIME;
+
+/// The further `statx` fields recorded with
+/// [`WalkOptions::metadata`](crate::WalkOptions::metadata).
+#[cfg(target_os = "linux")]
+const STATX_METADATA: libc::c_uint = libc::STATX_MODE
+    | libc::STATX_UID
+    | libc::STATX_GID
+    | libc::STATX_ATIME
+    | libc::STATX_CTIME
+    | libc::STATX_BTIME;
+
+impl Stat {
+    pub(crate) fn kind(&self) -> EntryKind {
+        match self.mode as libc::mode_t & libc::S_IFMT {
+            libc::S_IFDIR => EntryKind::Directory,
+            libc::S_IFREG => EntryKind::File,
+            libc::S_IFLNK => EntryKind::Symlink,
+            libc::S_IFIFO => EntryKind::Fifo,
+            libc::S_IFSOCK => EntryKind::Socket,
+            libc::S_IFBLK => EntryKind::BlockDevice,
+            libc::S_IFCHR => EntryKind::CharDevice,
+            _ => EntryKind::Unknown,
+        }
+    }
+
+    /// The device and inode, which identify the file.
+    pub(crate) fn id(&self) -> (u64, u64) {
+        (self.dev, self.ino)
+    }
+
+    pub(crate) fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Stat {
+            mode: metadata.mode(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.len(),
+            blocks: metadata.blocks(),
+            rdev: metadata.rdev(),
+            accessed: metadata.accessed().ok(),
+            modified: metadata.modified().ok(),
+            changed: timestamp(metadata.ctime(), metadata.ctime_nsec()),
+            // std reads the birth time with statx where the kernel has it.
+            created: metadata.created().ok(),
+        }
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    fn from_stat(stat: &libc::stat) -> Self {
+        Stat {
+            mode: stat.st_mode as u32,
+            dev: stat.st_dev as u64,
+            ino: stat.st_ino as u64,
+            nlink: stat.st_nlink as u64,
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            size: stat.st_size as u64,
+            blocks: stat.st_blocks as u64,
+            rdev: stat.st_rdev as u64,
+            accessed: timestamp(stat.st_atime, stat.st_atime_nsec),
+            modified: timestamp(stat.st_mtime, stat.st_mtime_nsec),
+            changed: timestamp(stat.st_ctime, stat.st_ctime_nsec),
+            created: None,
+        }
+    }
+
+    /// Reads what `statx` filled in, leaving out the fields it was not asked
+    /// for or could not give.
+    #[cfg(target_os = "linux")]
+    fn from_statx(statx: &libc::statx) -> Self {
+        let has = |field: libc::c_uint| statx.stx_mask & field != 0;
+        let device = |major, minor| Device { major, minor }.to_rdev();
+        let time = |field, time: &libc::statx_timestamp| {
+            if has(field) {
+                timestamp(time.tv_sec, time.tv_nsec.into())
+            } else {
+                None
+            }
+        };
+        Stat {
+            mode: statx.stx_mode.into(),
+            dev: device(statx.stx_dev_major, statx.stx_dev_minor),
+            ino: statx.stx_ino,
+            nlink: statx.stx_nlink.into(),
+            uid: if has(libc::STATX_UID) {
+                statx.stx_uid
+            } else {
+                0
+            },
+            gid: if has(libc::STATX_GID) {
+                statx.stx_gid
+            } else {
+                0
+            },
+            size: statx.stx_size,
+            blocks: statx.stx_blocks,
+            rdev: device(statx.stx_rdev_major, statx.stx_rdev_minor),
+            accessed: time(libc::STATX_ATIME, &statx.stx_atime),
+            modified: time(libc::STATX_MTIME, &statx.stx_mtime),
+            changed: time(libc::STATX_CTIME, &statx.stx_ctime),
+            created: time(libc::STATX_BTIME, &statx.stx_btime),
+        }
+    }
+}
+
+impl Dir {
+    /// Opens the directory at `path`, where a scan starts. `expected` is
+    /// the device and inode it should have, if known. `metadata` is whether
+    /// the scan records [`Metadata`](crate::Metadata), which `statx` is then
+    /// asked for.
+    pub(crate) fn open_root(
+        backend: Backend,
+        #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] metadata: bool,
+        path: &Path,
+        expected: Option<(u64, u64)>,
+    ) -> io::Result<Dir> {
+        let calls = match backend {
+            Backend::Paths => return Ok(Dir::Path),
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            Backend::Openat => Calls::Libc,
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            )))]
+            Backend::Openat => return Ok(Dir::Path),
+            #[cfg(target_os = "linux")]
+            Backend::Getdents if metadata => Calls::Raw(STATX_BASIC | STATX_METADATA),
+            #[cfg(target_os = "linux")]
+            Backend::Getdents => Calls::Raw(STATX_BASIC),
+            #[cfg(not(target_os = "linux"))]
+            Backend::Getdents => return Ok(Dir::Path),
+        };
+        let path = c_string(path.as_os_str())?;
+        // SAFETY: `path` is a valid C string.
+        let opened = unsafe { libc::open(path.as_ptr(), DIR_FLAGS) };
+        match Dir::checked(opened, calls, expected) {
+            Err(error) if calls.may_be_unsupported() && is_unsupported(&error) => Ok(Dir::Path),
+            result => result,
+        }
+    }
+
+    /// Opens directory `name` of this one. A symlink is only followed with
+    /// `follow`. `expected` is the device and inode it should have, as it
+    /// was listed, if known.
+    pub(crate) fn open(
+        &self,
+        name: &OsStr,
+        follow: bool,
+        expected: Option<(u64, u64)>,
+    ) -> io::Result<Dir> {
+        let handle = match self {
+            Dir::Path => return Ok(Dir::Path),
+            Dir::Fd(handle) => handle,
+            Dir::Closed => return Err(closed()),
+        };
+        let name = c_string(name)?;
+        let flags = if follow {
+            DIR_FLAGS
+        } else {
+            DIR_FLAGS | libc::O_NOFOLLOW
+        };
+        // SAFETY: the descriptor is open and `name` is a valid C string.
+        let opened = unsafe { libc::openat(handle.fd.as_raw_fd(), name.as_ptr(), flags) };
+        Dir::checked(opened, handle.calls, expected)
+    }
+
+    /// Takes ownership of `fd`, as returned by an `open` call, checking that
+    /// it is the directory `expected` identifies. The check is made with
+    /// `calls` even when nothing is expected, so that a kernel lacking them
+    /// is found out before the scan starts.
+    fn checked(fd: RawFd, calls: Calls, expected: Option<(u64, u64)>) -> io::Result<Dir> {
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened, and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if expected.is_some() || calls.may_be_unsupported() {
+            let stat = stat_fd(fd.as_raw_fd(), calls.for_id())?;
+            if expected.is_some_and(|expected| stat.id() != expected) {
+                return Err(io::Error::other("replaced during the scan"));
+            }
+        }
+        Ok(Dir::Fd(Arc::new(Handle { fd, calls })))
+    }
+
+    /// Whether this holds an open descriptor.
+    pub(crate) fn is_fd(&self) -> bool {
+        matches!(self, Dir::Fd(_))
+    }
+
+    /// Lists the entries of this directory, which is at `path`, other than
+    /// `.` and `..`. Entries that cannot be read are listed as errors. A
+    /// listing by path is closed before this returns, so that a walk does not
+    /// hold a descriptor for every directory above the one it is in.
+    pub(crate) fn list(&self, path: &Path) -> io::Result<Vec<io::Result<DirEntry>>> {
+        let handle = match self {
+            Dir::Path => {
+                return Ok(fs::read_dir(path)?
+                    .map(|dir_entry| {
+                        dir_entry.map(|dir_entry| DirEntry {
+                            name: dir_entry.file_name(),
+                            kind: dir_entry.file_type().ok().map(kind_of_type),
+                        })
+                    })
+                    .collect())
+            }
+            Dir::Fd(handle) => handle,
+            Dir::Closed => return Err(closed()),
+        };
+
+        // A descriptor of the listing's own, so that reading it does not
+        // move the offset of the shared one.
+        let dot = CStr::from_bytes_with_nul(b".\0").unwrap();
+        // SAFETY: the descriptor is open and `dot` is a valid C string.
+        let own = unsafe { libc::openat(handle.fd.as_raw_fd(), dot.as_ptr(), DIR_FLAGS) };
+        if own < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `own` was just opened, and nothing else owns it.
+        let own = unsafe { OwnedFd::from_raw_fd(own) };
+        match handle.calls {
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            Calls::Libc => list_stream(own),
+            #[cfg(target_os = "linux")]
+            Calls::Raw(_) => Ok(list_getdents(own)),
+        }
+    }
+
+    /// Reads the metadata of entry `name` of this directory, which is at
+    /// `path`, following a symlink only with `follow`.
+    pub(crate) fn stat(&self, name: &OsStr, path: &Path, follow: bool) -> io::Result<Stat> {
+        #[cfg(test)]
+        STATS.with(|stats| stats.set(stats.get() + 1));
+        let handle = match self {
+            Dir::Path => {
+                let metadata = if follow {
+                    fs::metadata(path)?
+                } else {
+                    fs::symlink_metadata(path)?
+                };
+                return Ok(Stat::from_metadata(&metadata));
+            }
+            Dir::Fd(handle) => handle,
+            Dir::Closed => return Err(closed()),
+        };
+        let name = c_string(name)?;
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        stat_at(handle.fd.as_raw_fd(), &name, flags, handle.calls)
+    }
+
+    /// Reads the type of entry `name` of this directory, which is at `path`,
+    /// as [`stat`](Dir::stat) does but asking for nothing else.
+    pub(crate) fn kind(&self, name: &OsStr, path: &Path, follow: bool) -> io::Result<EntryKind> {
+        let handle = match self {
+            Dir::Fd(handle) => handle,
+            _ => return Ok(self.stat(name, path, follow)?.kind()),
+        };
+        #[cfg(test)]
+        STATS.with(|stats| stats.set(stats.get() + 1));
+        let name = c_string(name)?;
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        let calls = handle.calls.for_kind();
+        Ok(stat_at(handle.fd.as_raw_fd(), &name, flags, calls)?.kind())
+    }
+
+    /// Reads the target of symlink `name` of this directory, which is at
+    /// `path`.
+    pub(crate) fn read_link(&self, name: &OsStr, path: &Path) -> io::Result<PathBuf> {
+        let fd = match self {
+            Dir::Path => return fs::read_link(path),
+            Dir::Fd(handle) => &handle.fd,
+            Dir::Closed => return Err(closed()),
+        };
+        let name = c_string(name)?;
+        let mut buffer = vec![0u8; 256];
+        loop {
+            // SAFETY: `fd` is open, `name` is a valid C string and `buffer`
+            // has room for as many bytes as are asked for.
+            let len = unsafe {
+                libc::readlinkat(
+                    fd.as_raw_fd(),
+                    name.as_ptr(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // A target that fills the buffer may have been cut short.
+            let len = len as usize;
+            if len < buffer.len() {
+                buffer.truncate(len);
+                return Ok(PathBuf::from(OsString::from_vec(buffer)));
+            }
+            buffer.resize(buffer.len() * 2, 0);
+        }
+    }
+
+    /// Opens file `name` of this directory, which is at `path`, for reading.
+    /// A symlink is only followed with `follow`.
+    pub(crate) fn open_file(&self, name: &OsStr, path: &Path, follow: bool) -> io::Result<File> {
+        let fd = match self {
+            Dir::Path => return File::open(path),
+            Dir::Fd(handle) => &handle.fd,
+            Dir::Closed => return Err(closed()),
+        };
+        let name = c_string(name)?;
+        let flags = if follow {
+            libc::O_RDONLY | libc::O_CLOEXEC
+        } else {
+            libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOFOLLOW
+        };
+        // SAFETY: `fd` is open and `name` is a valid C string.
+        let opened = unsafe { libc::openat(fd.as_raw_fd(), name.as_ptr(), flags) };
+        if opened < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `opened` was just opened, and nothing else owns it.
+        Ok(File::from(unsafe { OwnedFd::from_raw_fd(opened) }))
+    }
+
+    /// Reads the whole of file `name` of this directory, following symlinks.
+    pub(crate) fn read_file(&self, name: &OsStr, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        self.open_file(name, path, true)?
+            .read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // SAFETY: the stream is open, and is not used again.
+        unsafe { libc::closedir(self.0) };
+    }
+}
+
+/// Lists the directory open as `fd` through a `DIR` stream.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn list_stream(fd: OwnedFd) -> io::Result<Vec<io::Result<DirEntry>>> {
+    use std::os::fd::IntoRawFd;
+
+    // Where the C library keeps the calling thread's `errno`.
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    use libc::__errno as errno_location;
+    #[cfg(target_os = "linux")]
+    use libc::__errno_location as errno_location;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    use libc::__error as errno_location;
+    // Plain `readdir` reads 32-bit inodes on 32-bit glibc.
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    use libc::readdir;
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    use libc::readdir64 as readdir;
+
+    // SAFETY: `fd` is open; the stream owns it from here on if this succeeds.
+    let stream = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+    if stream.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let _ = fd.into_raw_fd();
+    let stream = Stream(stream);
+
+    let mut entries = vec![];
+    loop {
+        // `readdir` signals the end and errors alike with a null entry,
+        // leaving `errno` alone at the end.
+        // SAFETY: `errno` is thread-local.
+        unsafe { *errno_location() = 0 };
+        // SAFETY: `stream` is open.
+        let entry = unsafe { readdir(stream.0) };
+        if entry.is_null() {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(0) {
+                entries.push(Err(error));
+            }
+            return Ok(entries);
+        }
+        // SAFETY: the entry stays valid until the next `readdir`, and its
+        // name is a C string.
+        let (name, d_type) = unsafe { (CStr::from_ptr((*entry).d_name.as_ptr()), (*entry).d_type) };
+        push_entry(&mut entries, name.to_bytes(), d_type);
+    }
+}
+
+/// Lists the directory open as `fd` with `getdents64`, reading as many
+/// entries at once as fit in `GETDENTS_BUFFER` bytes.
+#[cfg(target_os = "linux")]
+fn list_getdents(fd: OwnedFd) -> Vec<io::Result<DirEntry>> {
+    let mut buffer = vec![0u8; GETDENTS_BUFFER];
+    let mut entries = vec![];
+    loop {
+        // SAFETY: `fd` is open and `buffer` has room for as many bytes as are
+        // asked for.
+        let read = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                fd.as_raw_fd(),
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            )
+        };
+        if read < 0 {
+            entries.push(Err(io::Error::last_os_error()));
+            return entries;
+        }
+        if read == 0 {
+            return entries;
+        }
+        // Each record is a `linux_dirent64`: the inode, the offset of the
+        // next record, the record's length, the type, and then the name,
+        // padded with nul bytes.
+        let mut records = &buffer[..read as usize];
+        while records.len() > 19 {
+            let len = usize::from(u16::from_ne_bytes([records[16], records[17]]));
+            let name = &records[19..len];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            push_entry(&mut entries, name, records[18]);
+            records = &records[len..];
+        }
+    }
+}
+
+/// Adds the entry with `name` and `d_type` to `entries`, unless it is `.`
+/// or `..`.
+fn push_entry(entries: &mut Vec<io::Result<DirEntry>>, name: &[u8], d_type: u8) {
+    if name != b"." && name != b".." {
+        entries.push(Ok(DirEntry {
+            name: OsStr::from_bytes(name).to_os_string(),
+            kind: kind_of_d_type(d_type),
+        }));
+    }
+}
+
+/// Reads the metadata of `name` in the directory open as `fd` with `calls`.
+fn stat_at(fd: RawFd, name: &CStr, flags: libc::c_int, calls: Calls) -> io::Result<Stat> {
+    match calls {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))]
+        Calls::Libc => {
+            let mut stat = MaybeUninit::uninit();
+            // SAFETY: `fd` is open, `name` is a valid C string and `stat` is
+            // large enough for the result.
+            if unsafe { libc::fstatat(fd, name.as_ptr(), stat.as_mut_ptr(), flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fstatat` succeeded, so it filled in `stat`.
+            Ok(Stat::from_stat(unsafe { &stat.assume_init() }))
+        }
+        #[cfg(target_os = "linux")]
+        Calls::Raw(mask) => {
+            let mut statx = MaybeUninit::<libc::statx>::uninit();
+            // SAFETY: `fd` is open, `name` is a valid C string and `statx` is
+            // large enough for the result.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_statx,
+                    fd,
+                    name.as_ptr(),
+                    flags | libc::AT_STATX_SYNC_AS_STAT,
+                    mask,
+                    statx.as_mut_ptr(),
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `statx` succeeded, so it filled in `statx`.
+            Ok(Stat::from_statx(unsafe { &statx.assume_init() }))
+        }
+    }
+}
+
+/// Reads the metadata of the file open as `fd` with `calls`.
+fn stat_fd(fd: RawFd, calls: Calls) -> io::Result<Stat> {
+    match calls {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))]
+        Calls::Libc => {
+            let mut stat = MaybeUninit::uninit();
+            // SAFETY: `fd` is open and `stat` is large enough for the result.
+            if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fstat` succeeded, so it filled in `stat`.
+            Ok(Stat::from_stat(unsafe { &stat.assume_init() }))
+        }
+        #[cfg(target_os = "linux")]
+        Calls::Raw(_) => {
+            let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+            stat_at(fd, empty, libc::AT_EMPTY_PATH, calls)
+        }
+    }
+}
+
+/// Whether `error` means that the kernel, or a sandbox around the process,
+/// does not allow a system call.
+fn is_unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::ENOSYS | libc::EPERM))
+}
+
+fn kind_of_type(file_type: fs::FileType) -> EntryKind {
+    if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else if file_type.is_file() {
+        EntryKind::File
+    } else if file_type.is_fifo() {
+        EntryKind::Fifo
+    } else if file_type.is_socket() {
+        EntryKind::Socket
+    } else if file_type.is_block_device() {
+        EntryKind::BlockDevice
+    } else if file_type.is_char_device() {
+        EntryKind::CharDevice
+    } else {
+        EntryKind::Unknown
+    }
+}
+
+fn kind_of_d_type(d_type: u8) -> Option<EntryKind> {
+    Some(match d_type {
+        libc::DT_DIR => EntryKind::Directory,
+        libc::DT_REG => EntryKind::File,
+        libc::DT_LNK => EntryKind::Symlink,
+        libc::DT_FIFO => EntryKind::Fifo,
+        libc::DT_SOCK => EntryKind::Socket,
+        libc::DT_BLK => EntryKind::BlockDevice,
+        libc::DT_CHR => EntryKind::CharDevice,
+        _ => return None,
+    })
+}
+
+fn c_string(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+/// The error for using a descriptor that was closed, which the walker
+/// prevents by opening it again first.
+fn closed() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
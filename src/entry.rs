@@ -0,0 +1,426 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fmt, mem, vec};
+
+use crate::error::Error;
+use crate::filter::Filter;
+use crate::link::Link;
+use crate::metadata::Metadata;
+use crate::render::{Render, RenderOptions};
+use crate::sort::SortOrder;
+use crate::visit::{self, BreadthFirst, DepthFirst, Visitor, VisitorMut};
+
+/// A single node of a [`FileTree`](crate::FileTree).
+pub struct Entry {
+    pub(crate) name: OsString,
+    pub(crate) size: u64,
+    pub(crate) disk_usage: u64,
+    pub(crate) links: u64,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) ignored: bool,
+    pub(crate) link: Option<Box<Link>>,
+    pub(crate) metadata: Option<Box<Metadata>>,
+    pub(crate) content_hash: Option<u64>,
+    pub(crate) data: EntryData,
+}
+
+/// What an [`Entry`] refers to on disk.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EntryData {
+    File,
+    Symlink(#[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::os_bytes"))] PathBuf),
+    Directory(Vec<Entry>),
+    /// A named pipe.
+    Fifo,
+    /// A Unix domain socket.
+    Socket,
+    BlockDevice(Device),
+    CharDevice(Device),
+    /// Anything else the platform may report.
+    Unknown,
+    /// A followed symlink to a directory that contains it, which was not
+    /// descended into again.
+    Loop,
+    /// An entry that could not be read.
+    Error(Error),
+}
+
+/// The device number of a block or character device file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Device {
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// Which of an entry's sizes to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SizeKind {
+    /// The length of files, see [`Entry::size`].
+    #[default]
+    Apparent,
+    /// The space allocated for them, see [`Entry::disk_usage`].
+    Allocated,
+}
+
+/// The kind of an [`Entry`], without any of its payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+    File,
+    Symlink,
+    Directory,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    Unknown,
+    Loop,
+    Error,
+}
+
+/// A directory being filtered by [`Entry::filter`].
+struct Filtering {
+    /// The directory, unless it is the one the filter started at.
+    dir: Option<Entry>,
+    /// The length of the path without the directory's name.
+    len: usize,
+    /// Its entries still to look at, taken out of it.
+    pending: vec::IntoIter<Entry>,
+    kept: Vec<Entry>,
+}
+
+impl Entry {
+    pub(crate) fn error(name: OsString, error: Error) -> Self {
+        Entry {
+            name,
+            size: 0,
+            disk_usage: 0,
+            links: 0,
+            modified: None,
+            ignored: false,
+            link: None,
+            metadata: None,
+            content_hash: None,
+            data: EntryData::Error(error),
+        }
+    }
+
+    /// The file name of this entry. For the root entry this is the last
+    /// component of the scanned path, or empty if it has none.
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// The apparent size in bytes. For directories this is the total size of
+    /// all entries below them.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The bytes allocated on disk, like `du` reports. For directories this
+    /// includes their own allocation as well as that of all entries below
+    /// them.
+    pub fn disk_usage(&self) -> u64 {
+        self.disk_usage
+    }
+
+    /// The number of hard links to this entry. Sizes of entries with more
+    /// than one are counted according to
+    /// [`WalkOptions::hard_links`](crate::WalkOptions::hard_links).
+    pub fn links(&self) -> u64 {
+        self.links
+    }
+
+    pub fn size_of(&self, kind: SizeKind) -> u64 {
+        match kind {
+            SizeKind::Apparent => self.size,
+            SizeKind::Allocated => self.disk_usage,
+        }
+    }
+
+    /// The last modification time, if the platform reports one.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Ownership, permissions and timestamps, if the scan captured them.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_deref()
+    }
+
+    /// A hash of a regular file's contents, if the scan recorded it with
+    /// [`WalkOptions::hash_contents`](crate::WalkOptions::hash_contents).
+    pub fn content_hash(&self) -> Option<u64> {
+        self.content_hash
+    }
+
+    /// Whether an ignore file matched this entry or one of its parents. Only
+    /// set when ignored entries are kept rather than left out.
+    pub fn is_ignored(&self) -> bool {
+        self.ignored
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.data.kind()
+    }
+
+    pub fn data(&self) -> &EntryData {
+        &self.data
+    }
+
+    /// The entries of a directory, or `None` if this is not a directory.
+    pub fn children(&self) -> Option<&[Entry]> {
+        match &self.data {
+            EntryData::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    /// The entries of a directory, to change in place. Sizes are not updated
+    /// to match.
+    pub fn children_mut(&mut self) -> Option<&mut Vec<Entry>> {
+        match &mut self.data {
+            EntryData::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    /// The raw target of a symlink, as returned by `read_link`. This is also
+    /// set for symlinks that were followed, whose data describes what they
+    /// point to.
+    pub fn symlink_target(&self) -> Option<&Path> {
+        self.link.as_ref().map(|link| link.target())
+    }
+
+    /// Where a symlink points and whether it can be followed. Set for the
+    /// same entries as [`symlink_target`](Entry::symlink_target).
+    pub fn link(&self) -> Option<&Link> {
+        self.link.as_deref()
+    }
+
+    /// Every broken symlink below this entry, with its path relative to this
+    /// entry.
+    pub fn broken_links(&self) -> Vec<(PathBuf, &Entry)> {
+        let mut links = vec![];
+        self.collect_broken_links(PathBuf::new(), &mut links);
+        links
+    }
+
+    pub(crate) fn collect_broken_links<'a>(
+        &'a self,
+        path: PathBuf,
+        links: &mut Vec<(PathBuf, &'a Entry)>,
+    ) {
+        let mut stack = vec![(path, self)];
+        while let Some((path, entry)) = stack.pop() {
+            for child in entry.children().unwrap_or(&[]).iter().rev() {
+                stack.push((path.join(&child.name), child));
+            }
+            if entry.link().is_some_and(Link::is_broken) {
+                links.push((path, entry));
+            }
+        }
+    }
+
+    /// The errors of every entry at or below this one that could not be
+    /// read.
+    pub fn errors(&self) -> Vec<&Error> {
+        let mut errors = vec![];
+        self.collect_errors(&mut errors);
+        errors
+    }
+
+    fn collect_errors<'a>(&'a self, errors: &mut Vec<&'a Error>) {
+        let mut stack = vec![self];
+        while let Some(entry) = stack.pop() {
+            match &entry.data {
+                EntryData::Error(error) => errors.push(error),
+                EntryData::Directory(children) => stack.extend(children.iter().rev()),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == EntryKind::Directory
+    }
+
+    /// Draws this entry and everything below it as a tree.
+    pub fn render<'a>(&'a self, options: &'a RenderOptions) -> Render<'a> {
+        Render::new(self, options)
+    }
+
+    /// This entry and everything below it, each directory followed by what
+    /// is in it, with paths relative to this entry.
+    pub fn depth_first(&self) -> DepthFirst<'_> {
+        DepthFirst::new(PathBuf::new(), self)
+    }
+
+    /// This entry and everything below it, level by level, with paths
+    /// relative to this entry.
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst::new(PathBuf::new(), self)
+    }
+
+    /// Passes this entry and everything below it to `visitor`, with paths
+    /// relative to this entry.
+    pub fn visit(&self, visitor: &mut impl Visitor) {
+        visit::visit(self, PathBuf::new(), visitor);
+    }
+
+    /// Passes this entry and everything below it to `visitor` to change.
+    pub fn visit_mut(&mut self, visitor: &mut impl VisitorMut) {
+        visit::visit_mut(self, PathBuf::new(), visitor);
+    }
+
+    /// Drops the entries below this one that `filter` would have left out of
+    /// a scan, as if it had been given at scan time, updating directory
+    /// sizes to match. `relative_dir` is this entry's path below the
+    /// scanned root, which path patterns are matched against. Directories
+    /// being filtered are kept on a stack rather than the call stack, so
+    /// that a tree of any depth can be filtered.
+    pub(crate) fn filter(&mut self, filter: &Filter, relative_dir: &Path) {
+        if !self.is_dir() {
+            return;
+        }
+        let mut path = relative_dir.to_path_buf();
+        let mut stack = vec![Filtering {
+            dir: None,
+            len: 0,
+            pending: self.take_children().into_iter(),
+            kept: vec![],
+        }];
+        loop {
+            let top = stack.last_mut().unwrap();
+            let mut child = match top.pending.next() {
+                Some(child) => child,
+                None => {
+                    let Filtering { dir, len, kept, .. } = stack.pop().unwrap();
+                    let mut dir = match dir {
+                        Some(dir) => dir,
+                        None => return self.put_children(kept),
+                    };
+                    dir.put_children(kept);
+                    visit::truncate(&mut path, len);
+                    if !filter.has_includes() || dir.children().is_some_and(|c| !c.is_empty()) {
+                        stack.last_mut().unwrap().kept.push(dir);
+                    }
+                    continue;
+                }
+            };
+            let len = visit::push(&mut path, &child.name);
+            let keep = !filter.is_excluded(&child.name, &path)
+                && match &child.data {
+                    EntryData::Directory(..) => {
+                        stack.push(Filtering {
+                            pending: child.take_children().into_iter(),
+                            dir: Some(child),
+                            len,
+                            kept: vec![],
+                        });
+                        continue;
+                    }
+                    EntryData::Error(..) => true,
+                    _ => filter.is_included(&child.name, &path),
+                };
+            if keep {
+                top.kept.push(child);
+            }
+            visit::truncate(&mut path, len);
+        }
+    }
+
+    /// Takes the entries out of a directory, leaving its disk usage as only
+    /// its own allocation.
+    fn take_children(&mut self) -> Vec<Entry> {
+        let children = match &mut self.data {
+            EntryData::Directory(children) => mem::take(children),
+            _ => return vec![],
+        };
+        let below: u64 = children.iter().map(|child| child.disk_usage).sum();
+        self.disk_usage = self.disk_usage.saturating_sub(below);
+        children
+    }
+
+    /// Puts entries taken out with [`take_children`](Entry::take_children)
+    /// back, with the sizes of those that are left.
+    fn put_children(&mut self, children: Vec<Entry>) {
+        self.size = children.iter().map(|child| child.size).sum();
+        self.disk_usage += children.iter().map(|child| child.disk_usage).sum::<u64>();
+        self.data = EntryData::Directory(children);
+    }
+
+    /// Reorders the entries of this directory and all directories below it.
+    pub fn sort(&mut self, order: &SortOrder) {
+        let mut stack = vec![self];
+        while let Some(entry) = stack.pop() {
+            if let EntryData::Directory(children) = &mut entry.data {
+                order.sort(children);
+                stack.extend(children.iter_mut());
+            }
+        }
+    }
+}
+
+impl EntryData {
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            EntryData::File => EntryKind::File,
+            EntryData::Symlink(..) => EntryKind::Symlink,
+            EntryData::Directory(..) => EntryKind::Directory,
+            EntryData::Fifo => EntryKind::Fifo,
+            EntryData::Socket => EntryKind::Socket,
+            EntryData::BlockDevice(..) => EntryKind::BlockDevice,
+            EntryData::CharDevice(..) => EntryKind::CharDevice,
+            EntryData::Unknown => EntryKind::Unknown,
+            EntryData::Loop => EntryKind::Loop,
+            EntryData::Error(..) => EntryKind::Error,
+        }
+    }
+}
+
+impl Device {
+    /// Splits a `st_rdev` value the way glibc's `major` and `minor` do.
+    pub(crate) fn from_rdev(rdev: u64) -> Self {
+        Device {
+            major: (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as u32,
+            minor: ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as u32,
+        }
+    }
+
+    /// Joins the numbers into a `st_rdev` value, as glibc's `makedev` does.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn to_rdev(self) -> u64 {
+        let (major, minor) = (u64::from(self.major), u64::from(self.minor));
+        ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // Dropping each directory's children from inside its own drop would
+        // recurse once per level, so the tree is taken apart here instead.
+        let mut stack = match &mut self.data {
+            EntryData::Directory(children) => mem::take(children),
+            _ => return,
+        };
+        while let Some(mut entry) = stack.pop() {
+            if let EntryData::Directory(children) = &mut entry.data {
+                stack.append(children);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.render(&RenderOptions::default()), f)
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+/// An I/O error encountered while scanning, with the path and operation it
+/// happened on.
+#[derive(Debug)]
+pub struct Error {
+    operation: Operation,
+    path: PathBuf,
+    depth: usize,
+    source: io::Error,
+}
+
+/// The file system operation that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operation {
+    /// Listing the entries of a directory.
+    ReadDir,
+    /// Reading the metadata of an entry.
+    Stat,
+    /// Reading the target of a symlink.
+    ReadLink,
+    /// Reading the contents of a file, such as an ignore file.
+    ReadFile,
+    /// Resolving a path to its canonical form.
+    Canonicalize,
+    /// Passing an entry on to a streaming scan's receiver.
+    WriteEvent,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn new(
+        operation: Operation,
+        path: impl Into<PathBuf>,
+        depth: usize,
+        source: io::Error,
+    ) -> Self {
+        Error {
+            operation,
+            path: path.into(),
+            depth,
+            source,
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// The path the operation was applied to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How many levels below the scanned root `path` is.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        self.source.kind()
+    }
+
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cannot {} {}: {}",
+            self.operation,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operation::ReadDir => "read directory",
+            Operation::Stat => "stat",
+            Operation::ReadLink => "read link",
+            Operation::ReadFile => "read",
+            Operation::Canonicalize => "canonicalize",
+            Operation::WriteEvent => "write event for",
+        })
+    }
+}
+
+/// Attaches the operation, path and depth to an `io::Result`.
+pub(crate) trait Context<T> {
+    fn context(self, operation: Operation, path: &Path, depth: usize) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, operation: Operation, path: &Path, depth: usize) -> Result<T> {
+        self.map_err(|source| Error::new(operation, path, depth, source))
+    }
+}
@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::glob::Glob;
+use crate::regex::Regex;
+
+/// A glob or regular expression matched against an entry's name, or against
+/// its path relative to the scanned root.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    matcher: Matcher,
+    on_path: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Glob(Glob),
+    Regex(Regex),
+}
+
+/// An invalid [`Pattern`].
+#[derive(Clone, Debug)]
+pub struct PatternError {
+    message: String,
+}
+
+/// Decides which entries a scan keeps.
+///
+/// An entry matching any exclude pattern is dropped along with everything
+/// below it. If there are include patterns, only non-directories matching at
+/// least one of them are kept, and directories are kept only while something
+/// below them is, so non-matching branches are pruned away.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Pattern {
+    /// A shell-style wildcard pattern. `?` and `*` do not match `/`, `**`
+    /// does, and `[...]` matches a set of characters.
+    pub fn glob(pattern: &str) -> Result<Self, PatternError> {
+        Ok(Pattern {
+            matcher: Matcher::Glob(Glob::new(pattern).map_err(PatternError::new)?),
+            on_path: false,
+        })
+    }
+
+    /// A regular expression that may match anywhere in the text, unless
+    /// anchored with `^` or `$`.
+    pub fn regex(pattern: &str) -> Result<Self, PatternError> {
+        Ok(Pattern {
+            matcher: Matcher::Regex(Regex::new(pattern).map_err(PatternError::new)?),
+            on_path: false,
+        })
+    }
+
+    /// Matches against the `/`-separated path relative to the scanned root
+    /// instead of the name.
+    pub fn on_path(mut self, yes: bool) -> Self {
+        self.on_path = yes;
+        self
+    }
+
+    /// The pattern as it was written.
+    pub fn as_str(&self) -> &str {
+        match &self.matcher {
+            Matcher::Glob(glob) => glob.as_str(),
+            Matcher::Regex(regex) => regex.as_str(),
+        }
+    }
+
+    pub fn is_regex(&self) -> bool {
+        matches!(self.matcher, Matcher::Regex(..))
+    }
+
+    pub fn is_on_path(&self) -> bool {
+        self.on_path
+    }
+
+    pub fn matches(&self, name: &OsStr, relative_path: &Path) -> bool {
+        let text = if self.on_path {
+            relative_path.as_os_str().as_bytes()
+        } else {
+            name.as_bytes()
+        };
+        match &self.matcher {
+            Matcher::Glob(glob) => glob.matches(text),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+impl PatternError {
+    fn new(message: String) -> Self {
+        PatternError { message }
+    }
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for PatternError {}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter::default()
+    }
+
+    pub fn include(mut self, pattern: Pattern) -> Self {
+        self.include.push(pattern);
+        self
+    }
+
+    pub fn exclude(mut self, pattern: Pattern) -> Self {
+        self.exclude.push(pattern);
+        self
+    }
+
+    pub fn includes(&self) -> &[Pattern] {
+        &self.include
+    }
+
+    pub fn excludes(&self) -> &[Pattern] {
+        &self.exclude
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub(crate) fn has_includes(&self) -> bool {
+        !self.include.is_empty()
+    }
+
+    pub(crate) fn is_excluded(&self, name: &OsStr, relative_path: &Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches(name, relative_path))
+    }
+
+    pub(crate) fn is_included(&self, name: &OsStr, relative_path: &Path) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches(name, relative_path))
+    }
+}
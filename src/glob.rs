@@ -0,0 +1,261 @@
+use std::fmt;
+
+/// A shell-style wildcard pattern.
+///
+/// `?` matches any single byte and `*` any run of bytes, neither crossing a
+/// `/`. `**` matches across `/`, and `**/` also matches no directories at
+/// all, so `a/**/b` matches `a/b`. `[...]` matches one byte from a set of
+/// bytes and ranges, negated by a leading `!` or `^`. A backslash makes the
+/// next character literal.
+#[derive(Clone)]
+pub struct Glob {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+#[derive(Clone)]
+enum Token {
+    Byte(u8),
+    AnyByte,
+    AnyRun,
+    AnyPath,
+    AnyDirs,
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let bytes = pattern.as_bytes();
+        let mut tokens = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'?' => tokens.push(Token::AnyByte),
+                b'*' if bytes.get(i + 1) == Some(&b'*') => {
+                    i += 1;
+                    if bytes.get(i + 1) == Some(&b'/') {
+                        i += 1;
+                        tokens.push(Token::AnyDirs);
+                    } else {
+                        tokens.push(Token::AnyPath);
+                    }
+                }
+                b'*' => tokens.push(Token::AnyRun),
+                b'[' => {
+                    let (token, end) = parse_class(bytes, i)
+                        .ok_or_else(|| format!("unclosed '[' in glob: {}", pattern))?;
+                    tokens.push(token);
+                    i = end;
+                }
+                b'\\' => {
+                    i += 1;
+                    let byte = *bytes
+                        .get(i)
+                        .ok_or_else(|| format!("trailing '\\' in glob: {}", pattern))?;
+                    tokens.push(Token::Byte(byte));
+                }
+                byte => tokens.push(Token::Byte(byte)),
+            }
+            i += 1;
+        }
+
+        Ok(Glob {
+            source: pattern.to_string(),
+            tokens,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches all of `text`.
+    pub fn matches(&self, text: &[u8]) -> bool {
+        match_tokens(&self.tokens, text)
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Glob").field(&self.source).finish()
+    }
+}
+
+/// Parses the class starting at the `[` at `start`, returning it and the
+/// index of its closing `]`.
+fn parse_class(bytes: &[u8], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(bytes.get(i), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = vec![];
+    let mut first = true;
+    loop {
+        let mut low = *bytes.get(i)?;
+        if low == b']' && !first {
+            return Some((Token::Class { negated, ranges }, i));
+        }
+        if low == b'\\' {
+            i += 1;
+            low = *bytes.get(i)?;
+        }
+        first = false;
+
+        let mut high = low;
+        if bytes.get(i + 1) == Some(&b'-') && bytes.get(i + 2).is_some_and(|&b| b != b']') {
+            i += 2;
+            high = bytes[i];
+            if high == b'\\' {
+                i += 1;
+                high = *bytes.get(i)?;
+            }
+        }
+        ranges.push((low, high));
+        i += 1;
+    }
+}
+
+/// Matches `text` against `tokens` by following every position in the
+/// pattern the text so far could have reached at once, so that wildcards never
+/// backtrack and matching takes time linear in the length of the text.
+fn match_tokens(tokens: &[Token], text: &[u8]) -> bool {
+    // `reached[i]` is whether the text so far can have matched the tokens
+    // before `i`. `inside[i]` is whether it can end partway through the
+    // directories of the `**/` at `i`, which must still end with a `/`.
+    let mut reached = vec![false; tokens.len() + 1];
+    let mut inside = vec![false; tokens.len()];
+    reached[0] = true;
+    skip_empty(tokens, &mut reached);
+
+    let mut next_reached = reached.clone();
+    let mut next_inside = inside.clone();
+    for &byte in text {
+        next_reached.iter_mut().for_each(|reached| *reached = false);
+        next_inside.iter_mut().for_each(|inside| *inside = false);
+        for (i, token) in tokens.iter().enumerate() {
+            if !reached[i] && !inside[i] {
+                continue;
+            }
+            match token {
+                Token::AnyRun => next_reached[i] |= byte != b'/',
+                Token::AnyPath => next_reached[i] = true,
+                Token::AnyDirs => {
+                    next_inside[i] = true;
+                    next_reached[i + 1] |= byte == b'/';
+                }
+                _ => next_reached[i + 1] |= matches_byte(token, byte),
+            }
+        }
+        skip_empty(tokens, &mut next_reached);
+        std::mem::swap(&mut reached, &mut next_reached);
+        std::mem::swap(&mut inside, &mut next_inside);
+        if !reached.contains(&true) && !inside.contains(&true) {
+            return false;
+        }
+    }
+    reached[tokens.len()]
+}
+
+/// Moves past the wildcards at each position reached, which can all match
+/// nothing.
+fn skip_empty(tokens: &[Token], reached: &mut [bool]) {
+    for (i, token) in tokens.iter().enumerate() {
+        if reached[i] && matches!(token, Token::AnyRun | Token::AnyPath | Token::AnyDirs) {
+            reached[i + 1] = true;
+        }
+    }
+}
+
+fn matches_byte(token: &Token, byte: u8) -> bool {
+    match token {
+        Token::Byte(b) => *b == byte,
+        Token::AnyByte => byte != b'/',
+        Token::Class { negated, ranges } => {
+            byte != b'/'
+                && ranges
+                    .iter()
+                    .any(|&(low, high)| low <= byte && byte <= high)
+                    != *negated
+        }
+        Token::AnyRun | Token::AnyPath | Token::AnyDirs => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Glob;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(text.as_bytes())
+    }
+
+    #[test]
+    fn matches_the_whole_text() {
+        assert!(matches("abc", "abc"));
+        assert!(!matches("ab", "abc"));
+        assert!(!matches("bc", "abc"));
+        assert!(matches("", ""));
+    }
+
+    #[test]
+    fn matches_wildcards_within_a_directory() {
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "a/c"));
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("src/*", "src/main.rs"));
+        assert!(matches("*a*b", "xaxxb"));
+        assert!(!matches("*a*b", "xaxxbx"));
+    }
+
+    #[test]
+    fn matches_across_directories() {
+        assert!(matches("**.rs", "src/a/main.rs"));
+        assert!(matches("src/**", "src/a/b"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "a/xb"));
+        assert!(matches("**/b", "b"));
+        assert!(matches("**/b", "x/y/b"));
+        assert!(!matches("**/b", "xb"));
+        assert!(matches("x**/y", "xy"));
+        assert!(matches("x**/y", "xa/b/y"));
+    }
+
+    #[test]
+    fn matches_classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[!a]", "a"));
+        assert!(!matches("[!a]", "/"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(Glob::new("[ab").is_err());
+    }
+
+    #[test]
+    fn matches_escapes() {
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "a"));
+        assert!(matches(r"a\?", "a?"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(Glob::new(r"a\").is_err());
+    }
+
+    #[test]
+    fn matches_long_texts_without_backtracking() {
+        let text = vec![b'a'; 1 << 16];
+        assert!(!Glob::new("*a*a*a*a*a*a*b").unwrap().matches(&text));
+        assert!(!Glob::new("**a**a**b").unwrap().matches(&text));
+        assert!(Glob::new("**a").unwrap().matches(&text));
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A fast non-cryptographic 64-bit hash, used to tell whether two files
+/// have the same contents.
+pub(crate) struct Hasher {
+    state: u64,
+    len: u64,
+}
+
+const PRIME_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+
+impl Hasher {
+    pub(crate) fn new() -> Self {
+        Hasher {
+            state: PRIME_2,
+            len: 0,
+        }
+    }
+
+    /// Feeds `bytes` in. Only the last call may pass a length that is not a
+    /// multiple of eight, or the result depends on how the input was split.
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let word = u64::from_le_bytes([
+                word[0], word[1], word[2], word[3], word[4], word[5], word[6], word[7],
+            ]);
+            self.mix(word);
+        }
+        let tail = words.remainder();
+        if !tail.is_empty() {
+            let mut word = [0; 8];
+            word[..tail.len()].copy_from_slice(tail);
+            self.mix(u64::from_le_bytes(word));
+        }
+        self.len += bytes.len() as u64;
+    }
+
+    fn mix(&mut self, word: u64) {
+        self.state = (self.state ^ word.wrapping_mul(PRIME_1))
+            .rotate_left(31)
+            .wrapping_mul(PRIME_2);
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        // The length is mixed in so that trailing zero bytes count.
+        let mut h = self.state ^ self.len;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+/// Hashes the contents of the file at `path`.
+pub(crate) fn hash_file(path: &Path) -> io::Result<u64> {
+    hash_reader(File::open(path)?)
+}
+
+/// Hashes everything `reader` gives until it ends.
+pub(crate) fn hash_reader(mut reader: impl Read) -> io::Result<u64> {
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        // Fill the whole buffer before hashing it, so that only the final,
+        // partial chunk can end in the middle of a word.
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        hasher.write(&buffer[..filled]);
+        if filled < buffer.len() {
+            return Ok(hasher.finish());
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{env, fs, io, mem};
+
+use crate::dir::Dir;
+use crate::error::{Context, Error, Operation, Result};
+use crate::glob::Glob;
+
+/// The ignore files in effect at some point of a scan.
+///
+/// Paths are kept relative to an anchor: the root of the git work tree
+/// containing the scanned directory, or the scanned directory itself if it
+/// is not inside one. Rules from deeper files take precedence over shallower
+/// ones, and the global excludes come last. Loaded files are shared
+/// between clones, which parallel scans make for each directory.
+///
+/// An ignore file that cannot be read is left out, as git leaves it out with
+/// a warning, and its error kept for the walk to record.
+pub(crate) struct IgnoreStack {
+    root_prefix: PathBuf,
+    levels: Vec<Arc<IgnoreFile>>,
+    global: Vec<Arc<IgnoreFile>>,
+    /// The errors of ignore files left out since the walk last took them.
+    unread: Vec<Error>,
+}
+
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    glob: Glob,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+impl Clone for IgnoreStack {
+    /// Clones the files in effect, leaving the errors to be recorded once.
+    fn clone(&self) -> Self {
+        IgnoreStack {
+            root_prefix: self.root_prefix.clone(),
+            levels: self.levels.clone(),
+            global: self.global.clone(),
+            unread: vec![],
+        }
+    }
+}
+
+impl IgnoreStack {
+    /// Loads the global excludes and any ignore files in the directories
+    /// between the work tree root and `root_path`.
+    pub(crate) fn new(root_path: &Path) -> Result<Self> {
+        let canonical_root =
+            fs::canonicalize(root_path).context(Operation::Canonicalize, root_path, 0)?;
+        let work_tree = canonical_root
+            .ancestors()
+            .find(|dir| dir.join(".git").exists());
+
+        let mut stack = IgnoreStack {
+            root_prefix: PathBuf::new(),
+            levels: vec![],
+            global: vec![],
+            unread: vec![],
+        };
+        let work_tree = match work_tree {
+            Some(work_tree) => work_tree,
+            None => {
+                stack.load_global(global_excludes_file(None));
+                return Ok(stack);
+            }
+        };
+        stack.root_prefix = canonical_root
+            .strip_prefix(work_tree)
+            .unwrap()
+            .to_path_buf();
+
+        let mut dir = PathBuf::new();
+        for component in stack.root_prefix.clone().iter() {
+            stack.push_dir(&Dir::Path, &work_tree.join(&dir), &dir, 0);
+            dir.push(component);
+        }
+
+        let git_dir = work_tree.join(".git");
+        stack.load_global(Some(git_dir.join("info").join("exclude")));
+        stack.load_global(global_excludes_file(Some(&git_dir)));
+        Ok(stack)
+    }
+
+    /// Loads the ignore files in `dir`, which is at `path` and
+    /// `relative_dir` below the scanned root, returning how many were found.
+    pub(crate) fn enter_dir(
+        &mut self,
+        dir: &Dir,
+        path: &Path,
+        relative_dir: &Path,
+        depth: usize,
+    ) -> usize {
+        let base = self.root_prefix.join(relative_dir);
+        self.push_dir(dir, path, &base, depth)
+    }
+
+    /// Takes the errors of the ignore files left out so far.
+    pub(crate) fn take_unread(&mut self) -> Vec<Error> {
+        mem::take(&mut self.unread)
+    }
+
+    /// Forgets the `count` most recently loaded ignore files.
+    pub(crate) fn leave_dir(&mut self, count: usize) {
+        self.levels.truncate(self.levels.len() - count);
+    }
+
+    /// Whether the entry at `relative_path` below the scanned root is
+    /// ignored.
+    pub(crate) fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if relative_path.file_name() == Some(".git".as_ref()) {
+            return true;
+        }
+
+        let path = self.root_prefix.join(relative_path);
+        self.levels
+            .iter()
+            .rev()
+            .chain(&self.global)
+            .find_map(|file| file.matches(&path, is_dir))
+            .unwrap_or(false)
+    }
+
+    fn load_global(&mut self, path: Option<PathBuf>) {
+        if let Some(path) = path {
+            let file = IgnoreFile::load(fs::read(&path), &path, PathBuf::new(), 0);
+            if let Some(file) = self.keep_readable(file) {
+                self.global.push(Arc::new(file));
+            }
+        }
+    }
+
+    fn push_dir(&mut self, dir: &Dir, path: &Path, base: &Path, depth: usize) -> usize {
+        let mut count = 0;
+        for name in IGNORE_FILES {
+            let path = path.join(name);
+            let contents = dir.read_file(name.as_ref(), &path);
+            let file = IgnoreFile::load(contents, &path, base.to_path_buf(), depth);
+            if let Some(file) = self.keep_readable(file) {
+                self.levels.push(Arc::new(file));
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// The file `loaded`, if there was one and it could be read.
+    fn keep_readable(&mut self, loaded: Result<Option<IgnoreFile>>) -> Option<IgnoreFile> {
+        loaded.unwrap_or_else(|error| {
+            self.unread.push(error);
+            None
+        })
+    }
+}
+
+impl IgnoreFile {
+    /// Parses an ignore file from `contents`, the result of reading the
+    /// file at `path`, which applies below `base`.
+    fn load(
+        contents: io::Result<Vec<u8>>,
+        path: &Path,
+        base: PathBuf,
+        depth: usize,
+    ) -> Result<Option<Self>> {
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(Operation::ReadFile, path, depth),
+        };
+
+        let rules = contents
+            .split(|&b| b == b'\n')
+            .filter_map(Rule::parse)
+            .collect();
+        Ok(Some(IgnoreFile { base, rules }))
+    }
+
+    /// Returns whether the last rule matching `path` ignores it, or `None`
+    /// if no rule matches.
+    fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative_path = path.strip_prefix(&self.base).ok()?.as_os_str().as_bytes();
+        let name = path.file_name()?.as_bytes();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only)
+                    && rule
+                        .glob
+                        .matches(if rule.anchored { relative_path } else { name })
+            })
+            .map(|rule| !rule.negated)
+    }
+}
+
+impl Rule {
+    fn parse(line: &[u8]) -> Option<Self> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+
+        // Trailing spaces are dropped unless escaped with a backslash.
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() {
+            return None;
+        }
+
+        let negated = line[0] == b'!';
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains(&b'/');
+        if line.starts_with(b"/") {
+            line = &line[1..];
+        }
+        if line.is_empty() {
+            return None;
+        }
+
+        let glob = Glob::new(&String::from_utf8_lossy(line)).ok()?;
+        Some(Rule {
+            glob,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+}
+
+/// Finds the file named by `core.excludesFile`, checking the repository
+/// config before the user's, or git's default location if neither sets it.
+fn global_excludes_file(git_dir: Option<&Path>) -> Option<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+
+    let mut configs = vec![];
+    configs.extend(git_dir.map(|git_dir| git_dir.join("config")));
+    configs.extend(home.as_ref().map(|home| home.join(".gitconfig")));
+    configs.extend(
+        config_home
+            .as_ref()
+            .map(|dir| dir.join("git").join("config")),
+    );
+
+    configs
+        .iter()
+        .find_map(|config| read_excludes_file(config))
+        .map(|path| match (path.strip_prefix("~/"), &home) {
+            (Ok(rest), Some(home)) => home.join(rest),
+            _ => path,
+        })
+        .or_else(|| config_home.map(|dir| dir.join("git").join("ignore")))
+}
+
+/// Reads `core.excludesFile` from a git config file, if it sets it. As in
+/// git, a later setting overrides an earlier one.
+fn read_excludes_file(config: &Path) -> Option<PathBuf> {
+    let contents = fs::read_to_string(config).ok()?;
+
+    let mut excludes_file = None;
+    let mut in_core = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_core = line
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .trim()
+                .eq_ignore_ascii_case("core");
+        } else if in_core {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            if key.eq_ignore_ascii_case("excludesfile") {
+                if let Some(value) = parts.next() {
+                    excludes_file = Some(PathBuf::from(value.trim().trim_matches('"')));
+                }
+            }
+        }
+    }
+    excludes_file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    use super::{read_excludes_file, Rule};
+
+    /// Parses `line`, returning its flags and whether its pattern matches
+    /// `text`.
+    fn parse(line: &str, text: &str) -> Option<(bool, bool, bool, bool)> {
+        let rule = Rule::parse(line.as_bytes())?;
+        let matches = rule.glob.matches(text.as_bytes());
+        Some((rule.negated, rule.dir_only, rule.anchored, matches))
+    }
+
+    #[test]
+    fn parses_plain_patterns() {
+        assert_eq!(parse("*.o", "main.o"), Some((false, false, false, true)));
+        assert_eq!(parse("*.o\r", "main.o"), Some((false, false, false, true)));
+        assert_eq!(
+            parse("target", "targets"),
+            Some((false, false, false, false))
+        );
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        for line in &["", "\r", "# comment", "#", "   ", " \r", "!", "/", "!/"] {
+            assert!(Rule::parse(line.as_bytes()).is_none(), "{:?}", line);
+        }
+        assert_eq!(parse("\\#hash", "#hash"), Some((false, false, false, true)));
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(parse("!keep.o", "keep.o"), Some((true, false, false, true)));
+        assert_eq!(parse("\\!bang", "!bang"), Some((false, false, false, true)));
+    }
+
+    #[test]
+    fn parses_anchoring() {
+        assert_eq!(parse("/target", "target"), Some((false, false, true, true)));
+        assert_eq!(
+            parse("docs/*.md", "docs/a.md"),
+            Some((false, false, true, true))
+        );
+        assert_eq!(
+            parse("docs/*.md", "a/docs/a.md"),
+            Some((false, false, true, false))
+        );
+        assert_eq!(
+            parse("**/build", "a/b/build"),
+            Some((false, false, true, true))
+        );
+    }
+
+    #[test]
+    fn parses_dir_only_rules() {
+        assert_eq!(parse("target/", "target"), Some((false, true, false, true)));
+        assert_eq!(parse("/out/", "out"), Some((false, true, true, true)));
+        assert_eq!(parse("!logs/", "logs"), Some((true, true, false, true)));
+    }
+
+    #[test]
+    fn drops_unescaped_trailing_spaces() {
+        assert_eq!(
+            parse("a.txt   ", "a.txt"),
+            Some((false, false, false, true))
+        );
+        assert_eq!(parse("a\\ ", "a "), Some((false, false, false, true)));
+        assert_eq!(parse("a\\ ", "a"), Some((false, false, false, false)));
+        assert_eq!(parse("dir/  ", "dir"), Some((false, true, false, true)));
+    }
+
+    #[test]
+    fn reads_the_last_excludes_file_set() {
+        let config = env::temp_dir().join(format!("file_tree_gitconfig_{}", process::id()));
+        fs::write(
+            &config,
+            "[core]\n\texcludesFile = ~/first\n[user]\n\texcludesfile = user\n\
+             [ core ]\n\texcludesfile = \"~/last\"\n\texcludesfile\n",
+        )
+        .unwrap();
+        assert_eq!(read_excludes_file(&config), Some(PathBuf::from("~/last")));
+        fs::write(&config, "[user]\n\texcludesfile = user\n").unwrap();
+        assert_eq!(read_excludes_file(&config), None);
+        fs::remove_file(&config).unwrap();
+    }
+}
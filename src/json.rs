@@ -0,0 +1,1202 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::{error, fmt, io, mem, slice};
+
+use crate::diff::{Change, DiffEntry, EntryState, TreeDiff};
+use crate::entry::{Device, Entry, EntryData, EntryKind, SizeKind};
+use crate::error::{Error, Operation};
+use crate::link::{Link, LinkStatus};
+use crate::metadata::{self, Metadata};
+use crate::tree::FileTree;
+use crate::walk::Event;
+
+/// The version of the JSON layout written by [`FileTree::to_json`].
+const VERSION: u64 = 1;
+
+/// JSON that could not be read back into a [`FileTree`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonError {
+    message: String,
+}
+
+/// A parsed JSON value. Numbers keep their text so that 64-bit integers
+/// survive the round trip.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl JsonError {
+    fn new(message: impl Into<String>) -> Self {
+        JsonError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid tree JSON: {}", self.message)
+    }
+}
+
+impl error::Error for JsonError {}
+
+impl Value {
+    pub(crate) fn parse(text: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    pub(crate) fn number(n: impl fmt::Display) -> Self {
+        Value::Number(n.to_string())
+    }
+
+    pub(crate) fn string(s: impl Into<String>) -> Self {
+        Value::String(s.into())
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    fn as_number<T: std::str::FromStr>(&self) -> Option<T> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Appends the compact JSON text of this value to `out`.
+    pub(crate) fn write(&self, out: &mut String) {
+        let mut stack = vec![Piece::Value(self)];
+        while let Some(piece) = stack.pop() {
+            let value = match piece {
+                Piece::Value(value) => value,
+                Piece::Key(key) => {
+                    write_string(key, out);
+                    out.push(':');
+                    continue;
+                }
+                Piece::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+            };
+            match value {
+                Value::Null => out.push_str("null"),
+                Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+                Value::Number(n) => out.push_str(n),
+                Value::String(s) => write_string(s, out),
+                Value::Array(values) => {
+                    out.push('[');
+                    stack.push(Piece::Text("]"));
+                    for (i, value) in values.iter().enumerate().rev() {
+                        stack.push(Piece::Value(value));
+                        if i > 0 {
+                            stack.push(Piece::Text(","));
+                        }
+                    }
+                }
+                Value::Object(fields) => {
+                    out.push('{');
+                    stack.push(Piece::Text("}"));
+                    for (i, (key, value)) in fields.iter().enumerate().rev() {
+                        stack.push(Piece::Value(value));
+                        stack.push(Piece::Key(key));
+                        if i > 0 {
+                            stack.push(Piece::Text(","));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the arrays and objects directly inside this value to `out`.
+    fn take_nested(&mut self, out: &mut Vec<Value>) {
+        let nested = |value: &mut Value| match value {
+            Value::Array(_) | Value::Object(_) => Some(mem::replace(value, Value::Null)),
+            _ => None,
+        };
+        match self {
+            Value::Array(values) => out.extend(values.iter_mut().filter_map(nested)),
+            Value::Object(fields) => {
+                out.extend(fields.iter_mut().filter_map(|(_, value)| nested(value)))
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        // Dropping nested values from inside their parent's drop would
+        // recurse once per level, so they are moved out and dropped here.
+        let mut stack = vec![];
+        self.take_nested(&mut stack);
+        while let Some(mut value) = stack.pop() {
+            value.take_nested(&mut stack);
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out);
+        f.write_str(&out)
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// What is left to write of a value, see [`Value::write`].
+enum Piece<'a> {
+    Value(&'a Value),
+    Key(&'a str),
+    Text(&'static str),
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+/// An array or object being parsed.
+enum Open {
+    Array(Vec<Value>),
+    /// The fields so far, and the key of the value being parsed.
+    Object(Vec<(String, Value)>, String),
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError::new(format!("{} at byte {}", message, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.bytes.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    /// Parses a value. Arrays and objects being parsed are kept on a stack
+    /// rather than the call stack, so that they can nest to any depth.
+    fn value(&mut self) -> Result<Value, JsonError> {
+        let mut stack = vec![];
+        loop {
+            self.skip_whitespace();
+            let mut value = match self.bytes.get(self.pos) {
+                Some(b'{') => {
+                    self.pos += 1;
+                    if self.eat(b'}') {
+                        Value::Object(vec![])
+                    } else {
+                        stack.push(Open::Object(vec![], self.key()?));
+                        continue;
+                    }
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    if self.eat(b']') {
+                        Value::Array(vec![])
+                    } else {
+                        stack.push(Open::Array(vec![]));
+                        continue;
+                    }
+                }
+                Some(b'"') => Value::String(self.string()?),
+                Some(b'-' | b'0'..=b'9') => self.number()?,
+                Some(_) => self.word()?,
+                None => return Err(self.error("unexpected end")),
+            };
+
+            // Add the value to the array or object it is in, closing those it
+            // is the last value of.
+            loop {
+                let open = match stack.last_mut() {
+                    Some(open) => open,
+                    None => return Ok(value),
+                };
+                let closed = match open {
+                    Open::Array(values) => {
+                        values.push(value);
+                        self.eat(b']')
+                    }
+                    Open::Object(fields, key) => {
+                        fields.push((mem::take(key), value));
+                        self.eat(b'}')
+                    }
+                };
+                if !closed {
+                    self.expect(b',')?;
+                    if let Open::Object(_, key) = open {
+                        *key = self.key()?;
+                    }
+                    break;
+                }
+                value = match stack.pop().unwrap() {
+                    Open::Array(values) => Value::Array(values),
+                    Open::Object(fields, _) => Value::Object(fields),
+                };
+            }
+        }
+    }
+
+    /// Parses an object's key and the colon after it.
+    fn key(&mut self) -> Result<String, JsonError> {
+        self.skip_whitespace();
+        let key = self.string()?;
+        self.expect(b':')?;
+        Ok(key)
+    }
+
+    fn word(&mut self) -> Result<Value, JsonError> {
+        for (word, value) in [
+            ("null", Value::Null),
+            ("true", Value::Bool(true)),
+            ("false", Value::Bool(false)),
+        ] {
+            if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        Err(self.error("unexpected character"))
+    }
+
+    /// Parses a number as JSON writes them: no leading zeros, and digits on
+    /// both sides of a decimal point and after an exponent's sign.
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.pos;
+        self.skip_byte(|b| b == b'-');
+        let integer = self.pos;
+        let mut valid = match self.digits() {
+            0 => false,
+            1 => true,
+            _ => self.bytes[integer] != b'0',
+        };
+        if self.skip_byte(|b| b == b'.') {
+            valid &= self.digits() > 0;
+        }
+        if self.skip_byte(|b| b == b'e' || b == b'E') {
+            self.skip_byte(|b| b == b'+' || b == b'-');
+            valid &= self.digits() > 0;
+        }
+        if !valid {
+            return Err(self.error("invalid number"));
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        Ok(Value::Number(text.to_string()))
+    }
+
+    /// Skips the next byte if it is one `accept` takes.
+    fn skip_byte(&mut self, accept: impl Fn(u8) -> bool) -> bool {
+        let skipped = self.bytes.get(self.pos).is_some_and(|&b| accept(b));
+        if skipped {
+            self.pos += 1;
+        }
+        skipped
+    }
+
+    /// Skips any digits, returning how many there were.
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.skip_byte(|b| b.is_ascii_digit()) {}
+        self.pos - start
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if self.bytes.get(self.pos) != Some(&b'"') {
+            return Err(self.error("expected string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(&b) = self.bytes.get(self.pos) {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // The input is a `str`, and quotes and backslashes are never part
+            // of a multi-byte character, so this slice is valid UTF-8.
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).unwrap());
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    out.push(self.escape()?);
+                }
+                Some(_) => return Err(self.error("control character in string")),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, JsonError> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| self.error("unterminated string"))?;
+        self.pos += 1;
+        Ok(match byte {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let high = self.hex4()?;
+                let code = if (0xd800..0xdc00).contains(&high) {
+                    if !self.bytes[self.pos..].starts_with(b"\\u") {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    self.pos += 2;
+                    let low = self.hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                } else {
+                    high
+                };
+                char::from_u32(code).ok_or_else(|| self.error("invalid escape"))?
+            }
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes what [`base64_encode`] wrote, which is always padded, with the
+/// bits past the last byte left zero.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let unpadded = text.trim_end_matches('=');
+    if !text.len().is_multiple_of(4) || text.len() - unpadded.len() > 2 {
+        return None;
+    }
+    let mut out = vec![];
+    for chunk in unpadded.as_bytes().chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let digit = BASE64.iter().position(|&b| b == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        if n & (0xff_ffff >> (8 * (chunk.len() - 1))) != 0 {
+            return None;
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Builds JSON objects, leaving out fields that have no value.
+#[derive(Default)]
+pub(crate) struct Object(Vec<(String, Value)>);
+
+impl Object {
+    pub(crate) fn field(mut self, key: &str, value: impl Into<Option<Value>>) -> Self {
+        if let Some(value) = value.into() {
+            self.0.push((key.to_string(), value));
+        }
+        self
+    }
+
+    /// Adds a name or path as a string under `key`, or base64-encoded under
+    /// `<key>_base64` if it is not valid UTF-8.
+    pub(crate) fn os_str(self, key: &str, s: &OsStr) -> Self {
+        match s.to_str() {
+            Some(s) => self.field(key, Value::string(s)),
+            None => self.field(
+                &format!("{}_base64", key),
+                Value::String(base64_encode(s.as_bytes())),
+            ),
+        }
+    }
+
+    pub(crate) fn extend(mut self, other: Object) -> Self {
+        self.0.extend(other.0);
+        self
+    }
+
+    pub(crate) fn build(self) -> Value {
+        Value::Object(self.0)
+    }
+}
+
+/// Reads a field written by [`Object::os_str`].
+fn os_string(value: &Value, key: &str) -> Result<Option<OsString>, JsonError> {
+    if let Some(s) = value.get(key) {
+        let s = s
+            .as_str()
+            .ok_or_else(|| JsonError::new(format!("{} is not a string", key)))?;
+        return Ok(Some(OsString::from(s)));
+    }
+    match value.get(&format!("{}_base64", key)) {
+        Some(encoded) => encoded
+            .as_str()
+            .and_then(base64_decode)
+            .map(|bytes| Some(OsString::from_vec(bytes)))
+            .ok_or_else(|| JsonError::new(format!("{}_base64 is not valid base64", key))),
+        None => Ok(None),
+    }
+}
+
+fn time(time: Option<SystemTime>) -> Option<Value> {
+    let (secs, nanos) = metadata::split_time(time?);
+    Some(
+        Object::default()
+            .field("secs", Value::number(secs))
+            .field("nanos", Value::number(nanos))
+            .build(),
+    )
+}
+
+fn read_time(value: &Value, key: &str) -> Result<Option<SystemTime>, JsonError> {
+    let value = match value.get(key) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    metadata::timestamp(required(value, "secs")?, required(value, "nanos")?)
+        .map(Some)
+        .ok_or_else(|| JsonError::new(format!("{} is out of range", key)))
+}
+
+fn required<T: std::str::FromStr>(value: &Value, key: &str) -> Result<T, JsonError> {
+    value
+        .get(key)
+        .and_then(Value::as_number)
+        .ok_or_else(|| JsonError::new(format!("missing or invalid number {}", key)))
+}
+
+fn required_str<'a>(value: &'a Value, key: &str) -> Result<&'a str, JsonError> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| JsonError::new(format!("missing or invalid string {}", key)))
+}
+
+impl Event<'_> {
+    /// Writes the event as a single line of JSON, with an `event` field of
+    /// `enter_dir`, `entry` or `exit_dir` and the same entry fields as
+    /// [`FileTree::to_json`], minus children.
+    pub fn to_json(&self) -> String {
+        let (name, path, depth, entry) = match *self {
+            Event::EnterDir { path, depth } => ("enter_dir", path, depth, None),
+            Event::Entry { path, depth, entry } => ("entry", path, depth, Some(entry)),
+            Event::ExitDir { path, depth, entry } => ("exit_dir", path, depth, Some(entry)),
+        };
+        Object::default()
+            .field("event", Value::string(name))
+            .os_str("path", path.as_os_str())
+            .field("depth", Value::number(depth))
+            .extend(entry.map(entry_fields).unwrap_or_default())
+            .build()
+            .to_string()
+    }
+}
+
+impl TreeDiff {
+    /// Writes the diff as a single line of JSON. Only changed entries are
+    /// listed, each directory giving the number of unchanged entries in it.
+    pub fn to_json(&self) -> String {
+        let summary = self.summary();
+        Object::default()
+            .field("version", Value::number(VERSION))
+            .os_str("old_root", self.old_root().as_os_str())
+            .os_str("new_root", self.new_root().as_os_str())
+            .field(
+                "summary",
+                Object::default()
+                    .field("added", Value::number(summary.added))
+                    .field("removed", Value::number(summary.removed))
+                    .field("modified", Value::number(summary.modified))
+                    .field("moved", Value::number(summary.moved))
+                    .build(),
+            )
+            .field("root", diff_entry_to_json(self.root()))
+            .build()
+            .to_string()
+    }
+}
+
+/// The fields describing a changed entry itself, without its children.
+fn diff_entry_fields(entry: &DiffEntry) -> Object {
+    let change = match entry.change() {
+        Change::Unchanged => "unchanged",
+        Change::Added => "added",
+        Change::Removed => "removed",
+        Change::Modified => "modified",
+        Change::Moved => "moved",
+    };
+    let changes = entry.changes();
+    let changed_fields: Vec<Value> = [
+        (changes.kind, "kind"),
+        (changes.size, "size"),
+        (changes.modified, "modified"),
+        (changes.symlink_target, "symlink_target"),
+    ]
+    .iter()
+    .filter(|(changed, _)| *changed)
+    .map(|(_, name)| Value::string(*name))
+    .collect();
+    let state = |state: &EntryState| {
+        let mut object = Object::default()
+            .field("kind", Value::string(kind_name(state.kind)))
+            .field("size", Value::number(state.size))
+            .field("disk_usage", Value::number(state.disk_usage))
+            .field("modified", time(state.modified));
+        if let Some(target) = &state.symlink_target {
+            object = object.os_str("symlink_target", target.as_os_str());
+        }
+        object.build()
+    };
+    let mut moved = Object::default();
+    if let Some(other) = entry.moved() {
+        let key = match entry.change() {
+            Change::Moved => "moved_from",
+            _ => "moved_to",
+        };
+        moved = moved
+            .os_str(key, other.path().as_os_str())
+            .field("similarity", Value::number(other.similarity()));
+    }
+
+    Object::default()
+        .os_str("name", entry.name())
+        .field("change", Value::string(change))
+        .extend(moved)
+        .field(
+            "changes",
+            Some(Value::Array(changed_fields)).filter(|_| !changes.is_empty()),
+        )
+        .field("before", entry.before().map(state))
+        .field("after", entry.after().map(state))
+        .field(
+            "size_delta",
+            Value::number(entry.size_delta(SizeKind::Apparent)),
+        )
+        .field(
+            "disk_usage_delta",
+            Value::number(entry.size_delta(SizeKind::Allocated)),
+        )
+}
+
+fn diff_entry_to_json(entry: &DiffEntry) -> Value {
+    // Entries being written, each with its fields, the children still to
+    // write and those written.
+    let mut stack: Vec<(Object, &DiffEntry, slice::Iter<DiffEntry>, Vec<Value>)> = vec![];
+    let mut next = entry;
+    loop {
+        stack.push((
+            diff_entry_fields(next),
+            next,
+            next.children().iter(),
+            vec![],
+        ));
+        loop {
+            let (_, _, children, _) = stack.last_mut().unwrap();
+            if let Some(child) = children.find(|child| child.change() != Change::Unchanged) {
+                next = child;
+                break;
+            }
+            let (fields, entry, _, values) = stack.pop().unwrap();
+            let unchanged = entry.children().len() - values.len();
+            let value = fields
+                .field(
+                    "children",
+                    Some(Value::Array(values)).filter(|_| !entry.children().is_empty()),
+                )
+                .field(
+                    "unchanged",
+                    Some(Value::number(unchanged)).filter(|_| unchanged > 0),
+                )
+                .build();
+            match stack.last_mut() {
+                Some((_, _, _, values)) => values.push(value),
+                None => return value,
+            }
+        }
+    }
+}
+
+pub(crate) fn tree_to_json(tree: &FileTree) -> Value {
+    Object::default()
+        .field("version", Value::number(VERSION))
+        .os_str("root_path", tree.root_path().as_os_str())
+        .field("root", entry_to_json(tree.root()))
+        .build()
+}
+
+pub(crate) fn tree_from_json(value: &Value) -> Result<FileTree, JsonError> {
+    let version: u64 = required(value, "version")?;
+    if version != VERSION {
+        return Err(JsonError::new(format!("unsupported version {}", version)));
+    }
+    let root_path = os_string(value, "root_path")?
+        .map(PathBuf::from)
+        .ok_or_else(|| JsonError::new("missing root_path"))?;
+    let root = value
+        .get("root")
+        .ok_or_else(|| JsonError::new("missing root"))?;
+    Ok(FileTree::new(root_path, entry_from_json(root)?))
+}
+
+pub(crate) fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "file",
+        EntryKind::Symlink => "symlink",
+        EntryKind::Directory => "directory",
+        EntryKind::Fifo => "fifo",
+        EntryKind::Socket => "socket",
+        EntryKind::BlockDevice => "block_device",
+        EntryKind::CharDevice => "char_device",
+        EntryKind::Unknown => "unknown",
+        EntryKind::Loop => "loop",
+        EntryKind::Error => "error",
+    }
+}
+
+/// The fields describing an entry itself, without its children.
+pub(crate) fn entry_fields(entry: &Entry) -> Object {
+    let device = match &entry.data {
+        EntryData::BlockDevice(device) | EntryData::CharDevice(device) => Some(
+            Object::default()
+                .field("major", Value::number(device.major))
+                .field("minor", Value::number(device.minor))
+                .build(),
+        ),
+        _ => None,
+    };
+    let error = match &entry.data {
+        EntryData::Error(error) => Some(
+            Object::default()
+                .field(
+                    "operation",
+                    Value::string(operation_name(error.operation())),
+                )
+                .os_str("path", error.path().as_os_str())
+                .field("depth", Value::number(error.depth()))
+                .field("message", Value::string(error.io_error().to_string()))
+                .field(
+                    "os_error",
+                    error.io_error().raw_os_error().map(Value::number),
+                )
+                .build(),
+        ),
+        _ => None,
+    };
+    let link = entry.link().map(|link| {
+        let mut object = Object::default().os_str("target", link.target().as_os_str());
+        if let Some(resolved) = link.resolved() {
+            object = object.os_str("resolved", resolved.as_os_str());
+        }
+        object
+            .field("status", Value::string(link_status_name(link.status())))
+            .field("outside_root", Value::Bool(link.is_outside_root()))
+            .build()
+    });
+
+    Object::default()
+        .os_str("name", &entry.name)
+        .field("kind", Value::string(kind_name(entry.kind())))
+        .field("size", Value::number(entry.size))
+        .field("disk_usage", Value::number(entry.disk_usage))
+        .field("links", Value::number(entry.links))
+        .field("modified", time(entry.modified))
+        .field("ignored", Some(Value::Bool(true)).filter(|_| entry.ignored))
+        .field("link", link)
+        .field("device", device)
+        .field("error", error)
+        .field("metadata", entry.metadata().map(metadata_to_json))
+        .field(
+            "content_hash",
+            entry
+                .content_hash
+                .map(|hash| Value::String(format!("{:016x}", hash))),
+        )
+}
+
+fn entry_to_json(entry: &Entry) -> Value {
+    // Directories being written, each with its fields, the children still
+    // to write and those written.
+    let mut stack: Vec<(Object, slice::Iter<Entry>, Vec<Value>)> = vec![];
+    let mut next = entry;
+    loop {
+        let mut value = match next.children() {
+            Some(children) => {
+                stack.push((entry_fields(next), children.iter(), vec![]));
+                None
+            }
+            None => Some(entry_fields(next).build()),
+        };
+        loop {
+            let (_, children, values) = match stack.last_mut() {
+                Some(dir) => dir,
+                None => return value.unwrap(),
+            };
+            values.extend(value.take());
+            if let Some(child) = children.next() {
+                next = child;
+                break;
+            }
+            let (fields, _, values) = stack.pop().unwrap();
+            value = Some(fields.field("children", Value::Array(values)).build());
+        }
+    }
+}
+
+fn metadata_to_json(metadata: &Metadata) -> Value {
+    Object::default()
+        .field("mode", Value::number(metadata.mode))
+        .field("uid", Value::number(metadata.uid))
+        .field("gid", Value::number(metadata.gid))
+        .field("inode", Value::number(metadata.inode))
+        .field("dev", Value::number(metadata.dev))
+        .field("nlink", Value::number(metadata.nlink))
+        .field("accessed", time(metadata.accessed))
+        .field("modified", time(metadata.modified))
+        .field("changed", time(metadata.changed))
+        .field("created", time(metadata.created))
+        .build()
+}
+
+fn entry_from_json(value: &Value) -> Result<Entry, JsonError> {
+    // Directories being read, each with its children still to read.
+    let mut stack: Vec<(Entry, slice::Iter<Value>)> = vec![];
+    let mut next = value;
+    loop {
+        let entry = entry_without_children(next)?;
+        let mut entry = if entry.is_dir() {
+            let children = match next.get("children") {
+                Some(children) => children
+                    .as_array()
+                    .ok_or_else(|| JsonError::new("children is not an array"))?,
+                None => &[],
+            };
+            stack.push((entry, children.iter()));
+            None
+        } else if next.get("children").is_some() {
+            return Err(JsonError::new(format!(
+                "children on a {}",
+                kind_name(entry.kind())
+            )));
+        } else {
+            Some(entry)
+        };
+        loop {
+            let (dir, children) = match stack.last_mut() {
+                Some(dir) => dir,
+                None => return Ok(entry.unwrap()),
+            };
+            if let (Some(entry), EntryData::Directory(entries)) = (entry.take(), &mut dir.data) {
+                entries.push(entry);
+            }
+            if let Some(child) = children.next() {
+                next = child;
+                break;
+            }
+            entry = Some(stack.pop().unwrap().0);
+        }
+    }
+}
+
+/// Reads an entry, leaving a directory's children out.
+fn entry_without_children(value: &Value) -> Result<Entry, JsonError> {
+    let name = os_string(value, "name")?.ok_or_else(|| JsonError::new("missing name"))?;
+    let link = match value.get("link") {
+        Some(link) => Some(Box::new(link_from_json(link)?)),
+        None => None,
+    };
+    let device = || -> Result<Device, JsonError> {
+        let device = value
+            .get("device")
+            .ok_or_else(|| JsonError::new("missing device"))?;
+        Ok(Device {
+            major: required(device, "major")?,
+            minor: required(device, "minor")?,
+        })
+    };
+
+    let data = match required_str(value, "kind")? {
+        "file" => EntryData::File,
+        "symlink" => EntryData::Symlink(
+            link.as_ref()
+                .map(|link| link.target.clone())
+                .ok_or_else(|| JsonError::new("symlink without link"))?,
+        ),
+        "directory" => EntryData::Directory(vec![]),
+        "fifo" => EntryData::Fifo,
+        "socket" => EntryData::Socket,
+        "block_device" => EntryData::BlockDevice(device()?),
+        "char_device" => EntryData::CharDevice(device()?),
+        "unknown" => EntryData::Unknown,
+        "loop" => EntryData::Loop,
+        "error" => EntryData::Error(error_from_json(
+            value
+                .get("error")
+                .ok_or_else(|| JsonError::new("error entry without error"))?,
+        )?),
+        kind => return Err(JsonError::new(format!("unknown kind {}", kind))),
+    };
+
+    Ok(Entry {
+        name,
+        size: required(value, "size")?,
+        disk_usage: required(value, "disk_usage")?,
+        links: required(value, "links")?,
+        modified: read_time(value, "modified")?,
+        ignored: value
+            .get("ignored")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        link,
+        metadata: match value.get("metadata") {
+            Some(metadata) => Some(Box::new(metadata_from_json(metadata)?)),
+            None => None,
+        },
+        content_hash: match value.get("content_hash") {
+            Some(hash) => Some(
+                hash.as_str()
+                    .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+                    .ok_or_else(|| JsonError::new("invalid content_hash"))?,
+            ),
+            None => None,
+        },
+        data,
+    })
+}
+
+fn link_from_json(value: &Value) -> Result<Link, JsonError> {
+    let status = match required_str(value, "status")? {
+        "file" => LinkStatus::File,
+        "directory" => LinkStatus::Directory,
+        "other" => LinkStatus::Other,
+        "dangling" => LinkStatus::Dangling,
+        "loop" => LinkStatus::Loop,
+        status => return Err(JsonError::new(format!("unknown link status {}", status))),
+    };
+    Ok(Link {
+        target: os_string(value, "target")?
+            .map(PathBuf::from)
+            .ok_or_else(|| JsonError::new("missing link target"))?,
+        resolved: os_string(value, "resolved")?.map(PathBuf::from),
+        status,
+        outside_root: value
+            .get("outside_root")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+fn metadata_from_json(value: &Value) -> Result<Metadata, JsonError> {
+    Ok(Metadata {
+        mode: required(value, "mode")?,
+        uid: required(value, "uid")?,
+        gid: required(value, "gid")?,
+        inode: required(value, "inode")?,
+        dev: required(value, "dev")?,
+        nlink: required(value, "nlink")?,
+        accessed: read_time(value, "accessed")?,
+        modified: read_time(value, "modified")?,
+        changed: read_time(value, "changed")?,
+        created: read_time(value, "created")?,
+    })
+}
+
+/// Rebuilds a scan error. The original `io::Error` cannot be recovered
+/// exactly, so OS errors are recreated from their code and anything else
+/// keeps only its message.
+fn error_from_json(value: &Value) -> Result<Error, JsonError> {
+    let operation = match required_str(value, "operation")? {
+        "read_dir" => Operation::ReadDir,
+        "stat" => Operation::Stat,
+        "read_link" => Operation::ReadLink,
+        "read_file" => Operation::ReadFile,
+        "canonicalize" => Operation::Canonicalize,
+        "write_event" => Operation::WriteEvent,
+        operation => return Err(JsonError::new(format!("unknown operation {}", operation))),
+    };
+    let source = match value.get("os_error").and_then(Value::as_number) {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::other(required_str(value, "message")?),
+    };
+    Ok(Error::new(
+        operation,
+        os_string(value, "path")?.unwrap_or_default(),
+        required(value, "depth")?,
+        source,
+    ))
+}
+
+fn operation_name(operation: Operation) -> &'static str {
+    match operation {
+        Operation::ReadDir => "read_dir",
+        Operation::Stat => "stat",
+        Operation::ReadLink => "read_link",
+        Operation::ReadFile => "read_file",
+        Operation::Canonicalize => "canonicalize",
+        Operation::WriteEvent => "write_event",
+    }
+}
+
+fn link_status_name(status: LinkStatus) -> &'static str {
+    match status {
+        LinkStatus::File => "file",
+        LinkStatus::Directory => "directory",
+        LinkStatus::Other => "other",
+        LinkStatus::Dangling => "dangling",
+        LinkStatus::Loop => "loop",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::{base64_decode, base64_encode};
+    use crate::entry::EntryData;
+    use crate::tree::FileTree;
+
+    /// A tree whose root path, names and link target are not UTF-8.
+    const NON_UTF8: &str = r#"{"version":1,"root_path_base64":"L3Iv/w==","root":{"name_base64":"/w==","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[
+        {"name_base64":"Y2Fm6Q==","kind":"file","size":1,"disk_usage":0,"links":1},
+        {"name":"link","kind":"symlink","size":0,"disk_usage":0,"links":1,"link":{"target_base64":"Y2Fm6Q==","resolved_base64":"L3Iv/y9jYWbp","status":"file","outside_root":false}}
+    ]}}"#;
+
+    fn error(json: &str) -> String {
+        match FileTree::from_json(json) {
+            Ok(_) => panic!("read {}", json),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trips_base64() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            let encoded = base64_encode(&bytes[..len]);
+            assert!(encoded.len().is_multiple_of(4));
+            assert_eq!(base64_decode(&encoded).as_deref(), Some(&bytes[..len]));
+        }
+        assert_eq!(base64_encode(b"caf\xe9"), "Y2Fm6Q==");
+    }
+
+    #[test]
+    fn round_trips_names_that_are_not_utf8() {
+        let tree = FileTree::from_json(NON_UTF8).unwrap();
+        assert_eq!(tree.root_path().as_os_str().as_bytes(), b"/r/\xff");
+        assert_eq!(tree.root().name().as_bytes(), b"\xff");
+        let children = tree.root().children().unwrap();
+        assert_eq!(children[0].name(), OsStr::from_bytes(b"caf\xe9"));
+        match children[1].data() {
+            EntryData::Symlink(target) => assert_eq!(target.as_os_str().as_bytes(), b"caf\xe9"),
+            _ => panic!("expected a symlink"),
+        }
+        let resolved = children[1].link().unwrap().resolved().unwrap();
+        assert_eq!(resolved, Path::new(OsStr::from_bytes(b"/r/\xff/caf\xe9")));
+
+        let json = tree.to_json();
+        assert!(json.contains(r#""name_base64":"Y2Fm6Q==""#), "{}", json);
+        assert_eq!(FileTree::from_json(&json).unwrap().to_json(), json);
+    }
+
+    #[test]
+    fn fails_on_truncated_input() {
+        for len in 0..NON_UTF8.len() {
+            assert!(FileTree::from_json(&NON_UTF8[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn fails_on_wrong_types() {
+        let tree = |root: &str| format!(r#"{{"version":1,"root_path":"/r","root":{}}}"#, root);
+        let file = r#"{"name":"f","kind":"file","size":1,"disk_usage":0,"links":1}"#;
+        assert!(FileTree::from_json(&tree(file)).is_ok());
+
+        assert!(error(r#"{"version":"1","root_path":"/r","root":{}}"#).contains("version"));
+        assert!(error(r#"{"version":1,"root_path":7,"root":{}}"#).contains("root_path"));
+        assert!(error(&tree(&file.replace(r#""f""#, "[]"))).contains("name"));
+        assert!(error(&tree(&file.replace("1,", r#""1","#))).contains("size"));
+        assert!(error(&tree(&file.replace(r#""file""#, "1"))).contains("kind"));
+        assert!(error(&tree(&file.replace(r#""file""#, r#""pipe""#))).contains("pipe"));
+        let dir =
+            r#"{"name":"d","kind":"directory","size":0,"disk_usage":0,"links":1,"children":{}}"#;
+        assert!(error(&tree(dir)).contains("children"));
+        let link = r#"{"name":"l","kind":"symlink","size":0,"disk_usage":0,"links":1,"link":{"target":"t","status":1}}"#;
+        assert!(error(&tree(link)).contains("status"));
+    }
+
+    #[test]
+    fn fails_on_children_of_a_file() {
+        let file = r#"{"name":"f","kind":"file","size":1,"disk_usage":0,"links":1,"children":[]}"#;
+        let json = format!(r#"{{"version":1,"root_path":"/r","root":{}}}"#, file);
+        assert!(error(&json).contains("children on a file"));
+    }
+
+    #[test]
+    fn fails_on_numbers_json_does_not_allow() {
+        let tree = |size: &str| {
+            format!(
+                r#"{{"version":1,"root_path":"/r","root":{{"name":"f","kind":"file","size":{},"disk_usage":0,"links":1}}}}"#,
+                size
+            )
+        };
+        assert!(FileTree::from_json(&tree("0")).is_ok());
+        assert!(FileTree::from_json(&tree("10")).is_ok());
+        for bad in &["01", "00", "-01", "-", "1.", "1.e1", "1e", "1e+"] {
+            assert!(error(&tree(bad)).contains("invalid number"), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn fails_on_control_characters_in_strings() {
+        let tree = |name: &str| {
+            format!(
+                r#"{{"version":1,"root_path":"/r","root":{{"name":"{}","kind":"file","size":0,"disk_usage":0,"links":1}}}}"#,
+                name
+            )
+        };
+        assert!(FileTree::from_json(&tree(r"a\tb")).is_ok());
+        for bad in &["a\tb", "a\nb", "\u{0}", "\u{1f}"] {
+            assert!(error(&tree(bad)).contains("control character"), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn fails_on_bad_base64() {
+        for bad in &[
+            "Y",
+            "Y2Fm6",
+            "Y2F!",
+            "Y2=m",
+            "Y2Fm6Q===",
+            "Y2Fm6===",
+            "Y2Fm6Q",
+            // Bits set past the last byte.
+            "Y2Fm6R==",
+            "Y2Fm6QB=",
+        ] {
+            let json = NON_UTF8.replace(
+                r#""Y2Fm6Q==","kind":"file""#,
+                &format!(r#""{}","kind":"file""#, bad),
+            );
+            assert!(error(&json).contains("name_base64"), "{}", bad);
+        }
+        let json = NON_UTF8.replace(r#""target_base64":"Y2Fm6Q==""#, r#""target_base64":5"#);
+        assert!(error(&json).contains("target_base64"));
+    }
+}
@@ -0,0 +1,60 @@
+//! Scans a directory into an in-memory tree of entries.
+//!
+//! Use [`FileTree::builder`] (or [`get_file_tree`] for the defaults) to scan
+//! a directory, then walk the result starting from [`FileTree::root`], with
+//! [`FileTree::depth_first`] or a [`Visitor`].
+//!
+//! With the `serde` feature, [`FileTree`], [`Entry`] and [`EntryData`]
+//! implement `Serialize` and `Deserialize`. Names and paths are written as
+//! strings in human-readable formats when they are valid UTF-8, and as bytes
+//! otherwise, so they round-trip exactly. An [`Entry`] is written as a
+//! sequence of itself and every entry below it, in depth-first order, so that
+//! trees of any depth can be written and read without running out of stack.
+
+mod diff;
+mod dir;
+mod entry;
+mod error;
+mod filter;
+mod glob;
+mod hash;
+mod ignore;
+mod json;
+mod link;
+mod metadata;
+mod moves;
+mod pool;
+mod regex;
+mod render;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod snapshot;
+mod sort;
+mod tree;
+mod visit;
+mod walk;
+
+pub use crate::diff::{
+    Change,
+    Changes,
+    DiffEntry,
+    DiffOptions,
+    DiffRender,
+    DiffSummary,
+    EntryState,
+    Move,
+    TreeDiff,
+};
+pub use crate::dir::Backend;
+pub use crate::entry::{Device, Entry, EntryData, EntryKind, SizeKind};
+pub use crate::error::{Error, Operation, Result};
+pub use crate::filter::{Filter, Pattern, PatternError};
+pub use crate::json::JsonError;
+pub use crate::link::{Link, LinkStatus};
+pub use crate::metadata::{Accounts, Column, Metadata};
+pub use crate::render::{Render, RenderOptions, SizeFormat, SizeUnits};
+pub use crate::snapshot::Snapshot;
+pub use crate::sort::{SortKey, SortOrder};
+pub use crate::tree::{get_file_tree, FileTree, TreeBuilder};
+pub use crate::visit::{BreadthFirst, DepthFirst, Visit, Visitor, VisitorMut};
+pub use crate::walk::{Event, HardLinks, WalkOptions};
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// Where a symlink points, as found during a scan.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Link {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::os_bytes"))]
+    pub(crate) target: PathBuf,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::option_os_bytes"))]
+    pub(crate) resolved: Option<PathBuf>,
+    pub(crate) status: LinkStatus,
+    pub(crate) outside_root: bool,
+}
+
+/// What following a symlink leads to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LinkStatus {
+    File,
+    Directory,
+    /// Something that exists but is neither a file nor a directory.
+    Other,
+    /// Nothing: the target, or a directory on the way to it, is missing or
+    /// cannot be resolved.
+    Dangling,
+    /// A chain of symlinks that leads back to itself.
+    Loop,
+}
+
+impl Link {
+    /// The raw target, as returned by `read_link`.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// The canonical path the link leads to, with every symlink on the way
+    /// resolved. `None` for broken links.
+    pub fn resolved(&self) -> Option<&Path> {
+        self.resolved.as_deref()
+    }
+
+    pub fn status(&self) -> LinkStatus {
+        self.status
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.target.is_relative()
+    }
+
+    /// Whether the link leads outside the scanned directory. For broken links
+    /// this is judged from the target as written.
+    pub fn is_outside_root(&self) -> bool {
+        self.outside_root
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.status.is_broken()
+    }
+}
+
+impl LinkStatus {
+    /// Whether following the link fails.
+    pub fn is_broken(self) -> bool {
+        matches!(self, LinkStatus::Dangling | LinkStatus::Loop)
+    }
+}
+
+impl fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LinkStatus::File => "file",
+            LinkStatus::Directory => "directory",
+            LinkStatus::Other => "other",
+            LinkStatus::Dangling => "dangling",
+            LinkStatus::Loop => "link loop",
+        })
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system, so `..`
+/// after a symlink goes back to the directory containing the link rather
+/// than the link target's parent.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
@@ -1,152 +1,593 @@
-use std::convert::{TryFrom, TryInto};
-use std::ffi::{OsStr, OsString};
+use std::error::Error;
+use std::io::{self, Write};
+use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
-use std::{fmt, fs, io};
+use std::{process, thread};
 
+use file_tree::{
+    Backend,
+    Column,
+    DiffOptions,
+    EntryKind,
+    Event,
+    FileTree,
+    Filter,
+    HardLinks,
+    Pattern,
+    RenderOptions,
+    SizeFormat,
+    SizeKind,
+    SizeUnits,
+    Snapshot,
+    SortKey,
+    SortOrder,
+    WalkOptions,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct Options {
+    /// The directory to scan, by default the current one
     #[structopt(parse(from_os_str))]
-    dir: PathBuf,
-}
+    dir: Option<PathBuf>,
+
+    /// Descend at most this many levels below the directory
+    #[structopt(short = "L", long = "level")]
+    max_depth: Option<usize>,
+
+    /// Include entries whose name starts with a dot
+    #[structopt(short = "a", long = "all")]
+    show_hidden: bool,
+
+    /// Stay on the file system of the directory
+    #[structopt(short = "x", long)]
+    one_file_system: bool,
+
+    /// Do not descend into directories with fewer entries than this
+    #[structopt(long)]
+    min_entries: Option<usize>,
+
+    /// Do not descend into directories with more entries than this
+    #[structopt(long = "filelimit")]
+    max_entries: Option<usize>,
+
+    /// Sort by name, iname, version, size, mtime or extension
+    #[structopt(long, default_value = "name")]
+    sort: SortKey,
+
+    /// Leave entries in the order they are visited, skipping the sort
+    #[structopt(short = "U", long, conflicts_with_all = &["reverse", "dirs-first"])]
+    unsorted: bool,
+
+    /// Reverse the sort order
+    #[structopt(short = "r", long)]
+    reverse: bool,
+
+    /// List directories before other entries
+    #[structopt(long = "dirsfirst")]
+    dirs_first: bool,
+
+    /// Only list files matching this glob, and the directories leading to them
+    #[structopt(short = "P", long, number_of_values = 1, parse(try_from_str = Pattern::glob))]
+    include: Vec<Pattern>,
+
+    /// Leave out entries matching this glob
+    #[structopt(short = "I", long, number_of_values = 1, parse(try_from_str = Pattern::glob))]
+    exclude: Vec<Pattern>,
+
+    /// Only list files matching this regex, and the directories leading to them
+    #[structopt(long, number_of_values = 1, parse(try_from_str = Pattern::regex))]
+    include_regex: Vec<Pattern>,
+
+    /// Leave out entries matching this regex
+    #[structopt(long, number_of_values = 1, parse(try_from_str = Pattern::regex))]
+    exclude_regex: Vec<Pattern>,
+
+    /// Match patterns against paths relative to the directory instead of names
+    #[structopt(long)]
+    match_path: bool,
+
+    /// Include entries left out by patterns in directory sizes
+    #[structopt(long)]
+    count_filtered: bool,
+
+    /// Leave out entries matched by .gitignore, .ignore and git's exclude files
+    #[structopt(long = "gitignore")]
+    git_ignore: bool,
+
+    /// List entries matched by ignore files, marked as ignored
+    #[structopt(long)]
+    show_ignored: bool,
+
+    /// Record unreadable entries in the tree and carry on instead of stopping
+    #[structopt(short = "k", long)]
+    keep_going: bool,
+
+    /// Read directories on this many threads, or one per CPU if 0
+    #[structopt(short = "j", long, default_value = "1")]
+    threads: usize,
+
+    /// Read directories by path (paths), through file descriptors with openat
+    /// (openat), which also reaches paths longer than PATH_MAX, or like openat
+    /// with getdents64 and statx (getdents)
+    #[structopt(long, default_value = "paths", parse(try_from_str = parse_backend))]
+    backend: Backend,
+
+    /// Keep at most this many directories open with --backend openat or
+    /// getdents
+    #[structopt(long)]
+    max_open_dirs: Option<usize>,
+
+    /// Show the size of each entry and the total size
+    #[structopt(short = "s", long)]
+    sizes: bool,
+
+    /// Units for sizes: bytes, iec (KiB, MiB, ...) or si (kB, MB, ...)
+    #[structopt(long, default_value = "iec")]
+    units: SizeUnits,
+
+    /// Digits after the decimal point for sizes in iec or si units
+    #[structopt(long, default_value = "1")]
+    precision: usize,
+
+    /// Show and sort by space allocated on disk rather than apparent size
+    #[structopt(long)]
+    disk_usage: bool,
+
+    /// Show sizes as a number of blocks of this size, like du -B (e.g. 4096,
+    /// 1K, 1MB)
+    #[structopt(short = "B", long, parse(try_from_str = parse_block_size))]
+    block_size: Option<NonZeroU64>,
+
+    /// Count files with several hard links once at the first link (first), in
+    /// equal shares at every link (split), or fully at every link (all)
+    #[structopt(long, default_value = "first", parse(try_from_str = parse_hard_links))]
+    hard_links: HardLinks,
+
+    /// Follow symlinks, descending into linked directories
+    #[structopt(short = "l", long)]
+    follow: bool,
+
+    /// Show file type and permissions, like tree -p
+    #[structopt(short = "p", long)]
+    permissions: bool,
+
+    /// Show the owner's name, or uid if it has none
+    #[structopt(short = "u", long)]
+    user: bool,
 
-struct FileTree {
-    _root_path: PathBuf,
-    root_entry: Entry,
+    /// Show the group's name, or gid if it has none
+    #[structopt(short = "g", long)]
+    group: bool,
+
+    /// Show the time chosen by --time
+    #[structopt(short = "D", long)]
+    date: bool,
+
+    /// The time -D shows: mtime, atime, ctime or btime
+    #[structopt(long, default_value = "mtime", parse(try_from_str = parse_time_column))]
+    time: Column,
+
+    /// Show inode numbers
+    #[structopt(long)]
+    inodes: bool,
+
+    /// Show the device each entry is stored on
+    #[structopt(long)]
+    device: bool,
+
+    /// Record permissions, ownership, timestamps and inodes even when no
+    /// column shows them, for --format json
+    #[structopt(long)]
+    metadata: bool,
+
+    /// Record a hash of every file's contents, so that diffs of saved
+    /// snapshots can find moved files
+    #[structopt(long)]
+    hash: bool,
+
+    /// Show these metadata columns, in order, instead of the ones picked by
+    /// -p, -u, -g, -D, --inodes and --device. One or more of mode, links,
+    /// user, group, inode, device, atime, mtime, ctime and btime
+    #[structopt(long, use_delimiter = true)]
+    columns: Option<Vec<Column>>,
+
+    /// Output format: tree, json, or ndjson to print one line per entry as it
+    /// is read, without sorting
+    #[structopt(long, default_value = "tree", parse(try_from_str = parse_format))]
+    format: Format,
+
+    /// Save the scan to this snapshot file as well as printing it
+    #[structopt(long, parse(from_os_str))]
+    save: Option<PathBuf>,
+
+    /// Print a tree saved with --save instead of scanning, applying patterns
+    /// and the sort order to it
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["dir", "save"])]
+    load: Option<PathBuf>,
+
+    /// List broken symlinks instead of the tree, failing if there are any
+    #[structopt(long)]
+    broken_links: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-struct Entry {
-    name: OsString,
-    size: u64,
-    data: EntryData,
+#[derive(StructOpt)]
+enum Command {
+    /// Compare two directories or snapshots, exiting with 1 if they differ.
+    /// Options given before `diff` apply to both scans
+    Diff {
+        /// The older directory, or a snapshot saved with --save
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        /// The newer directory, or a snapshot saved with --save
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+
+        /// List unchanged entries instead of collapsing them
+        #[structopt(long)]
+        show_unchanged: bool,
+
+        /// Report removed entries that reappear elsewhere as moved. Files
+        /// must have the same contents, directories mostly the same entries
+        #[structopt(short = "M", long)]
+        find_moves: bool,
+
+        /// How much of a directory, in percent, must be the same for it to
+        /// count as moved
+        #[structopt(long, default_value = "50")]
+        min_similarity: f64,
+    },
 }
 
-enum EntryData {
-    File,
-    Symlink(PathBuf),
-    Directory(Vec<Entry>),
-    Unknown,
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tree,
+    Json,
+    Ndjson,
 }
 
-impl fmt::Debug for Entry {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fn fmt_entry(
-            entry: &Entry,
-            f: &mut fmt::Formatter,
-            depth: usize,
-            is_last: bool,
-        ) -> fmt::Result {
-            for _ in 0..depth {
-                write!(f, " \u{2502}")?;
-            }
-            if depth > 0 {
-                if is_last {
-                    write!(f, " \u{2514}")?;
-                } else {
-                    write!(f, " \u{251c}")?;
-                }
-            }
+impl Options {
+    fn dir(&self) -> &Path {
+        self.dir.as_deref().unwrap_or_else(|| Path::new("."))
+    }
 
-            write!(f, "{}", &entry.name.to_string_lossy())?;
+    fn walk_options(&self) -> WalkOptions {
+        WalkOptions {
+            max_depth: self.max_depth,
+            show_hidden: self.show_hidden,
+            one_file_system: self.one_file_system,
+            min_entries: self.min_entries,
+            max_entries: self.max_entries,
+            sort: self.sort_order(),
+            filter: self.filter(),
+            count_filtered: self.count_filtered,
+            git_ignore: self.git_ignore || self.show_ignored,
+            show_ignored: self.show_ignored,
+            keep_going: self.keep_going,
+            hard_links: self.hard_links,
+            follow_symlinks: self.follow,
+            metadata: self.metadata || !self.columns().is_empty(),
+            hash_contents: self.hash,
+            threads: self.threads(),
+            backend: self.backend,
+            max_open_dirs: self.max_open_dirs,
+        }
+    }
 
-            match &entry.data {
-                EntryData::File | EntryData::Symlink(..) | EntryData::Unknown => {}
-                EntryData::Directory(children) => {
-                    writeln!(f)?;
+    fn threads(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, |count| count.get()),
+            count => count,
+        }
+    }
 
-                    let mut iter = children.iter();
-                    let mut next = iter.next();
-                    while let Some(child) = next {
-                        next = iter.next();
-                        fmt_entry(child, f, depth + 1, next.is_none())?;
-                    }
-                }
-            }
-            writeln!(f)
+    fn sort_order(&self) -> Option<SortOrder> {
+        if self.unsorted {
+            None
+        } else {
+            Some(
+                SortOrder::new(self.sort)
+                    .reverse(self.reverse)
+                    .dirs_first(self.dirs_first)
+                    .size_kind(self.size_kind()),
+            )
         }
+    }
 
-        fmt_entry(self, f, 0, true)
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            sizes: if self.sizes {
+                let format = SizeFormat::new(self.units).precision(self.precision);
+                Some(match self.block_size {
+                    Some(block_size) => format.block_size(block_size),
+                    None => format,
+                })
+            } else {
+                None
+            },
+            size_kind: self.size_kind(),
+            columns: self.columns(),
+        }
+    }
+
+    fn columns(&self) -> Vec<Column> {
+        if let Some(columns) = &self.columns {
+            return columns.clone();
+        }
+        let flags = [
+            (self.inodes, Column::Inode),
+            (self.device, Column::Device),
+            (self.permissions, Column::Mode),
+            (self.user, Column::User),
+            (self.group, Column::Group),
+            (self.date, self.time),
+        ];
+        flags
+            .iter()
+            .filter(|(shown, _)| *shown)
+            .map(|&(_, column)| column)
+            .collect()
+    }
+
+    fn size_kind(&self) -> SizeKind {
+        if self.disk_usage {
+            SizeKind::Allocated
+        } else {
+            SizeKind::Apparent
+        }
+    }
+
+    fn filter(&self) -> Filter {
+        let mut filter = Filter::new();
+        for pattern in self.include.iter().chain(&self.include_regex) {
+            filter = filter.include(pattern.clone().on_path(self.match_path));
+        }
+        for pattern in self.exclude.iter().chain(&self.exclude_regex) {
+            filter = filter.exclude(pattern.clone().on_path(self.match_path));
+        }
+        filter
     }
 }
 
-fn get_file_tree(path: impl AsRef<Path>) -> io::Result<FileTree> {
-    let path = path.as_ref().to_path_buf();
+/// Parses a block size as du does: a number with an optional K, M, G, T, P
+/// or E suffix for powers of 1024, or KB, MB, ... for powers of 1000.
+fn parse_block_size(s: &str) -> Result<NonZeroU64, String> {
+    let invalid = || format!("invalid block size: {}", s);
 
-    let (children, size) = get_child_entries(&path)?;
-    let name = path
-        .file_name()
-        .unwrap_or(AsRef::<OsStr>::as_ref(""))
-        .to_os_string();
-    let root_entry = Entry {
-        name,
-        size,
-        data: EntryData::Directory(children),
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: u64 = if number.is_empty() {
+        1
+    } else {
+        number.parse().map_err(|_| invalid())?
     };
 
-    Ok(FileTree {
-        root_entry,
-        _root_path: path,
+    let suffix = suffix.to_ascii_uppercase();
+    let (prefix, base) = match suffix.as_bytes() {
+        [] => return NonZeroU64::new(number).ok_or_else(invalid),
+        [prefix] | [prefix, b'I', b'B'] => (*prefix, 1024u64),
+        [prefix, b'B'] => (*prefix, 1000),
+        _ => return Err(invalid()),
+    };
+    let exponent = b"KMGTPE"
+        .iter()
+        .position(|&p| p == prefix)
+        .ok_or_else(invalid)? as u32
+        + 1;
+    base.checked_pow(exponent)
+        .and_then(|unit| unit.checked_mul(number))
+        .and_then(NonZeroU64::new)
+        .ok_or_else(invalid)
+}
+
+fn parse_hard_links(s: &str) -> Result<HardLinks, String> {
+    Ok(match s {
+        "first" => HardLinks::FirstSeen,
+        "split" => HardLinks::Split,
+        "all" => HardLinks::CountAll,
+        _ => return Err(format!("unknown hard link policy: {}", s)),
+    })
+}
+
+fn parse_backend(s: &str) -> Result<Backend, String> {
+    Ok(match s {
+        "paths" => Backend::Paths,
+        "openat" => Backend::Openat,
+        "getdents" => Backend::Getdents,
+        _ => return Err(format!("unknown backend: {}", s)),
     })
 }
 
-impl TryFrom<fs::DirEntry> for Entry {
-    type Error = io::Error;
+fn parse_format(s: &str) -> Result<Format, String> {
+    Ok(match s {
+        "tree" => Format::Tree,
+        "json" => Format::Json,
+        "ndjson" => Format::Ndjson,
+        _ => return Err(format!("unknown format: {}", s)),
+    })
+}
 
-    fn try_from(dir_entry: fs::DirEntry) -> Result<Self, Self::Error> {
-        let name = dir_entry.file_name();
-        let file_type = dir_entry.file_type()?;
+fn parse_time_column(s: &str) -> Result<Column, String> {
+    match s.parse()? {
+        column @ (Column::Accessed | Column::Modified | Column::Changed | Column::Created) => {
+            Ok(column)
+        }
+        _ => Err(format!("not a time: {}", s)),
+    }
+}
 
-        Ok(if file_type.is_dir() {
-            let (children, size) = get_child_entries(dir_entry.path())?;
-            Entry {
-                name,
-                size,
-                data: EntryData::Directory(children),
-            }
-        } else if file_type.is_symlink() {
-            let link_to = fs::read_link(dir_entry.path())?;
-            Entry {
-                name,
-                size: 0,
-                data: EntryData::Symlink(link_to),
-            }
-        } else if file_type.is_file() {
-            let metadata = dir_entry.metadata()?;
-            Entry {
-                name,
-                size: metadata.len(),
-                data: EntryData::File,
-            }
-        } else {
-            Entry {
-                name,
-                size: 0,
-                data: EntryData::Unknown,
-            }
-        })
+#[paw::main]
+fn main(options: Options) {
+    match run(&options) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("file_tree: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the tree, returning whether every entry in it could be read.
+fn run(options: &Options) -> Result<bool, Box<dyn Error>> {
+    if let Some(Command::Diff {
+        old,
+        new,
+        show_unchanged,
+        find_moves,
+        min_similarity,
+    }) = &options.command
+    {
+        let diff_options = DiffOptions {
+            detect_moves: *find_moves,
+            // Files are only read from directories being scanned, since a
+            // snapshot's root may have changed since it was saved.
+            read_files: old.is_dir() && new.is_dir(),
+            min_similarity: min_similarity / 100.0,
+        };
+        return diff(options, old, new, &diff_options, *show_unchanged);
+    }
+    if options.format == Format::Ndjson && !options.broken_links {
+        return stream(options);
+    }
+    let file_tree = file_tree(options)?;
+
+    if options.broken_links {
+        return report_broken_links(&file_tree);
+    }
+    match options.format {
+        Format::Tree => print!("{}", file_tree.render(&options.render_options())),
+        Format::Json | Format::Ndjson => println!("{}", file_tree.to_json()),
     }
+
+    let errors = file_tree.errors();
+    if !errors.is_empty() {
+        io::stdout().flush()?;
+        eprintln!("file_tree: {} entries could not be read:", errors.len());
+        for error in &errors {
+            eprintln!("  {}", error);
+        }
+    }
+    Ok(errors.is_empty())
 }
 
-fn get_child_entries(path: impl AsRef<Path>) -> io::Result<(Vec<Entry>, u64)> {
-    let dir_entries = fs::read_dir(path)?;
+/// Scans the directory, saving a snapshot if asked to, or loads the tree
+/// from a snapshot.
+fn file_tree(options: &Options) -> Result<FileTree, Box<dyn Error>> {
+    if let Some(path) = &options.load {
+        return load(options, path);
+    }
 
-    let mut entries = vec![];
-    let mut total_size = 0;
-    for dir_entry in dir_entries {
-        let entry: Entry = dir_entry?.try_into()?;
+    let walk_options = options.walk_options();
+    let file_tree = FileTree::builder(options.dir())
+        .options(walk_options.clone())
+        .build()?;
+    match &options.save {
+        Some(path) => {
+            let snapshot = Snapshot::new(file_tree, walk_options);
+            snapshot
+                .save(path)
+                .map_err(|error| format!("cannot save {}: {}", path.display(), error))?;
+            Ok(snapshot.into_tree())
+        }
+        None => Ok(file_tree),
+    }
+}
 
-        total_size += entry.size;
-        entries.push(entry);
+/// Loads a snapshot and applies the patterns and sort order to it.
+fn load(options: &Options, path: &Path) -> Result<FileTree, Box<dyn Error>> {
+    let mut file_tree = Snapshot::load(path)
+        .map_err(|error| format!("cannot load {}: {}", path.display(), error))?
+        .into_tree();
+    file_tree.filter(&options.filter());
+    if let Some(order) = options.sort_order() {
+        file_tree.sort(&order);
     }
+    Ok(file_tree)
+}
+
+/// Prints the differences between two directories or snapshots, returning
+/// whether there were none.
+fn diff(
+    options: &Options,
+    old: &Path,
+    new: &Path,
+    diff_options: &DiffOptions,
+    show_unchanged: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let tree = |path: &Path| -> Result<FileTree, Box<dyn Error>> {
+        if path.is_dir() {
+            Ok(FileTree::builder(path)
+                .options(options.walk_options())
+                .build()?)
+        } else {
+            load(options, path)
+        }
+    };
+    let diff = tree(old)?.diff_with(&tree(new)?, diff_options);
 
-    Ok((entries, total_size))
+    match options.format {
+        Format::Tree => print!(
+            "{}",
+            diff.render(&options.render_options())
+                .show_unchanged(show_unchanged)
+        ),
+        Format::Json => println!("{}", diff.to_json()),
+        Format::Ndjson => return Err("--format ndjson cannot be used with diff".into()),
+    }
+    Ok(diff.is_empty())
 }
 
-#[paw::main]
-fn main(options: Options) -> io::Result<()> {
-    let file_tree = get_file_tree(&options.dir)?;
+/// Prints an event per line as the scan goes, returning whether every entry
+/// could be read.
+fn stream(options: &Options) -> Result<bool, Box<dyn Error>> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    if options.load.is_some() {
+        return Err("--format ndjson cannot be used with --load".into());
+    }
+    if options.save.is_some() {
+        return Err("--format ndjson cannot be used with --save".into());
+    }
+    let mut errors = 0;
+    FileTree::builder(options.dir())
+        .options(options.walk_options())
+        .stream(|event| {
+            if let Event::Entry { entry, .. } | Event::ExitDir { entry, .. } = event {
+                if entry.kind() == EntryKind::Error {
+                    errors += 1;
+                }
+            }
+            writeln!(out, "{}", event.to_json())
+        })?;
+    out.flush()?;
+
+    if errors > 0 {
+        eprintln!("file_tree: {} entries could not be read", errors);
+    }
+    Ok(errors == 0)
+}
 
-    println!("{:?}", &file_tree.root_entry);
-    Ok(())
+/// Prints one line per broken symlink, returning whether there were none.
+fn report_broken_links(file_tree: &FileTree) -> Result<bool, Box<dyn Error>> {
+    let links = file_tree.broken_links();
+    for (path, entry) in &links {
+        if let Some(link) = entry.link() {
+            println!(
+                "{} -> {} ({})",
+                path.display(),
+                link.target().display(),
+                link.status()
+            );
+        }
+    }
+    if !links.is_empty() {
+        io::stdout().flush()?;
+        eprintln!("file_tree: {} broken links", links.len());
+    }
+    Ok(links.is_empty())
 }
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dir::Stat;
+
+/// Ownership, permissions, timestamps and identity of an entry, captured
+/// when [`WalkOptions::metadata`](crate::WalkOptions::metadata) is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    /// The file type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub inode: u64,
+    /// The device the entry is stored on.
+    pub dev: u64,
+    pub nlink: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::option_time"))]
+    pub accessed: Option<SystemTime>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::option_time"))]
+    pub modified: Option<SystemTime>,
+    /// When the inode last changed.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::option_time"))]
+    pub changed: Option<SystemTime>,
+    /// When the entry was created, if the file system records it.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::option_time"))]
+    pub created: Option<SystemTime>,
+}
+
+/// A column of metadata shown before the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// File type and permissions, like `drwxr-xr-x`.
+    Mode,
+    /// The number of hard links.
+    Links,
+    User,
+    Group,
+    Inode,
+    Device,
+    Accessed,
+    Modified,
+    Changed,
+    Created,
+}
+
+/// User and group names, read from `/etc/passwd` and `/etc/group`.
+#[derive(Clone, Debug, Default)]
+pub struct Accounts {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl Metadata {
+    pub(crate) fn new(stat: &Stat) -> Self {
+        Metadata {
+            mode: stat.mode,
+            uid: stat.uid,
+            gid: stat.gid,
+            inode: stat.ino,
+            dev: stat.dev,
+            nlink: stat.nlink,
+            accessed: stat.accessed,
+            modified: stat.modified,
+            changed: stat.changed,
+            created: stat.created,
+        }
+    }
+
+    /// The mode as `ls -l` writes it, such as `drwxr-xr-x` or `-rwsr-x--T`.
+    pub fn mode_string(&self) -> String {
+        // `mode_t`, which the constants have, is narrower on some systems.
+        let mode = self.mode as libc::mode_t;
+        let file_type = match mode & libc::S_IFMT {
+            libc::S_IFDIR => 'd',
+            libc::S_IFLNK => 'l',
+            libc::S_IFIFO => 'p',
+            libc::S_IFSOCK => 's',
+            libc::S_IFBLK => 'b',
+            libc::S_IFCHR => 'c',
+            _ => '-',
+        };
+        let special = |bit: libc::mode_t, exec: libc::mode_t, set: char| match (
+            mode & bit != 0,
+            mode & exec != 0,
+        ) {
+            (true, true) => set,
+            (true, false) => set.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        };
+        let flag = |bit: libc::mode_t, c: char| if mode & bit != 0 { c } else { '-' };
+
+        [
+            file_type,
+            flag(libc::S_IRUSR, 'r'),
+            flag(libc::S_IWUSR, 'w'),
+            special(libc::S_ISUID, libc::S_IXUSR, 's'),
+            flag(libc::S_IRGRP, 'r'),
+            flag(libc::S_IWGRP, 'w'),
+            special(libc::S_ISGID, libc::S_IXGRP, 's'),
+            flag(libc::S_IROTH, 'r'),
+            flag(libc::S_IWOTH, 'w'),
+            special(libc::S_ISVTX, libc::S_IXOTH, 't'),
+        ]
+        .iter()
+        .collect()
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "mode" => Column::Mode,
+            "links" => Column::Links,
+            "user" => Column::User,
+            "group" => Column::Group,
+            "inode" => Column::Inode,
+            "device" => Column::Device,
+            "atime" => Column::Accessed,
+            "mtime" => Column::Modified,
+            "ctime" => Column::Changed,
+            "btime" => Column::Created,
+            _ => return Err(format!("unknown column: {}", s)),
+        })
+    }
+}
+
+impl Accounts {
+    /// Reads the local account databases. Missing or unreadable files leave
+    /// the table empty, so ids are shown as numbers.
+    pub fn load() -> Self {
+        Accounts {
+            users: read_names("/etc/passwd"),
+            groups: read_names("/etc/group"),
+        }
+    }
+
+    pub fn user(&self, uid: u32) -> Option<&str> {
+        self.users.get(&uid).map(String::as_str)
+    }
+
+    pub fn group(&self, gid: u32) -> Option<&str> {
+        self.groups.get(&gid).map(String::as_str)
+    }
+}
+
+/// Reads the name and id from the first and third fields of each line of a
+/// colon-separated file such as `/etc/passwd`. The first entry for an id
+/// wins, as with `getpwuid`.
+fn read_names(path: &str) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if let (Some(name), Some(id)) = (fields.first(), fields.get(2)) {
+            if let Ok(id) = id.parse() {
+                names.entry(id).or_insert_with(|| name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Builds a time from seconds and nanoseconds since the epoch, as `stat`
+/// reports them. Negative seconds are before the epoch. `None` if the time
+/// cannot be represented.
+pub(crate) fn timestamp(secs: i64, nsecs: i64) -> Option<SystemTime> {
+    let nanos = Duration::from_nanos(u64::try_from(nsecs).ok()?);
+    if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64).checked_add(nanos)?)
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))?
+            .checked_add(nanos)
+    }
+}
+
+/// The inverse of [`timestamp`], with nanoseconds always counting forwards
+/// from the whole second.
+pub(crate) fn split_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(error) => {
+            let before = error.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
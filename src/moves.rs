@@ -0,0 +1,373 @@
+use std::cell::OnceCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use crate::diff::{DiffOptions, Move};
+use crate::entry::{Entry, EntryKind};
+use crate::tree::FileTree;
+use crate::{hash, visit};
+
+/// Removed and added entries that were paired up as moves.
+#[derive(Default)]
+pub(crate) struct Moves<'a> {
+    /// The old entry moved to each new path, and where it was.
+    pub(crate) to: HashMap<PathBuf, (&'a Entry, Move)>,
+    /// Where the entry at each old path moved to.
+    pub(crate) from: HashMap<PathBuf, Move>,
+}
+
+/// An entry only one of the trees has, or one below such an entry.
+struct Candidate<'a> {
+    path: PathBuf,
+    /// The number of components in the path.
+    depth: usize,
+    /// The hash of the path, by [`PATH_BASE`].
+    path_hash: u64,
+    entry: &'a Entry,
+    /// The index after the last candidate below this one.
+    end: usize,
+    hash: OnceCell<Option<u64>>,
+}
+
+/// One side of the comparison: every entry a path-based diff would call
+/// removed, or every one it would call added, in pre-order.
+struct Side<'a> {
+    root_path: &'a Path,
+    candidates: Vec<Candidate<'a>>,
+    taken: Vec<bool>,
+    /// The sizes of files on the other side, which are worth hashing.
+    other_sizes: HashSet<u64>,
+    read_files: bool,
+}
+
+/// A directory both trees have, being compared by [`collect`].
+struct Pair<'a> {
+    /// The entries of the old directory still to look at.
+    old: slice::Iter<'a, Entry>,
+    new: &'a [Entry],
+    new_by_name: HashMap<&'a OsStr, &'a Entry>,
+    old_names: HashSet<&'a OsStr>,
+    /// The length of the path without the directory's name.
+    len: usize,
+}
+
+/// The multiplier of a path's hash, which is the sum of its components'
+/// hashes each multiplied by it once for every component after. The hash of
+/// a path relative to any directory above it follows from the two hashes.
+const PATH_BASE: u64 = 0x0000_0100_0000_01b3;
+
+/// The most directories an element of a fingerprint is counted towards, so
+/// that ones nearly every directory has, as in a long chain of them, cost no
+/// more than the rest.
+const MAX_CANDIDATES: usize = 100;
+
+/// Pairs entries removed from `old` with entries added to `new` that hold
+/// the same content. Directories are paired first, shallowest first, by
+/// the Jaccard similarity of what is below them; files left over are
+/// paired when their contents hash the same.
+///
+/// Files are only hashed when a file of the same size is on the other side,
+/// and only by reading them if the scan did not record a hash.
+pub(crate) fn find_moves<'a>(
+    old: &'a FileTree,
+    new: &'a FileTree,
+    options: &DiffOptions,
+) -> Moves<'a> {
+    let mut removed = Side::new(old.root_path());
+    let mut added = Side::new(new.root_path());
+    collect(old.root(), new.root(), &mut removed, &mut added);
+    removed.prepare(&added, options);
+    added.prepare(&removed, options);
+
+    // Directories: count the elements each removed directory shares with
+    // each added one through an index from element to added directories.
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut added_lens = HashMap::new();
+    for a in added.dirs() {
+        let elements = added.fingerprint(a);
+        added_lens.insert(a, elements.len());
+        for element in elements {
+            index.entry(element).or_default().push(a);
+        }
+    }
+    // Shallowest first, a level at a time, so that a directory paired has
+    // taken everything below it before that is fingerprinted.
+    let mut dirs: Vec<(usize, usize)> = removed
+        .dirs()
+        .map(|r| (removed.candidates[r].depth, r))
+        .collect();
+    dirs.sort_unstable();
+    let mut moves = Moves::default();
+    for level in dirs.chunk_by(|(d1, _), (d2, _)| d1 == d2) {
+        let mut pairs = vec![];
+        for &(_, r) in level {
+            if removed.taken[r] {
+                continue;
+            }
+            let elements = removed.fingerprint(r);
+            let mut shared: HashMap<usize, usize> = HashMap::new();
+            for element in &elements {
+                let dirs = index.get(element).map_or(&[][..], Vec::as_slice);
+                let free = dirs.iter().filter(|&&a| !added.taken[a]);
+                for &a in free.take(MAX_CANDIDATES) {
+                    *shared.entry(a).or_default() += 1;
+                }
+            }
+            for (a, shared) in shared {
+                let union = elements.len() + added_lens[&a] - shared;
+                let similarity = shared as f64 / union as f64;
+                if similarity >= options.min_similarity {
+                    pairs.push((r, a, similarity));
+                }
+            }
+        }
+        pairs.sort_by(|&(r1, a1, s1), &(r2, a2, s2)| {
+            s2.total_cmp(&s1)
+                .then_with(|| {
+                    removed
+                        .same_name(r2, &added, a2)
+                        .cmp(&removed.same_name(r1, &added, a1))
+                })
+                .then((r1, a1).cmp(&(r2, a2)))
+        });
+        for (r, a, similarity) in pairs {
+            if removed.taken[r] || added.taken[a] {
+                continue;
+            }
+            removed.take(r);
+            added.take(a);
+            moves.pair(&removed.candidates[r], &added.candidates[a], similarity);
+        }
+    }
+
+    // Files: pair the rest by content, preferring ones of the same name.
+    let mut by_content: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for a in added.files() {
+        if let Some(hash) = added.hash(a) {
+            let size = added.candidates[a].entry.size();
+            by_content.entry((size, hash)).or_default().push(a);
+        }
+    }
+    let files: Vec<usize> = removed.files().collect();
+    for r in files {
+        let hash = match removed.hash(r) {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let size = removed.candidates[r].entry.size();
+        let matches = match by_content.get(&(size, hash)) {
+            Some(matches) => matches,
+            None => continue,
+        };
+        let free = matches.iter().copied().filter(|&a| !added.taken[a]);
+        let best = free
+            .clone()
+            .find(|&a| removed.same_name(r, &added, a))
+            .or_else(|| free.clone().next());
+        if let Some(a) = best {
+            removed.taken[r] = true;
+            added.taken[a] = true;
+            moves.pair(&removed.candidates[r], &added.candidates[a], 1.0);
+        }
+    }
+    moves
+}
+
+/// Adds the entries below `old` and `new` that only one of them has, and
+/// everything below those, to `removed` and `added`. Directories both have
+/// are compared on a stack rather than the call stack, so that trees of any
+/// depth can be.
+fn collect<'a>(old: &'a Entry, new: &'a Entry, removed: &mut Side<'a>, added: &mut Side<'a>) {
+    let mut path = PathBuf::new();
+    let mut stack = vec![Pair::new(old, new, 0)];
+    while let Some(pair) = stack.last_mut() {
+        match pair.old.next() {
+            Some(child) => {
+                let len = visit::push(&mut path, child.name());
+                match pair.new_by_name.get(child.name()) {
+                    Some(&other) if child.is_dir() && other.is_dir() => {
+                        stack.push(Pair::new(child, other, len));
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => removed.push(child, path.clone()),
+                }
+                visit::truncate(&mut path, len);
+            }
+            None => {
+                let pair = stack.pop().unwrap();
+                for child in pair.new {
+                    if !pair.old_names.contains(child.name()) {
+                        added.push(child, path.join(child.name()));
+                    }
+                }
+                visit::truncate(&mut path, pair.len);
+            }
+        }
+    }
+}
+
+impl<'a> Pair<'a> {
+    fn new(old: &'a Entry, new: &'a Entry, len: usize) -> Self {
+        let old = old.children().unwrap_or(&[]);
+        let new = new.children().unwrap_or(&[]);
+        Pair {
+            old: old.iter(),
+            new,
+            new_by_name: new.iter().map(|child| (child.name(), child)).collect(),
+            old_names: old.iter().map(Entry::name).collect(),
+            len,
+        }
+    }
+}
+
+impl<'a> Side<'a> {
+    fn new(root_path: &'a Path) -> Self {
+        Side {
+            root_path,
+            candidates: vec![],
+            taken: vec![],
+            other_sizes: HashSet::new(),
+            read_files: false,
+        }
+    }
+
+    /// Readies this side for pairing once both are collected.
+    fn prepare(&mut self, other: &Side, options: &DiffOptions) {
+        self.taken = vec![false; self.candidates.len()];
+        self.other_sizes = other
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.entry.kind() == EntryKind::File)
+            .map(|candidate| candidate.entry.size())
+            .collect();
+        self.read_files = options.read_files;
+    }
+
+    /// Adds `entry` and everything below it, in pre-order.
+    fn push(&mut self, entry: &'a Entry, path: PathBuf) {
+        // Each candidate whose children are being added, with those still
+        // to add.
+        let mut stack: Vec<(usize, slice::Iter<'a, Entry>)> = vec![];
+        let depth = path.components().count();
+        let path_hash = path.iter().fold(0, extend_hash);
+        let mut next = Some((entry, path, depth, path_hash));
+        loop {
+            if let Some((entry, path, depth, path_hash)) = next.take() {
+                let i = self.candidates.len();
+                self.candidates.push(Candidate {
+                    path,
+                    depth,
+                    path_hash,
+                    entry,
+                    end: i + 1,
+                    hash: OnceCell::new(),
+                });
+                stack.push((i, entry.children().unwrap_or(&[]).iter()));
+            }
+            let (i, children) = match stack.last_mut() {
+                Some(top) => top,
+                None => return,
+            };
+            match children.next() {
+                Some(child) => {
+                    let parent = &self.candidates[*i];
+                    next = Some((
+                        child,
+                        parent.path.join(child.name()),
+                        parent.depth + 1,
+                        extend_hash(parent.path_hash, child.name()),
+                    ));
+                }
+                None => {
+                    let (i, _) = stack.pop().unwrap();
+                    self.candidates[i].end = self.candidates.len();
+                }
+            }
+        }
+    }
+
+    fn dirs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.candidates.len()).filter(move |&i| self.candidates[i].entry.is_dir())
+    }
+
+    fn files(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.candidates.len()).filter(move |&i| {
+            let entry = self.candidates[i].entry;
+            !self.taken[i] && entry.kind() == EntryKind::File && entry.size() > 0
+        })
+    }
+
+    /// The content hash of a file, if another file of its size is on the
+    /// other side. A file that can no longer be read has none.
+    fn hash(&self, i: usize) -> Option<u64> {
+        let candidate = &self.candidates[i];
+        let entry = candidate.entry;
+        if entry.kind() != EntryKind::File || !self.other_sizes.contains(&entry.size()) {
+            return None;
+        }
+        *candidate.hash.get_or_init(|| {
+            entry.content_hash().or_else(|| {
+                if self.read_files {
+                    hash::hash_file(&self.root_path.join(&candidate.path)).ok()
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    /// Everything below directory `i`, each hashed by its path relative to
+    /// the directory, kind, size and content hash. Directories count with no
+    /// size, so that a change below one does not also change it.
+    fn fingerprint(&self, i: usize) -> Vec<u64> {
+        let dir = &self.candidates[i];
+        (i + 1..dir.end)
+            .map(|j| {
+                let candidate = &self.candidates[j];
+                let entry = candidate.entry;
+                let levels = (candidate.depth - dir.depth) as u32;
+                let above = dir.path_hash.wrapping_mul(PATH_BASE.wrapping_pow(levels));
+                let relative = candidate.path_hash.wrapping_sub(above);
+                let size = if entry.is_dir() { 0 } else { entry.size() };
+                let mut hasher = DefaultHasher::new();
+                (relative, entry.kind(), size, self.hash(j)).hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    fn same_name(&self, i: usize, other: &Side, j: usize) -> bool {
+        self.candidates[i].entry.name() == other.candidates[j].entry.name()
+    }
+
+    /// Marks candidate `i` and everything below it as paired.
+    fn take(&mut self, i: usize) {
+        let end = self.candidates[i].end;
+        for taken in &mut self.taken[i..end] {
+            *taken = true;
+        }
+    }
+}
+
+/// The hash of the path with hash `hash` followed by `name`.
+fn extend_hash(hash: u64, name: &OsStr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hash.wrapping_mul(PATH_BASE).wrapping_add(hasher.finish())
+}
+
+impl<'a> Moves<'a> {
+    fn pair(&mut self, old: &Candidate<'a>, new: &Candidate<'a>, similarity: f64) {
+        self.to.insert(
+            new.path.clone(),
+            (old.entry, Move::new(old.path.clone(), similarity)),
+        );
+        self.from
+            .insert(old.path.clone(), Move::new(new.path.clone(), similarity));
+    }
+}
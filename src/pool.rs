@@ -0,0 +1,196 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// A fixed set of threads running tasks that fork more tasks. Each thread
+/// runs its own newest task first and, when it has none, takes the oldest
+/// task of another thread, which tends to be the largest piece of work.
+pub(crate) struct Pool {
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    /// How many jobs are in the queues.
+    queued: AtomicUsize,
+    done: AtomicBool,
+    /// How many threads are asleep, or deciding whether to sleep. Nothing
+    /// needs waking while there are none.
+    sleepers: AtomicUsize,
+    /// Held by threads deciding to sleep until they are woken, and by the
+    /// threads waking them, so that no wakeup is missed in between.
+    idle: Mutex<()>,
+    /// Signalled when a task is queued or finishes, and when the pool is
+    /// done with.
+    work: Condvar,
+}
+
+type Job = Box<dyn FnOnce(&Worker) + Send>;
+
+/// One of a pool's threads, given to every task it runs.
+pub(crate) struct Worker<'a> {
+    pool: &'a Pool,
+    index: usize,
+    /// How many tasks are running on this thread inside [`Worker::wait`].
+    nesting: Cell<usize>,
+}
+
+/// A task handed to [`Worker::spawn`], whose result is collected with
+/// [`Worker::wait`].
+pub(crate) struct Task<T> {
+    slot: Arc<Slot<T>>,
+}
+
+struct Slot<T> {
+    done: AtomicBool,
+    /// Whether a thread has gone to sleep until the task is done.
+    awaited: AtomicBool,
+    result: Mutex<Option<thread::Result<T>>>,
+}
+
+/// The stack size of the pool's threads, matching the usual main thread
+/// rather than the smaller default for spawned ones.
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+impl Pool {
+    /// Runs `f` on the calling thread as one of `threads` workers, with the
+    /// others started for as long as it runs. A panic in any task is
+    /// passed on to whoever waits for it.
+    pub(crate) fn run<T>(threads: usize, f: impl FnOnce(&Worker) -> T) -> T {
+        let pool = Pool {
+            queues: (0..threads.max(1)).map(|_| Mutex::default()).collect(),
+            queued: AtomicUsize::new(0),
+            done: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            idle: Mutex::new(()),
+            work: Condvar::new(),
+        };
+        let result = thread::scope(|scope| {
+            for index in 1..pool.queues.len() {
+                let pool = &pool;
+                thread::Builder::new()
+                    .stack_size(STACK_SIZE)
+                    .spawn_scoped(scope, move || Worker::new(pool, index).work())
+                    .expect("failed to start scan thread");
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(&Worker::new(&pool, 0))));
+            pool.done.store(true, Ordering::SeqCst);
+            pool.wake(true);
+            result
+        });
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Wakes one sleeping thread to take a newly queued task, or all of them
+    /// to check on the tasks they wait for.
+    fn wake(&self, all: bool) {
+        if self.sleepers.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let _idle = self.idle.lock().unwrap();
+        if all {
+            self.work.notify_all();
+        } else {
+            self.work.notify_one();
+        }
+    }
+
+    /// Blocks until there may be something for the calling thread to do: a
+    /// task is queued, `ready` returns true, or the pool is done with.
+    fn sleep(&self, ready: impl Fn() -> bool) {
+        let idle = self.idle.lock().unwrap();
+        // Whoever queues or finishes a task after this sees the sleeper, and
+        // takes the lock to wake it, which it only gets once this thread is
+        // waiting.
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        if self.queued.load(Ordering::SeqCst) == 0 && !ready() && !self.done.load(Ordering::SeqCst)
+        {
+            drop(self.work.wait(idle).unwrap());
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a> Worker<'a> {
+    fn new(pool: &'a Pool, index: usize) -> Self {
+        Worker {
+            pool,
+            index,
+            nesting: Cell::new(0),
+        }
+    }
+
+    /// How many tasks are running on this thread's stack, each waiting on
+    /// the next.
+    pub(crate) fn nesting(&self) -> usize {
+        self.nesting.get()
+    }
+
+    /// Queues `f` to run on this or another thread.
+    pub(crate) fn spawn<T, F>(&self, f: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Worker) -> T + Send + 'static,
+    {
+        let slot = Arc::new(Slot {
+            done: AtomicBool::new(false),
+            awaited: AtomicBool::new(false),
+            result: Mutex::new(None),
+        });
+        let task = Task { slot: slot.clone() };
+        let job: Job = Box::new(move |worker| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(worker)));
+            *slot.result.lock().unwrap() = Some(result);
+            slot.done.store(true, Ordering::SeqCst);
+            if slot.awaited.load(Ordering::SeqCst) {
+                worker.pool.wake(true);
+            }
+        });
+        self.pool.queues[self.index].lock().unwrap().push_back(job);
+        self.pool.queued.fetch_add(1, Ordering::SeqCst);
+        self.pool.wake(false);
+        task
+    }
+
+    /// Returns the result of `task`, running other tasks until it is done.
+    pub(crate) fn wait<T>(&self, task: Task<T>) -> T {
+        while !task.slot.done.load(Ordering::Acquire) {
+            match self.find_job() {
+                Some(job) => {
+                    self.nesting.set(self.nesting.get() + 1);
+                    job(self);
+                    self.nesting.set(self.nesting.get() - 1);
+                }
+                None => self.pool.sleep(|| {
+                    task.slot.awaited.store(true, Ordering::SeqCst);
+                    task.slot.done.load(Ordering::SeqCst)
+                }),
+            }
+        }
+        let result = task.slot.result.lock().unwrap().take().unwrap();
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    fn find_job(&self) -> Option<Job> {
+        let queues = &self.pool.queues;
+        // The thread's own queue is unlocked before any other is locked, or
+        // two threads stealing from each other would each wait for the other.
+        let own = queues[self.index].lock().unwrap().pop_back();
+        let job = own.or_else(|| {
+            (1..queues.len())
+                .map(|offset| &queues[(self.index + offset) % queues.len()])
+                .find_map(|queue| queue.lock().unwrap().pop_front())
+        })?;
+        self.pool.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(job)
+    }
+
+    /// Runs tasks until the pool is done with.
+    fn work(&self) {
+        while !self.pool.done.load(Ordering::Acquire) {
+            match self.find_job() {
+                Some(job) => job(self),
+                None => self.pool.sleep(|| false),
+            }
+        }
+    }
+}
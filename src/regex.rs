@@ -0,0 +1,578 @@
+use std::fmt;
+
+/// A small regular expression matcher over bytes.
+///
+/// Supports literals, `.`, bracket classes, the `\d \w \s` escapes and their
+/// negations, `^` and `$`, grouping with `(...)`, alternation with `|`, and
+/// the quantifiers `* + ? {n} {n,} {n,m}` with counts of up to 1000. Other
+/// escapes are `\n`, `\t` and any punctuation standing for itself. A match
+/// may start anywhere in the text unless the pattern is anchored with `^`.
+///
+/// Patterns are compiled to a small program that is run over the text as a
+/// Pike VM, following every way the pattern could match at once, so matching
+/// takes time linear in the length of the text and never backtracks.
+#[derive(Clone)]
+pub struct Regex {
+    source: String,
+    program: Vec<Inst>,
+}
+
+enum Node {
+    Empty,
+    Byte(u8),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+#[derive(Clone)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(u8, u8)>,
+}
+
+/// An instruction of a compiled pattern. The ones that consume a byte move
+/// on to the next instruction if it matches.
+#[derive(Clone)]
+enum Inst {
+    Byte(u8),
+    Any,
+    Class(Class),
+    /// Continues only at the start of the text.
+    Start,
+    /// Continues only at the end of the text.
+    End,
+    /// Continues at both instructions.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// The most instructions a pattern may compile to, which bounded
+/// repetitions of large groups could otherwise run far past.
+const MAX_PROGRAM: usize = 1 << 16;
+
+/// The largest count a bounded repetition may have.
+const MAX_REPEAT: usize = 1000;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            bytes: pattern.as_bytes(),
+            pos: 0,
+        };
+        let program = parser
+            .alternation()
+            .and_then(|node| match parser.peek() {
+                None => Ok(node),
+                Some(_) => Err("unmatched ')'".to_string()),
+            })
+            .and_then(|node| {
+                let mut program = vec![];
+                compile(&node, &mut program)?;
+                program.push(Inst::Match);
+                Ok(program)
+            })
+            .map_err(|message| format!("{} in regex: {}", message, pattern))?;
+
+        Ok(Regex {
+            source: pattern.to_string(),
+            program,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &[u8]) -> bool {
+        let mut threads = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        for pos in 0..=text.len() {
+            // A match may start here as well as continue from earlier.
+            threads.add(&self.program, 0, pos, text);
+            if threads.matched {
+                return true;
+            }
+            let byte = match text.get(pos) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            next.clear();
+            for &pc in &threads.pcs {
+                let matches = match &self.program[pc] {
+                    Inst::Byte(b) => *b == byte,
+                    Inst::Any => true,
+                    Inst::Class(class) => class.matches(byte),
+                    _ => false,
+                };
+                if matches {
+                    next.add(&self.program, pc + 1, pos + 1, text);
+                }
+            }
+            std::mem::swap(&mut threads, &mut next);
+        }
+        threads.matched
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Regex").field(&self.source).finish()
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek();
+        self.pos += 1;
+        byte
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.eat(b'|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(byte) = self.peek() {
+            if byte == b'|' || byte == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.next() {
+            Some(b'.') => Node::Any,
+            Some(b'^') => Node::Start,
+            Some(b'$') => Node::End,
+            Some(b'(') => {
+                let node = self.alternation()?;
+                if !self.eat(b')') {
+                    return Err("unclosed '('".to_string());
+                }
+                node
+            }
+            Some(b'[') => Node::Class(self.class()?),
+            Some(b'\\') => self.escape()?,
+            Some(b'*') | Some(b'+') | Some(b'?') | Some(b'{') => {
+                return Err("nothing to repeat".to_string())
+            }
+            Some(byte) => Node::Byte(byte),
+            None => unreachable!(),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let byte = self.next().ok_or("trailing '\\'")?;
+        Ok(match escape_class(byte) {
+            Some(class) => Node::Class(class),
+            None => Node::Byte(escape_byte(byte)?),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat(b'^');
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let mut low = self.next().ok_or("unclosed '['")?;
+            if low == b']' && !first {
+                return Ok(Class { negated, ranges });
+            }
+            first = false;
+            if low == b'\\' {
+                let byte = self.next().ok_or("unclosed '['")?;
+                if let Some(class) = escape_class(byte) {
+                    if class.negated {
+                        return Err("negated escape inside '[...]'".to_string());
+                    }
+                    ranges.extend(class.ranges);
+                    continue;
+                }
+                low = escape_byte(byte)?;
+            }
+
+            let mut high = low;
+            if self.peek() == Some(b'-') && self.bytes.get(self.pos + 1) != Some(&b']') {
+                self.pos += 1;
+                high = self.next().ok_or("unclosed '['")?;
+                if high == b'\\' {
+                    high = escape_byte(self.next().ok_or("unclosed '['")?)?;
+                }
+                if high < low {
+                    return Err("invalid range in '[...]'".to_string());
+                }
+            }
+            ranges.push((low, high));
+        }
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            Some(b'{') => {
+                self.pos += 1;
+                let min = self.number().ok_or("expected a number after '{'")?;
+                let max = if self.eat(b',') {
+                    self.number()
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some(b'}') {
+                    return Err("unclosed '{'".to_string());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err("invalid repetition bounds".to_string());
+                }
+                if max.unwrap_or(min) > MAX_REPEAT {
+                    return Err(format!("repetition count over {}", MAX_REPEAT));
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("nothing to repeat".to_string());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+}
+
+/// The byte an escape other than a class stands for. Letters and digits are
+/// reserved, so that a pattern written for another engine fails rather than
+/// matching something else.
+fn escape_byte(byte: u8) -> Result<u8, String> {
+    match byte {
+        b'n' => Ok(b'\n'),
+        b't' => Ok(b'\t'),
+        byte if byte.is_ascii_alphanumeric() => Err(format!("unknown escape '\\{}'", byte as char)),
+        byte => Ok(byte),
+    }
+}
+
+fn escape_class(byte: u8) -> Option<Class> {
+    let (negated, ranges) = match byte {
+        b'd' | b'D' => (byte == b'D', vec![(b'0', b'9')]),
+        b'w' | b'W' => (
+            byte == b'W',
+            vec![(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')],
+        ),
+        b's' | b'S' => (byte == b'S', vec![(b'\t', b'\r'), (b' ', b' ')]),
+        _ => return None,
+    };
+    Some(Class { negated, ranges })
+}
+
+/// Appends the instructions for `node` to `program`.
+fn compile(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    match node {
+        Node::Empty => {}
+        Node::Byte(byte) => program.push(Inst::Byte(*byte)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program)?;
+            }
+        }
+        Node::Alternate(branches) => {
+            let (last, rest) = branches.split_last().unwrap();
+            let mut jumps = vec![];
+            for branch in rest {
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(branch, program)?;
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            compile(last, program)?;
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        // Repeating what compiles to nothing would only spin, however many
+        // times it is asked for.
+        Node::Repeat { node, .. } if is_empty(node) => {}
+        Node::Repeat { node, min, max } => {
+            for _ in 0..*min {
+                compile(node, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    // Each optional repetition is only tried once the ones
+                    // before it have matched.
+                    let mut splits = vec![];
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(node, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+    if program.len() > MAX_PROGRAM {
+        return Err("pattern too large".to_string());
+    }
+    Ok(())
+}
+
+/// Whether `node` compiles to no instructions at all.
+fn is_empty(node: &Node) -> bool {
+    match node {
+        Node::Empty => true,
+        Node::Concat(nodes) => nodes.iter().all(is_empty),
+        Node::Repeat { node, .. } => is_empty(node),
+        _ => false,
+    }
+}
+
+/// The instructions a match could be waiting at, at one position in the text.
+struct Threads {
+    pcs: Vec<usize>,
+    /// The generation each instruction was last reached in, so each is
+    /// followed once per position however many ways it is reached.
+    seen: Vec<usize>,
+    generation: usize,
+    matched: bool,
+    stack: Vec<usize>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            pcs: vec![],
+            seen: vec![0; len],
+            generation: 1,
+            matched: false,
+            stack: vec![],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pcs.clear();
+        self.generation += 1;
+        self.matched = false;
+    }
+
+    /// Adds the thread at `pc`, following jumps, splits and assertions at
+    /// `pos` to the instructions that consume a byte.
+    fn add(&mut self, program: &[Inst], pc: usize, pos: usize, text: &[u8]) {
+        self.stack.push(pc);
+        while let Some(pc) = self.stack.pop() {
+            if self.seen[pc] == self.generation {
+                continue;
+            }
+            self.seen[pc] = self.generation;
+            match program[pc] {
+                Inst::Jump(to) => self.stack.push(to),
+                Inst::Split(first, second) => {
+                    self.stack.push(second);
+                    self.stack.push(first);
+                }
+                Inst::Start if pos == 0 => self.stack.push(pc + 1),
+                Inst::End if pos == text.len() => self.stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Match => self.matched = true,
+                Inst::Byte(_) | Inst::Any | Inst::Class(_) => self.pcs.push(pc),
+            }
+        }
+    }
+}
+
+impl Class {
+    fn matches(&self, byte: u8) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(low, high)| low <= byte && byte <= high)
+            != self.negated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regex;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text.as_bytes())
+    }
+
+    #[test]
+    fn matches_literals_anywhere() {
+        assert!(matches("b", "abc"));
+        assert!(matches("", "abc"));
+        assert!(!matches("ac", "abc"));
+        assert!(matches("a.c", "abc"));
+        assert!(matches(r"a\.c", "a.c"));
+        assert!(!matches(r"a\.c", "abc"));
+        assert!(matches(r"\n", "a\nb"));
+    }
+
+    #[test]
+    fn matches_classes() {
+        assert!(matches("[abc]", "xbx"));
+        assert!(matches("^[a-c]+$", "abcba"));
+        assert!(!matches("^[a-c]+$", "abd"));
+        assert!(matches("^[^a-c]$", "d"));
+        assert!(!matches("[^a-c]", "abc"));
+        assert!(matches("^[]a]+$", "]a]"));
+        assert!(matches("^[a-]+$", "a-a"));
+        assert!(matches(r"^[\d_]+$", "4_2"));
+        assert!(matches(r"^\d\D\w\W\s\S$", "1a_- x"));
+        assert!(!matches(r"\d", "abc"));
+        assert!(Regex::new(r"[\D]").is_err());
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new("[ab").is_err());
+    }
+
+    #[test]
+    fn matches_anchors() {
+        assert!(matches("^ab", "abc"));
+        assert!(!matches("^bc", "abc"));
+        assert!(matches("bc$", "abc"));
+        assert!(!matches("ab$", "abc"));
+        assert!(matches("^$", ""));
+        assert!(!matches("^$", "a"));
+        assert!(matches("(^a|c$)", "bc"));
+        assert!(!matches("a^b", "ab"));
+    }
+
+    #[test]
+    fn matches_alternation() {
+        assert!(matches("^(cat|dog)s?$", "dogs"));
+        assert!(!matches("^(cat|dog)s?$", "cow"));
+        assert!(matches("^a|b$", "xb"));
+        assert!(matches("^(a|)$", ""));
+        assert!(matches("^(|a)b$", "ab"));
+    }
+
+    #[test]
+    fn matches_repetition() {
+        assert!(matches("^a*$", ""));
+        assert!(matches("^ab*c$", "abbbc"));
+        assert!(!matches("^ab+c$", "ac"));
+        assert!(matches("^ab?c$", "ac"));
+        assert!(!matches("^ab?c$", "abbc"));
+        assert!(matches("^(ab)*$", "ababab"));
+        assert!(!matches("^(ab)*$", "aba"));
+        assert!(matches("^(a*)*$", "aaa"));
+        assert!(matches("^(a|b)*c$", "abbac"));
+    }
+
+    #[test]
+    fn matches_repetition_bounds() {
+        assert!(matches("^a{3}$", "aaa"));
+        assert!(!matches("^a{3}$", "aa"));
+        assert!(!matches("^a{3}$", "aaaa"));
+        assert!(matches("^a{2,}$", "aaaaa"));
+        assert!(!matches("^a{2,}$", "a"));
+        assert!(matches("^a{1,2}$", "aa"));
+        assert!(!matches("^a{1,2}$", "aaa"));
+        assert!(matches("^(ab){0,1}$", ""));
+        assert!(matches("^a{0}b$", "b"));
+        assert!(Regex::new("a{2,1}").is_err());
+        assert!(Regex::new("a{").is_err());
+        assert!(Regex::new("a{1000}{1000}").is_err());
+        assert!(Regex::new("a{1001}").is_err());
+        assert!(Regex::new("a{0,99999999999}").is_err());
+        assert!(Regex::new("(){99999999999}").is_err());
+        assert!(Regex::new("(a{1000}){1000}").is_err());
+        assert!(matches("^((){1000}){1000}b$", "b"));
+        assert!(matches("^(a?){1000}$", "aaa"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in &[
+            "(a", "a)", "*a", "a|+", "^*", r"a\", r"\b", r"[\q]", r"[a-\z]",
+        ] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn matches_long_texts_without_backtracking() {
+        let text = vec![b'a'; 1 << 16];
+        assert!(!Regex::new("^.*b").unwrap().is_match(&text));
+        assert!(!Regex::new("^(a|a)*b").unwrap().is_match(&text));
+        assert!(Regex::new("^(a|aa)*$").unwrap().is_match(&text));
+    }
+}
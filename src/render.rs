@@ -0,0 +1,398 @@
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, mem};
+
+use crate::entry::{Entry, EntryData, SizeKind};
+use crate::metadata::{Accounts, Column};
+
+/// How to draw a tree.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    /// Show each entry's size in a column before the tree, and the total
+    /// size on a line after it.
+    pub sizes: Option<SizeFormat>,
+    /// Which size to show.
+    pub size_kind: SizeKind,
+    /// Metadata to show in columns before the sizes, in order. Entries
+    /// scanned without [`WalkOptions::metadata`](crate::WalkOptions::metadata)
+    /// leave them blank.
+    pub columns: Vec<Column>,
+}
+
+/// How to write a size in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeFormat {
+    pub units: SizeUnits,
+    /// Digits after the decimal point for sizes scaled to a larger unit.
+    pub precision: usize,
+    /// Show sizes as a count of blocks of this many bytes, rounded up, like
+    /// `du -B`. Overrides `units`.
+    pub block_size: Option<NonZeroU64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeUnits {
+    /// Plain byte counts.
+    Bytes,
+    /// Powers of 1024: KiB, MiB, GiB, ...
+    Iec,
+    /// Powers of 1000: kB, MB, GB, ...
+    Si,
+}
+
+/// A tree drawn with [`Entry::render`].
+pub struct Render<'a> {
+    entry: &'a Entry,
+    options: &'a RenderOptions,
+}
+
+/// What drawing a tree needs to know about all of it up front.
+struct Layout {
+    accounts: Accounts,
+    column_widths: Vec<usize>,
+    size_width: usize,
+}
+
+impl SizeFormat {
+    pub fn new(units: SizeUnits) -> Self {
+        SizeFormat {
+            units,
+            precision: 1,
+            block_size: None,
+        }
+    }
+
+    pub fn precision(mut self, precision: usize) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: NonZeroU64) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn format(&self, bytes: u64) -> String {
+        if let Some(block_size) = self.block_size {
+            return bytes.div_ceil(block_size.get()).to_string();
+        }
+
+        let (base, prefixes): (f64, &[&str]) = match self.units {
+            SizeUnits::Bytes => return bytes.to_string(),
+            SizeUnits::Iec => (1024.0, &["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+            SizeUnits::Si => (1000.0, &["kB", "MB", "GB", "TB", "PB", "EB"]),
+        };
+
+        if (bytes as f64) < base {
+            return format!("{} B", bytes);
+        }
+        let (largest, prefixes) = prefixes.split_last().unwrap();
+        let mut value = bytes as f64;
+        for prefix in prefixes {
+            value /= base;
+            // The unit is picked by the value as written, since rounding can
+            // carry it up to the next one, as 1023.96 KiB is 1024.0 KiB.
+            let written = format!("{:.*}", self.precision, value);
+            if written.parse::<f64>().unwrap() < base {
+                return format!("{} {}", written, prefix);
+            }
+        }
+        format!("{:.*} {}", self.precision, value / base, largest)
+    }
+}
+
+impl FromStr for SizeUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bytes" => SizeUnits::Bytes,
+            "iec" => SizeUnits::Iec,
+            "si" => SizeUnits::Si,
+            _ => return Err(format!("unknown size units: {}", s)),
+        })
+    }
+}
+
+impl<'a> Render<'a> {
+    pub(crate) fn new(entry: &'a Entry, options: &'a RenderOptions) -> Self {
+        Render { entry, options }
+    }
+
+    fn size_column(&self, entry: &Entry) -> Option<String> {
+        let format = self.options.sizes.as_ref()?;
+        Some(match entry.data {
+            EntryData::Error(..) => String::new(),
+            _ => format.format(entry.size_of(self.options.size_kind)),
+        })
+    }
+
+    fn metadata_column(&self, entry: &Entry, column: Column, accounts: &Accounts) -> String {
+        let metadata = match entry.metadata() {
+            Some(metadata) => metadata,
+            None => return String::new(),
+        };
+        let time = |time: Option<SystemTime>| time.map_or_else(|| "-".to_string(), format_time);
+        match column {
+            Column::Mode => metadata.mode_string(),
+            Column::Links => metadata.nlink.to_string(),
+            Column::User => accounts
+                .user(metadata.uid)
+                .map_or_else(|| metadata.uid.to_string(), str::to_string),
+            Column::Group => accounts
+                .group(metadata.gid)
+                .map_or_else(|| metadata.gid.to_string(), str::to_string),
+            Column::Inode => metadata.inode.to_string(),
+            Column::Device => metadata.dev.to_string(),
+            Column::Accessed => time(metadata.accessed),
+            Column::Modified => time(metadata.modified),
+            Column::Changed => time(metadata.changed),
+            Column::Created => time(metadata.created),
+        }
+    }
+
+    fn measure(&self, layout: &mut Layout) {
+        let mut stack = vec![self.entry];
+        while let Some(entry) = stack.pop() {
+            for (i, &column) in self.options.columns.iter().enumerate() {
+                let width = self.metadata_column(entry, column, &layout.accounts).len();
+                layout.column_widths[i] = layout.column_widths[i].max(width);
+            }
+            let width = self.size_column(entry).map_or(0, |size| size.len());
+            layout.size_width = layout.size_width.max(width);
+            stack.extend(entry.children().unwrap_or(&[]));
+        }
+    }
+
+    /// Draws the tree a line per entry, with a blank line after the entries
+    /// of each directory. Directories being drawn are kept on a stack rather
+    /// than the call stack, so that a tree of any depth can be drawn.
+    fn fmt_tree(&self, f: &mut fmt::Formatter, layout: &Layout) -> fmt::Result {
+        let mut branches = Branches::default();
+        self.fmt_entry(self.entry, f, layout, &mut branches, 0, true)?;
+        let mut stack = vec![];
+        if let EntryData::Directory(children) = &self.entry.data {
+            stack.push(children.iter().peekable());
+        }
+        while let Some(children) = stack.last_mut() {
+            match children.next() {
+                Some(child) => {
+                    let is_last = children.peek().is_none();
+                    self.fmt_entry(child, f, layout, &mut branches, stack.len(), is_last)?;
+                    if let EntryData::Directory(children) = &child.data {
+                        stack.push(children.iter().peekable());
+                    }
+                }
+                None => {
+                    stack.pop();
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the line of one entry.
+    fn fmt_entry(
+        &self,
+        entry: &Entry,
+        f: &mut fmt::Formatter,
+        layout: &Layout,
+        branches: &mut Branches,
+        depth: usize,
+        is_last: bool,
+    ) -> fmt::Result {
+        for (&column, &width) in self.options.columns.iter().zip(&layout.column_widths) {
+            let text = self.metadata_column(entry, column, &layout.accounts);
+            match column {
+                Column::Links | Column::Inode | Column::Device => {
+                    write!(f, "{:>width$} ", text, width = width)?
+                }
+                _ => write!(f, "{:<width$} ", text, width = width)?,
+            }
+        }
+        if !self.options.columns.is_empty() && self.options.sizes.is_none() {
+            write!(f, " ")?;
+        }
+        if let Some(size) = self.size_column(entry) {
+            write!(f, "{:>width$}  ", size, width = layout.size_width)?;
+        }
+        branches.write(f, depth, is_last)?;
+
+        write!(f, "{}", &entry.name.to_string_lossy())?;
+        if let Some(link) = entry.link() {
+            write!(f, " -> {} [{}]", link.target().display(), link.status())?;
+            if link.is_outside_root() {
+                write!(f, " [outside root]")?;
+            }
+        }
+        if entry.ignored {
+            write!(f, " [ignored]")?;
+        }
+
+        match &entry.data {
+            EntryData::File
+            | EntryData::Symlink(..)
+            | EntryData::Unknown
+            | EntryData::Directory(..) => {}
+            EntryData::Fifo => write!(f, " [fifo]")?,
+            EntryData::Socket => write!(f, " [socket]")?,
+            EntryData::BlockDevice(device) => write!(f, " [block {}]", device)?,
+            EntryData::CharDevice(device) => write!(f, " [char {}]", device)?,
+            EntryData::Loop => write!(f, " [loop]")?,
+            EntryData::Error(error) => write!(f, " [{}]", error)?,
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for Render<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let uses_accounts = self
+            .options
+            .columns
+            .iter()
+            .any(|&column| column == Column::User || column == Column::Group);
+        let mut layout = Layout {
+            accounts: if uses_accounts {
+                Accounts::load()
+            } else {
+                Accounts::default()
+            },
+            column_widths: vec![0; self.options.columns.len()],
+            size_width: 0,
+        };
+        self.measure(&mut layout);
+        self.fmt_tree(f, &layout)?;
+
+        if let Some(format) = &self.options.sizes {
+            let indent: usize = layout.column_widths.iter().map(|width| width + 1).sum();
+            writeln!(
+                f,
+                "{:indent$}{:>width$}  total",
+                "",
+                format.format(self.entry.size_of(self.options.size_kind)),
+                indent = indent,
+                width = layout.size_width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws the lines leading to entries, keeping the bars for the deepest
+/// entry drawn so far so that each line's are written in one go.
+#[derive(Default)]
+pub(crate) struct Branches {
+    bars: String,
+}
+
+impl Branches {
+    const BAR: &'static str = " \u{2502}";
+
+    /// Draws the lines leading to an entry `depth` levels below the root.
+    pub(crate) fn write(
+        &mut self,
+        f: &mut fmt::Formatter,
+        depth: usize,
+        is_last: bool,
+    ) -> fmt::Result {
+        if depth == 0 {
+            return Ok(());
+        }
+        let len = depth * Self::BAR.len();
+        while self.bars.len() < len {
+            self.bars.push_str(Self::BAR);
+        }
+        f.write_str(&self.bars[..len])?;
+        if is_last {
+            f.write_str(" \u{2514}")
+        } else {
+            f.write_str(" \u{251c}")
+        }
+    }
+}
+
+/// Writes a time in the local time zone as `2024-01-31 23:59`, like
+/// `ls --time-style=long-iso`.
+fn format_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as libc::time_t,
+        Err(error) => -(error.duration().as_secs() as libc::time_t),
+    };
+    // SAFETY: `localtime_r` only writes to the `tm` it is given, which is
+    // plain data that is valid when zeroed.
+    let tm = unsafe {
+        let mut tm: libc::tm = mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return "-".to_string();
+        }
+        tm
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use super::{SizeFormat, SizeUnits};
+
+    #[test]
+    fn formats_bytes() {
+        let format = SizeFormat::new(SizeUnits::Bytes);
+        assert_eq!(format.format(0), "0");
+        assert_eq!(format.format(1_048_575), "1048575");
+        assert_eq!(format.format(u64::MAX), "18446744073709551615");
+    }
+
+    #[test]
+    fn formats_iec_units() {
+        let format = SizeFormat::new(SizeUnits::Iec);
+        assert_eq!(format.format(0), "0 B");
+        assert_eq!(format.format(1023), "1023 B");
+        assert_eq!(format.format(1024), "1.0 KiB");
+        assert_eq!(format.format(1536), "1.5 KiB");
+        assert_eq!(format.format(1_048_575), "1.0 MiB");
+        assert_eq!(format.format(1_048_576), "1.0 MiB");
+        assert_eq!(format.precision(3).format(1_048_575), "1023.999 KiB");
+        assert_eq!(format.precision(0).format(1_048_063), "1023 KiB");
+        assert_eq!(format.precision(0).format(1_048_064), "1 MiB");
+        assert_eq!(format.format(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn formats_si_units() {
+        let format = SizeFormat::new(SizeUnits::Si);
+        assert_eq!(format.format(999), "999 B");
+        assert_eq!(format.format(1000), "1.0 kB");
+        assert_eq!(format.format(999_949), "999.9 kB");
+        assert_eq!(format.format(999_950), "1.0 MB");
+        assert_eq!(format.precision(2).format(1_234_567_890), "1.23 GB");
+        assert_eq!(format.format(u64::MAX), "18.4 EB");
+    }
+
+    #[test]
+    fn formats_blocks() {
+        let block_size = |size| NonZeroU64::new(size).unwrap();
+        let format = SizeFormat::new(SizeUnits::Iec).block_size(block_size(1024));
+        assert_eq!(format.format(0), "0");
+        assert_eq!(format.format(1), "1");
+        assert_eq!(format.format(1024), "1");
+        assert_eq!(format.format(1025), "2");
+        assert_eq!(
+            SizeFormat::new(SizeUnits::Si)
+                .block_size(block_size(1))
+                .format(999_950),
+            "999950"
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::{fmt, io};
+
+use serde::de::{self, Deserializer, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::entry::{Device, Entry, EntryData};
+use crate::error::{Error, Operation};
+use crate::link::Link;
+use crate::metadata::Metadata;
+
+/// Writes a name or path as a string in human-readable formats when it is
+/// valid UTF-8, and as bytes otherwise, so that it round-trips byte for
+/// byte in every format.
+fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if serializer.is_human_readable() => serializer.serialize_str(text),
+        _ => serializer.serialize_bytes(bytes),
+    }
+}
+
+/// Reads back what [`serialize_bytes`] wrote.
+fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(BytesVisitor)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string or bytes")
+    }
+
+    fn visit_str<E>(self, text: &str) -> Result<Vec<u8>, E> {
+        Ok(text.as_bytes().to_vec())
+    }
+
+    fn visit_string<E>(self, text: String) -> Result<Vec<u8>, E> {
+        Ok(text.into_bytes())
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// For `#[serde(with)]` on names and paths.
+pub(crate) mod os_bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &impl AsRef<OsStr>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serialize_bytes(value.as_ref().as_bytes(), serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: From<OsString>,
+    {
+        deserialize_bytes(deserializer).map(|bytes| OsString::from_vec(bytes).into())
+    }
+}
+
+/// For `#[serde(with)]` on optional paths.
+pub(crate) mod option_os_bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(Bytes::from).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        let bytes = Option::<ByteBuf>::deserialize(deserializer)?;
+        Ok(bytes.map(|ByteBuf(bytes)| OsString::from_vec(bytes).into()))
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl<'a> From<&'a PathBuf> for Bytes<'a> {
+        fn from(path: &'a PathBuf) -> Self {
+            Bytes(path.as_os_str().as_bytes())
+        }
+    }
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_bytes(self.0, serializer)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize_bytes(deserializer).map(ByteBuf)
+        }
+    }
+}
+
+/// For `#[serde(with)]` on optional times, which are written as seconds and
+/// nanoseconds since the epoch, as in the JSON output, so that times before
+/// it can be written too.
+pub(crate) mod option_time {
+    use std::time::SystemTime;
+
+    use serde::de::Error as _;
+
+    use super::*;
+    use crate::metadata::{split_time, timestamp};
+
+    #[derive(Serialize, Deserialize)]
+    struct Time {
+        secs: i64,
+        nanos: u32,
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value
+            .map(|time| {
+                let (secs, nanos) = split_time(time);
+                Time { secs, nanos }
+            })
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        match Option::<Time>::deserialize(deserializer)? {
+            Some(Time { secs, nanos }) => timestamp(secs, nanos.into())
+                .map(Some)
+                .ok_or_else(|| D::Error::custom("time out of range")),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How a scan error is written: the I/O error is kept as its OS error code
+/// if it has one, and as its message otherwise, as in the JSON output.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Error")]
+struct ErrorData {
+    operation: Operation,
+    #[serde(with = "os_bytes")]
+    path: PathBuf,
+    depth: usize,
+    message: String,
+    os_error: Option<i32>,
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ErrorData {
+            operation: self.operation(),
+            path: self.path().to_path_buf(),
+            depth: self.depth(),
+            message: self.io_error().to_string(),
+            os_error: self.io_error().raw_os_error(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = ErrorData::deserialize(deserializer)?;
+        let source = match data.os_error {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::other(data.message),
+        };
+        Ok(Error::new(data.operation, data.path, data.depth, source))
+    }
+}
+
+/// How an entry is written: its own fields, with the number of entries in
+/// it in place of a directory's children.
+#[derive(Serialize)]
+#[serde(rename = "Entry")]
+struct NodeRef<'a> {
+    #[serde(with = "os_bytes")]
+    name: &'a OsString,
+    size: u64,
+    disk_usage: u64,
+    links: u64,
+    #[serde(with = "option_time")]
+    modified: Option<SystemTime>,
+    ignored: bool,
+    link: &'a Option<Box<Link>>,
+    metadata: &'a Option<Box<Metadata>>,
+    content_hash: Option<u64>,
+    data: DataRef<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "EntryData")]
+enum DataRef<'a> {
+    File,
+    Symlink(#[serde(with = "os_bytes")] &'a PathBuf),
+    Directory(usize),
+    Fifo,
+    Socket,
+    BlockDevice(Device),
+    CharDevice(Device),
+    Unknown,
+    Loop,
+    Error(&'a Error),
+}
+
+/// What [`NodeRef`] wrote.
+#[derive(Deserialize)]
+#[serde(rename = "Entry")]
+struct Node {
+    #[serde(with = "os_bytes")]
+    name: OsString,
+    size: u64,
+    disk_usage: u64,
+    links: u64,
+    #[serde(with = "option_time")]
+    modified: Option<SystemTime>,
+    ignored: bool,
+    link: Option<Box<Link>>,
+    metadata: Option<Box<Metadata>>,
+    content_hash: Option<u64>,
+    data: Data,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "EntryData")]
+enum Data {
+    File,
+    Symlink(#[serde(with = "os_bytes")] PathBuf),
+    Directory(usize),
+    Fifo,
+    Socket,
+    BlockDevice(Device),
+    CharDevice(Device),
+    Unknown,
+    Loop,
+    Error(Error),
+}
+
+impl<'a> From<&'a Entry> for NodeRef<'a> {
+    fn from(entry: &'a Entry) -> Self {
+        NodeRef {
+            name: &entry.name,
+            size: entry.size,
+            disk_usage: entry.disk_usage,
+            links: entry.links,
+            modified: entry.modified,
+            ignored: entry.ignored,
+            link: &entry.link,
+            metadata: &entry.metadata,
+            content_hash: entry.content_hash,
+            data: match &entry.data {
+                EntryData::File => DataRef::File,
+                EntryData::Symlink(target) => DataRef::Symlink(target),
+                EntryData::Directory(children) => DataRef::Directory(children.len()),
+                EntryData::Fifo => DataRef::Fifo,
+                EntryData::Socket => DataRef::Socket,
+                EntryData::BlockDevice(device) => DataRef::BlockDevice(*device),
+                EntryData::CharDevice(device) => DataRef::CharDevice(*device),
+                EntryData::Unknown => DataRef::Unknown,
+                EntryData::Loop => DataRef::Loop,
+                EntryData::Error(error) => DataRef::Error(error),
+            },
+        }
+    }
+}
+
+impl Node {
+    /// The entry, without its children, and how many it has.
+    fn into_entry(self) -> (Entry, usize) {
+        let mut children = 0;
+        let data = match self.data {
+            Data::File => EntryData::File,
+            Data::Symlink(target) => EntryData::Symlink(target),
+            Data::Directory(count) => {
+                children = count;
+                EntryData::Directory(Vec::with_capacity(count.min(4096)))
+            }
+            Data::Fifo => EntryData::Fifo,
+            Data::Socket => EntryData::Socket,
+            Data::BlockDevice(device) => EntryData::BlockDevice(device),
+            Data::CharDevice(device) => EntryData::CharDevice(device),
+            Data::Unknown => EntryData::Unknown,
+            Data::Loop => EntryData::Loop,
+            Data::Error(error) => EntryData::Error(error),
+        };
+        let entry = Entry {
+            name: self.name,
+            size: self.size,
+            disk_usage: self.disk_usage,
+            links: self.links,
+            modified: self.modified,
+            ignored: self.ignored,
+            link: self.link,
+            metadata: self.metadata,
+            content_hash: self.content_hash,
+            data,
+        };
+        (entry, children)
+    }
+}
+
+/// An entry is written as a sequence of it and every entry below it, in
+/// depth-first order, rather than with its children nested in it, so that
+/// neither writing nor reading a tree recurses once per level.
+impl Serialize for Entry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut count = 0;
+        let mut stack = vec![self];
+        while let Some(entry) = stack.pop() {
+            count += 1;
+            stack.extend(entry.children().unwrap_or(&[]));
+        }
+
+        let mut seq = serializer.serialize_seq(Some(count))?;
+        let mut stack = vec![self];
+        while let Some(entry) = stack.pop() {
+            seq.serialize_element(&NodeRef::from(entry))?;
+            stack.extend(entry.children().unwrap_or(&[]).iter().rev());
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(EntryVisitor)
+    }
+}
+
+struct EntryVisitor;
+
+impl<'de> Visitor<'de> for EntryVisitor {
+    type Value = Entry;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Entry, A::Error> {
+        // Directories still being filled, with how many entries each is
+        // still to get.
+        let mut stack: Vec<(Entry, usize)> = vec![];
+        loop {
+            let node: Node = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::custom("missing entries"))?;
+            stack.push(node.into_entry());
+            while let Some(&(_, 0)) = stack.last() {
+                let (entry, _) = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some((parent, remaining)) => {
+                        parent.children_mut().unwrap().push(entry);
+                        *remaining -= 1;
+                    }
+                    None => {
+                        if seq.next_element::<IgnoredAny>()?.is_some() {
+                            return Err(de::Error::custom("trailing entries"));
+                        }
+                        return Ok(entry);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::time::SystemTime;
+
+use crate::dir::Backend;
+use crate::entry::{Device, Entry, EntryData, SizeKind};
+use crate::error::{Error, Operation};
+use crate::filter::{Filter, Pattern};
+use crate::link::{Link, LinkStatus};
+use crate::metadata::{self, Metadata};
+use crate::sort::{SortKey, SortOrder};
+use crate::tree::FileTree;
+use crate::walk::{HardLinks, WalkOptions};
+
+const MAGIC: &[u8; 8] = b"FTSNAP\n\0";
+/// The version written. Version 1, which lacks content hashes, can still be
+/// read.
+const VERSION: u64 = 2;
+
+/// A scanned tree together with when and how it was scanned, which can be
+/// saved to a compact binary file and loaded again without rescanning.
+pub struct Snapshot {
+    tree: FileTree,
+    scanned_at: SystemTime,
+    options: WalkOptions,
+}
+
+impl Snapshot {
+    /// Records `tree`, scanned just now with `options`.
+    pub fn new(tree: FileTree, options: WalkOptions) -> Self {
+        Snapshot {
+            tree,
+            scanned_at: SystemTime::now(),
+            options,
+        }
+    }
+
+    pub fn tree(&self) -> &FileTree {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> FileTree {
+        self.tree
+    }
+
+    /// When the scan finished.
+    pub fn scanned_at(&self) -> SystemTime {
+        self.scanned_at
+    }
+
+    /// The options the tree was scanned with.
+    pub fn options(&self) -> &WalkOptions {
+        &self.options
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Snapshot::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, out: impl Write) -> io::Result<()> {
+        let mut encoder = Encoder { out };
+        encoder.out.write_all(MAGIC)?;
+        encoder.uint(VERSION)?;
+        encoder.bytes(self.tree.root_path().as_os_str().as_bytes())?;
+        encoder.time(self.scanned_at)?;
+        encoder.options(&self.options)?;
+        encoder.tree(self.tree.root())
+    }
+
+    /// Reads a snapshot written by [`write`](Snapshot::write). Fails with
+    /// [`io::ErrorKind::InvalidData`] if it is not one, or was written by an
+    /// incompatible version.
+    pub fn read(input: impl Read) -> io::Result<Self> {
+        let mut decoder = Decoder { input, version: 0 };
+        let mut magic = [0; 8];
+        decoder.input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        decoder.version = decoder.uint()?;
+        if !(1..=VERSION).contains(&decoder.version) {
+            return Err(invalid(format!(
+                "unsupported snapshot version {}",
+                decoder.version
+            )));
+        }
+        let root_path = PathBuf::from(decoder.os_string()?);
+        let scanned_at = decoder.time()?;
+        let options = decoder.options()?;
+        let root = decoder.tree()?;
+        Ok(Snapshot {
+            tree: FileTree::new(root_path, root),
+            scanned_at,
+            options,
+        })
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+const FILE: u8 = 0;
+const SYMLINK: u8 = 1;
+const DIRECTORY: u8 = 2;
+const FIFO: u8 = 3;
+const SOCKET: u8 = 4;
+const BLOCK_DEVICE: u8 = 5;
+const CHAR_DEVICE: u8 = 6;
+const UNKNOWN: u8 = 7;
+const LOOP: u8 = 8;
+const ERROR: u8 = 9;
+
+const SORT_KEYS: &[SortKey] = &[
+    SortKey::Name,
+    SortKey::NameIgnoreCase,
+    SortKey::Version,
+    SortKey::Size,
+    SortKey::Modified,
+    SortKey::Extension,
+];
+const SIZE_KINDS: &[SizeKind] = &[SizeKind::Apparent, SizeKind::Allocated];
+const HARD_LINKS: &[HardLinks] = &[HardLinks::FirstSeen, HardLinks::Split, HardLinks::CountAll];
+const LINK_STATUSES: &[LinkStatus] = &[
+    LinkStatus::File,
+    LinkStatus::Directory,
+    LinkStatus::Other,
+    LinkStatus::Dangling,
+    LinkStatus::Loop,
+];
+const OPERATIONS: &[Operation] = &[
+    Operation::ReadDir,
+    Operation::Stat,
+    Operation::ReadLink,
+    Operation::ReadFile,
+    Operation::Canonicalize,
+    Operation::WriteEvent,
+];
+
+/// The index of `value` in `table`, which must list every variant.
+fn tag<T: PartialEq>(table: &[T], value: &T) -> u8 {
+    table.iter().position(|v| v == value).unwrap() as u8
+}
+
+/// Writes integers as LEB128 varints and byte strings with their length in
+/// front.
+struct Encoder<W> {
+    out: W,
+}
+
+impl<W: Write> Encoder<W> {
+    fn u8(&mut self, byte: u8) -> io::Result<()> {
+        self.out.write_all(&[byte])
+    }
+
+    fn bool(&mut self, b: bool) -> io::Result<()> {
+        self.u8(b as u8)
+    }
+
+    fn uint(&mut self, mut n: u64) -> io::Result<()> {
+        while n >= 0x80 {
+            self.u8(n as u8 | 0x80)?;
+            n >>= 7;
+        }
+        self.u8(n as u8)
+    }
+
+    fn int(&mut self, n: i64) -> io::Result<()> {
+        self.uint(((n << 1) ^ (n >> 63)) as u64)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.uint(bytes.len() as u64)?;
+        self.out.write_all(bytes)
+    }
+
+    fn option<T>(
+        &mut self,
+        value: Option<T>,
+        write: impl FnOnce(&mut Self, T) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.bool(value.is_some())?;
+        match value {
+            Some(value) => write(self, value),
+            None => Ok(()),
+        }
+    }
+
+    fn time(&mut self, time: SystemTime) -> io::Result<()> {
+        let (secs, nanos) = metadata::split_time(time);
+        self.int(secs)?;
+        self.uint(nanos as u64)
+    }
+
+    fn options(&mut self, options: &WalkOptions) -> io::Result<()> {
+        self.option(options.max_depth, |e, n| e.uint(n as u64))?;
+        self.bool(options.show_hidden)?;
+        self.bool(options.one_file_system)?;
+        self.option(options.min_entries, |e, n| e.uint(n as u64))?;
+        self.option(options.max_entries, |e, n| e.uint(n as u64))?;
+        self.option(options.sort.as_ref(), |e, order| {
+            e.u8(tag(SORT_KEYS, &order.key))?;
+            e.bool(order.reverse)?;
+            e.bool(order.dirs_first)?;
+            e.u8(tag(SIZE_KINDS, &order.size_kind))
+        })?;
+        for patterns in &[options.filter.includes(), options.filter.excludes()] {
+            self.uint(patterns.len() as u64)?;
+            for pattern in patterns.iter() {
+                self.bool(pattern.is_regex())?;
+                self.bool(pattern.is_on_path())?;
+                self.bytes(pattern.as_str().as_bytes())?;
+            }
+        }
+        self.bool(options.count_filtered)?;
+        self.bool(options.git_ignore)?;
+        self.bool(options.show_ignored)?;
+        self.bool(options.keep_going)?;
+        self.u8(tag(HARD_LINKS, &options.hard_links))?;
+        self.bool(options.follow_symlinks)?;
+        self.bool(options.metadata)?;
+        self.bool(options.hash_contents)
+    }
+
+    /// Writes `root` and everything below it, each directory followed by
+    /// its entries. Directories being written are kept on a stack rather
+    /// than the call stack, so that a tree of any depth can be written.
+    fn tree(&mut self, root: &Entry) -> io::Result<()> {
+        let mut stack = vec![slice::from_ref(root).iter()];
+        while let Some(entries) = stack.last_mut() {
+            match entries.next() {
+                Some(entry) => {
+                    self.entry(entry)?;
+                    if let EntryData::Directory(children) = &entry.data {
+                        stack.push(children.iter());
+                    }
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an entry, with the number of entries in it if it is a
+    /// directory but not the entries themselves.
+    fn entry(&mut self, entry: &Entry) -> io::Result<()> {
+        let kind = match &entry.data {
+            EntryData::File => FILE,
+            EntryData::Symlink(..) => SYMLINK,
+            EntryData::Directory(..) => DIRECTORY,
+            EntryData::Fifo => FIFO,
+            EntryData::Socket => SOCKET,
+            EntryData::BlockDevice(..) => BLOCK_DEVICE,
+            EntryData::CharDevice(..) => CHAR_DEVICE,
+            EntryData::Unknown => UNKNOWN,
+            EntryData::Loop => LOOP,
+            EntryData::Error(..) => ERROR,
+        };
+        self.u8(kind)?;
+        self.bytes(entry.name.as_bytes())?;
+        self.uint(entry.size)?;
+        self.uint(entry.disk_usage)?;
+        self.uint(entry.links)?;
+        self.option(entry.modified, Self::time)?;
+        self.bool(entry.ignored)?;
+        self.option(entry.link(), |e, link| {
+            e.bytes(link.target.as_os_str().as_bytes())?;
+            e.option(link.resolved(), |e, path| {
+                e.bytes(path.as_os_str().as_bytes())
+            })?;
+            e.u8(tag(LINK_STATUSES, &link.status))?;
+            e.bool(link.outside_root)
+        })?;
+        self.option(entry.metadata(), Self::metadata)?;
+        self.option(entry.content_hash, |e, hash| {
+            e.out.write_all(&hash.to_le_bytes())
+        })?;
+
+        match &entry.data {
+            EntryData::Directory(children) => self.uint(children.len() as u64)?,
+            EntryData::BlockDevice(device) | EntryData::CharDevice(device) => {
+                self.uint(device.major as u64)?;
+                self.uint(device.minor as u64)?;
+            }
+            EntryData::Error(error) => {
+                self.u8(tag(OPERATIONS, &error.operation()))?;
+                self.bytes(error.path().as_os_str().as_bytes())?;
+                self.uint(error.depth() as u64)?;
+                self.option(error.io_error().raw_os_error(), |e, code| {
+                    e.int(code as i64)
+                })?;
+                self.bytes(error.io_error().to_string().as_bytes())?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, metadata: &Metadata) -> io::Result<()> {
+        self.uint(metadata.mode as u64)?;
+        self.uint(metadata.uid as u64)?;
+        self.uint(metadata.gid as u64)?;
+        self.uint(metadata.inode)?;
+        self.uint(metadata.dev)?;
+        self.uint(metadata.nlink)?;
+        for time in &[
+            metadata.accessed,
+            metadata.modified,
+            metadata.changed,
+            metadata.created,
+        ] {
+            self.option(*time, Self::time)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<R> {
+    input: R,
+    version: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(invalid(format!("invalid flag {}", byte))),
+        }
+    }
+
+    fn uint(&mut self) -> io::Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            // The tenth byte holds only the top bit.
+            if shift == 63 && byte & 0x7f > 1 {
+                return Err(invalid("integer too large"));
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("integer too long"))
+    }
+
+    fn uint_as<T: TryFrom<u64>>(&mut self) -> io::Result<T> {
+        T::try_from(self.uint()?).map_err(|_| invalid("integer out of range"))
+    }
+
+    fn int(&mut self) -> io::Result<i64> {
+        let n = self.uint()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.uint()?;
+        let mut bytes = vec![];
+        self.input.by_ref().take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    fn os_string(&mut self) -> io::Result<OsString> {
+        Ok(OsString::from_vec(self.bytes()?))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("invalid UTF-8"))
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        if self.bool()? {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn tagged<T: Copy>(&mut self, table: &[T]) -> io::Result<T> {
+        let tag = self.u8()?;
+        table
+            .get(tag as usize)
+            .copied()
+            .ok_or_else(|| invalid(format!("invalid tag {}", tag)))
+    }
+
+    fn time(&mut self) -> io::Result<SystemTime> {
+        let secs = self.int()?;
+        let nanos = self.uint_as()?;
+        metadata::timestamp(secs, nanos).ok_or_else(|| invalid("time out of range"))
+    }
+
+    fn options(&mut self) -> io::Result<WalkOptions> {
+        let max_depth = self.option(Self::uint_as)?;
+        let show_hidden = self.bool()?;
+        let one_file_system = self.bool()?;
+        let min_entries = self.option(Self::uint_as)?;
+        let max_entries = self.option(Self::uint_as)?;
+        let sort = self.option(|d| {
+            Ok(SortOrder::new(d.tagged(SORT_KEYS)?)
+                .reverse(d.bool()?)
+                .dirs_first(d.bool()?)
+                .size_kind(d.tagged(SIZE_KINDS)?))
+        })?;
+        let mut filter = Filter::new();
+        for include in &[true, false] {
+            for _ in 0..self.uint()? {
+                let is_regex = self.bool()?;
+                let on_path = self.bool()?;
+                let source = self.string()?;
+                let pattern = if is_regex {
+                    Pattern::regex(&source)
+                } else {
+                    Pattern::glob(&source)
+                };
+                let pattern = pattern
+                    .map_err(|error| invalid(error.to_string()))?
+                    .on_path(on_path);
+                filter = if *include {
+                    filter.include(pattern)
+                } else {
+                    filter.exclude(pattern)
+                };
+            }
+        }
+        Ok(WalkOptions {
+            max_depth,
+            show_hidden,
+            one_file_system,
+            min_entries,
+            max_entries,
+            sort,
+            filter,
+            count_filtered: self.bool()?,
+            git_ignore: self.bool()?,
+            show_ignored: self.bool()?,
+            keep_going: self.bool()?,
+            hard_links: self.tagged(HARD_LINKS)?,
+            follow_symlinks: self.bool()?,
+            metadata: self.bool()?,
+            hash_contents: self.version >= 2 && self.bool()?,
+            // How a scan was spread over threads does not change its result.
+            threads: 0,
+            backend: Backend::default(),
+            max_open_dirs: None,
+        })
+    }
+
+    /// Reads an entry and everything below it. Directories being read are
+    /// kept on a stack rather than the call stack, so that a tree of any
+    /// depth can be read.
+    fn tree(&mut self) -> io::Result<Entry> {
+        // Directories being read, each with how many of its entries are
+        // still to read.
+        let mut stack: Vec<(Entry, u64)> = vec![];
+        loop {
+            let (entry, count) = self.entry()?;
+            let mut entry = if entry.is_dir() {
+                stack.push((entry, count));
+                None
+            } else {
+                Some(entry)
+            };
+            loop {
+                let (dir, remaining) = match stack.last_mut() {
+                    Some(dir) => dir,
+                    None => return Ok(entry.unwrap()),
+                };
+                if let (Some(entry), EntryData::Directory(entries)) = (entry.take(), &mut dir.data)
+                {
+                    entries.push(entry);
+                }
+                if *remaining > 0 {
+                    *remaining -= 1;
+                    break;
+                }
+                entry = Some(stack.pop().unwrap().0);
+            }
+        }
+    }
+
+    /// Reads an entry, leaving out the entries of a directory, which follow
+    /// it, and returns how many of those there are.
+    fn entry(&mut self) -> io::Result<(Entry, u64)> {
+        let kind = self.u8()?;
+        let name = self.os_string()?;
+        let size = self.uint()?;
+        let disk_usage = self.uint()?;
+        let links = self.uint()?;
+        let modified = self.option(Self::time)?;
+        let ignored = self.bool()?;
+        let link = self.option(|d| {
+            Ok(Box::new(Link {
+                target: PathBuf::from(d.os_string()?),
+                resolved: d.option(|d| d.os_string().map(PathBuf::from))?,
+                status: d.tagged(LINK_STATUSES)?,
+                outside_root: d.bool()?,
+            }))
+        })?;
+        let metadata = self.option(|d| d.metadata().map(Box::new))?;
+        let content_hash = if self.version >= 2 {
+            self.option(|d| {
+                let mut hash = [0; 8];
+                d.input.read_exact(&mut hash)?;
+                Ok(u64::from_le_bytes(hash))
+            })?
+        } else {
+            None
+        };
+
+        let mut count = 0;
+        let data = match kind {
+            FILE => EntryData::File,
+            SYMLINK => EntryData::Symlink(
+                link.as_ref()
+                    .map(|link| link.target.clone())
+                    .ok_or_else(|| invalid("symlink without target"))?,
+            ),
+            DIRECTORY => {
+                count = self.uint()?;
+                EntryData::Directory(vec![])
+            }
+            FIFO => EntryData::Fifo,
+            SOCKET => EntryData::Socket,
+            BLOCK_DEVICE => EntryData::BlockDevice(self.device()?),
+            CHAR_DEVICE => EntryData::CharDevice(self.device()?),
+            UNKNOWN => EntryData::Unknown,
+            LOOP => EntryData::Loop,
+            ERROR => {
+                let operation = self.tagged(OPERATIONS)?;
+                let path = self.os_string()?;
+                let depth = self.uint_as()?;
+                let os_error = self.option(|d| {
+                    i32::try_from(d.int()?).map_err(|_| invalid("error code out of range"))
+                })?;
+                let message = self.string()?;
+                let source = match os_error {
+                    Some(code) => io::Error::from_raw_os_error(code),
+                    None => io::Error::other(message),
+                };
+                EntryData::Error(Error::new(operation, path, depth, source))
+            }
+            kind => return Err(invalid(format!("invalid entry kind {}", kind))),
+        };
+
+        let entry = Entry {
+            name,
+            size,
+            disk_usage,
+            links,
+            modified,
+            ignored,
+            link,
+            metadata,
+            content_hash,
+            data,
+        };
+        Ok((entry, count))
+    }
+
+    fn device(&mut self) -> io::Result<Device> {
+        Ok(Device {
+            major: self.uint_as()?,
+            minor: self.uint_as()?,
+        })
+    }
+
+    fn metadata(&mut self) -> io::Result<Metadata> {
+        Ok(Metadata {
+            mode: self.uint_as()?,
+            uid: self.uint_as()?,
+            gid: self.uint_as()?,
+            inode: self.uint()?,
+            dev: self.uint()?,
+            nlink: self.uint()?,
+            accessed: self.option(Self::time)?,
+            modified: self.option(Self::time)?,
+            changed: self.option(Self::time)?,
+            created: self.option(Self::time)?,
+        })
+    }
+}
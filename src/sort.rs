@@ -0,0 +1,178 @@
+use std::cmp::Ordering;
+use std::os::unix::ffi::OsStrExt;
+use std::str::FromStr;
+
+use crate::entry::{Entry, SizeKind};
+
+/// What to compare entries by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    /// Byte-wise comparison of names.
+    Name,
+    /// Names compared without regard to case.
+    NameIgnoreCase,
+    /// Names compared with runs of digits ordered numerically, so `file2`
+    /// sorts before `file10`.
+    Version,
+    /// Largest first, by the order's [`SizeKind`].
+    Size,
+    /// Most recently modified first.
+    Modified,
+    /// By extension, then by name. Names without an extension sort first.
+    Extension,
+}
+
+/// An ordering of the entries within each directory.
+///
+/// Entries that compare equal by the key are ordered by name, so the result
+/// never depends on the order the file system returned them in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub reverse: bool,
+    /// List directories before all other entries, regardless of `reverse`.
+    pub dirs_first: bool,
+    /// Which size [`SortKey::Size`] compares.
+    pub size_kind: SizeKind,
+}
+
+impl SortOrder {
+    pub fn new(key: SortKey) -> Self {
+        SortOrder {
+            key,
+            reverse: false,
+            dirs_first: false,
+            size_kind: SizeKind::Apparent,
+        }
+    }
+
+    pub fn reverse(mut self, yes: bool) -> Self {
+        self.reverse = yes;
+        self
+    }
+
+    pub fn dirs_first(mut self, yes: bool) -> Self {
+        self.dirs_first = yes;
+        self
+    }
+
+    pub fn size_kind(mut self, kind: SizeKind) -> Self {
+        self.size_kind = kind;
+        self
+    }
+
+    pub fn compare(&self, a: &Entry, b: &Entry) -> Ordering {
+        if self.dirs_first {
+            let ordering = b.is_dir().cmp(&a.is_dir());
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        let name_a = a.name().as_bytes();
+        let name_b = b.name().as_bytes();
+        let ordering = match self.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::NameIgnoreCase => compare_ignore_case(name_a, name_b),
+            SortKey::Version => compare_version(name_a, name_b),
+            SortKey::Size => b.size_of(self.size_kind).cmp(&a.size_of(self.size_kind)),
+            SortKey::Modified => b.modified().cmp(&a.modified()),
+            SortKey::Extension => extension(name_a).cmp(extension(name_b)),
+        }
+        .then_with(|| name_a.cmp(name_b));
+
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    pub(crate) fn sort(&self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| self.compare(a, b));
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "name" => SortKey::Name,
+            "iname" => SortKey::NameIgnoreCase,
+            "version" => SortKey::Version,
+            "size" => SortKey::Size,
+            "mtime" => SortKey::Modified,
+            "extension" => SortKey::Extension,
+            _ => return Err(format!("unknown sort key: {}", s)),
+        })
+    }
+}
+
+fn compare_ignore_case(a: &[u8], b: &[u8]) -> Ordering {
+    let a = String::from_utf8_lossy(a).to_lowercase();
+    let b = String::from_utf8_lossy(b).to_lowercase();
+    a.cmp(&b)
+}
+
+fn compare_version(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (digits_a, rest_a) = split_digits(a);
+                let (digits_b, rest_b) = split_digits(b);
+                let ordering = compare_numbers(digits_a, digits_b);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = rest_a;
+                b = rest_b;
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Compares two runs of ASCII digits by numeric value, without overflowing on
+/// long runs.
+fn compare_numbers(a: &[u8], b: &[u8]) -> Ordering {
+    let (trimmed_a, trimmed_b) = (trim_zeros(a), trim_zeros(b));
+    trimmed_a
+        .len()
+        .cmp(&trimmed_b.len())
+        .then_with(|| trimmed_a.cmp(trimmed_b))
+        // Fewer leading zeros first, so "1" < "01".
+        .then_with(|| a.len().cmp(&b.len()))
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let start = digits
+        .iter()
+        .position(|&c| c != b'0')
+        .unwrap_or(digits.len());
+    &digits[start..]
+}
+
+fn extension(name: &[u8]) -> &[u8] {
+    match name.iter().rposition(|&c| c == b'.') {
+        Some(0) | None => b"",
+        Some(dot) => &name[dot + 1..],
+    }
+}
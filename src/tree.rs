@@ -0,0 +1,301 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::diff::{DiffOptions, TreeDiff};
+use crate::dir::Backend;
+use crate::entry::Entry;
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+use crate::json::{self, JsonError, Value};
+use crate::render::{Render, RenderOptions};
+use crate::sort::SortOrder;
+use crate::visit::{self, BreadthFirst, DepthFirst, Visitor, VisitorMut};
+use crate::walk::{Event, HardLinks, WalkOptions, Walker};
+
+/// The result of scanning a directory.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileTree {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::os_bytes"))]
+    root_path: PathBuf,
+    #[cfg_attr(feature = "serde", serde(rename = "root"))]
+    root_entry: Entry,
+}
+
+impl FileTree {
+    pub(crate) fn new(root_path: PathBuf, root_entry: Entry) -> Self {
+        FileTree {
+            root_path,
+            root_entry,
+        }
+    }
+
+    /// Starts configuring a scan of the directory at `path`.
+    pub fn builder(path: impl AsRef<Path>) -> TreeBuilder {
+        TreeBuilder::new(path)
+    }
+
+    /// The path the tree was scanned from.
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// The entry for the scanned directory itself.
+    pub fn root(&self) -> &Entry {
+        &self.root_entry
+    }
+
+    pub fn into_root(self) -> Entry {
+        self.root_entry
+    }
+
+    /// The errors of every entry that could not be read. Always empty unless
+    /// the scan was told to keep going, or skipped an ignore file it could not
+    /// read.
+    pub fn errors(&self) -> Vec<&Error> {
+        self.root_entry.errors()
+    }
+
+    /// Every broken symlink in the tree, with its path below
+    /// [`root_path`](FileTree::root_path).
+    pub fn broken_links(&self) -> Vec<(PathBuf, &Entry)> {
+        let mut links = vec![];
+        self.root_entry
+            .collect_broken_links(self.root_path.clone(), &mut links);
+        links
+    }
+
+    /// Every entry in the tree, each directory followed by what is in it,
+    /// with paths starting at [`root_path`](FileTree::root_path).
+    pub fn depth_first(&self) -> DepthFirst<'_> {
+        DepthFirst::new(self.root_path.clone(), &self.root_entry)
+    }
+
+    /// Every entry in the tree, level by level, with paths starting at
+    /// [`root_path`](FileTree::root_path).
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst::new(self.root_path.clone(), &self.root_entry)
+    }
+
+    /// Passes every entry in the tree to `visitor`, with paths starting at
+    /// [`root_path`](FileTree::root_path).
+    pub fn visit(&self, visitor: &mut impl Visitor) {
+        visit::visit(&self.root_entry, self.root_path.clone(), visitor);
+    }
+
+    /// Passes every entry in the tree to `visitor` to change, see
+    /// [`VisitorMut`].
+    pub fn visit_mut(&mut self, visitor: &mut impl VisitorMut) {
+        visit::visit_mut(&mut self.root_entry, self.root_path.clone(), visitor);
+    }
+
+    /// Draws the tree, see [`Entry::render`].
+    pub fn render<'a>(&'a self, options: &'a RenderOptions) -> Render<'a> {
+        self.root_entry.render(options)
+    }
+
+    /// Writes the tree as a single line of JSON. Names and paths that are not
+    /// valid UTF-8 are written as base64 under a `_base64` key instead, so
+    /// [`from_json`](FileTree::from_json) gives back the same bytes.
+    pub fn to_json(&self) -> String {
+        json::tree_to_json(self).to_string()
+    }
+
+    /// Reads a tree written by [`to_json`](FileTree::to_json).
+    pub fn from_json(text: &str) -> std::result::Result<FileTree, JsonError> {
+        json::tree_from_json(&Value::parse(text)?)
+    }
+
+    /// Compares this tree with a later scan of the same, or a different,
+    /// directory.
+    pub fn diff(&self, new: &FileTree) -> TreeDiff {
+        self.diff_with(new, &DiffOptions::default())
+    }
+
+    /// Compares this tree with another, see [`DiffOptions`].
+    pub fn diff_with(&self, new: &FileTree, options: &DiffOptions) -> TreeDiff {
+        TreeDiff::new(self, new, options)
+    }
+
+    /// Drops the entries `filter` would have left out of the scan. Directory
+    /// sizes then count only what is left.
+    pub fn filter(&mut self, filter: &Filter) {
+        if !filter.is_empty() {
+            self.root_entry.filter(filter, Path::new(""));
+        }
+    }
+
+    /// Reorders every directory in the tree.
+    pub fn sort(&mut self, order: &SortOrder) {
+        self.root_entry.sort(order);
+    }
+}
+
+/// Configures and runs a scan, producing a [`FileTree`].
+///
+/// ```no_run
+/// let tree = file_tree::FileTree::builder("/tmp")
+///     .max_depth(2)
+///     .one_file_system(true)
+///     .build()?;
+/// println!("{} bytes", tree.root().size());
+/// # Ok::<(), file_tree::Error>(())
+/// ```
+pub struct TreeBuilder {
+    root_path: PathBuf,
+    options: WalkOptions,
+}
+
+impl TreeBuilder {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        TreeBuilder {
+            root_path: path.as_ref().to_path_buf(),
+            options: WalkOptions::default(),
+        }
+    }
+
+    /// Replaces all walk options at once.
+    pub fn options(mut self, options: WalkOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See [`WalkOptions::max_depth`].
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.options.max_depth = Some(depth);
+        self
+    }
+
+    /// See [`WalkOptions::show_hidden`].
+    pub fn show_hidden(mut self, yes: bool) -> Self {
+        self.options.show_hidden = yes;
+        self
+    }
+
+    /// See [`WalkOptions::one_file_system`].
+    pub fn one_file_system(mut self, yes: bool) -> Self {
+        self.options.one_file_system = yes;
+        self
+    }
+
+    /// See [`WalkOptions::min_entries`].
+    pub fn min_entries(mut self, count: usize) -> Self {
+        self.options.min_entries = Some(count);
+        self
+    }
+
+    /// See [`WalkOptions::max_entries`].
+    pub fn max_entries(mut self, count: usize) -> Self {
+        self.options.max_entries = Some(count);
+        self
+    }
+
+    /// See [`WalkOptions::sort`].
+    pub fn sort(mut self, order: SortOrder) -> Self {
+        self.options.sort = Some(order);
+        self
+    }
+
+    /// See [`WalkOptions::filter`].
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.options.filter = filter;
+        self
+    }
+
+    /// See [`WalkOptions::count_filtered`].
+    pub fn count_filtered(mut self, yes: bool) -> Self {
+        self.options.count_filtered = yes;
+        self
+    }
+
+    /// See [`WalkOptions::git_ignore`].
+    pub fn git_ignore(mut self, yes: bool) -> Self {
+        self.options.git_ignore = yes;
+        self
+    }
+
+    /// See [`WalkOptions::show_ignored`].
+    pub fn show_ignored(mut self, yes: bool) -> Self {
+        self.options.show_ignored = yes;
+        self
+    }
+
+    /// See [`WalkOptions::keep_going`].
+    pub fn keep_going(mut self, yes: bool) -> Self {
+        self.options.keep_going = yes;
+        self
+    }
+
+    /// See [`WalkOptions::hard_links`].
+    pub fn hard_links(mut self, policy: HardLinks) -> Self {
+        self.options.hard_links = policy;
+        self
+    }
+
+    /// See [`WalkOptions::follow_symlinks`].
+    pub fn follow_symlinks(mut self, yes: bool) -> Self {
+        self.options.follow_symlinks = yes;
+        self
+    }
+
+    /// See [`WalkOptions::metadata`].
+    pub fn metadata(mut self, yes: bool) -> Self {
+        self.options.metadata = yes;
+        self
+    }
+
+    /// See [`WalkOptions::hash_contents`].
+    pub fn hash_contents(mut self, yes: bool) -> Self {
+        self.options.hash_contents = yes;
+        self
+    }
+
+    /// See [`WalkOptions::threads`].
+    pub fn threads(mut self, count: usize) -> Self {
+        self.options.threads = count;
+        self
+    }
+
+    /// See [`WalkOptions::backend`].
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.options.backend = backend;
+        self
+    }
+
+    /// See [`WalkOptions::max_open_dirs`].
+    pub fn max_open_dirs(mut self, count: usize) -> Self {
+        self.options.max_open_dirs = Some(count);
+        self
+    }
+
+    /// Scans the directory according to the configured options.
+    pub fn build(self) -> Result<FileTree> {
+        let path = self.root_path;
+
+        let root_entry = Walker::new(&self.options, &path)?.root()?;
+
+        Ok(FileTree::new(path, root_entry))
+    }
+
+    /// Scans the directory like [`build`](TreeBuilder::build), but passes
+    /// each entry to `on_event` as soon as it has been read instead of
+    /// keeping it, so memory use does not grow with the size of the tree.
+    /// Returns the root entry, which has the total sizes but no children.
+    ///
+    /// Entries are reported in the order they are visited, without applying
+    /// [`WalkOptions::sort`]. Directories are reported as they are entered,
+    /// so with include patterns they appear even if nothing in them matches.
+    pub fn stream<F>(self, mut on_event: F) -> Result<Entry>
+    where
+        F: FnMut(Event) -> io::Result<()>,
+    {
+        let path = self.root_path;
+        Walker::new(&self.options, &path)?
+            .stream(&mut on_event)
+            .root()
+    }
+}
+
+/// Scans the directory at `path` with the default options.
+pub fn get_file_tree(path: impl AsRef<Path>) -> Result<FileTree> {
+    TreeBuilder::new(path).build()
+}
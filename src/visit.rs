@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::{mem, slice, vec};
+
+use crate::entry::{Entry, EntryData};
+
+/// What to do once a [`Visitor`] or [`VisitorMut`] has seen an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    /// Leave out everything in the directory just entered. The directory is
+    /// still left. From any other callback this is the same as `Continue`.
+    SkipSubtree,
+    /// Visit nothing more, not even to leave the directories entered.
+    Stop,
+}
+
+/// Receives the entries of a tree depth first, in the order they are stored,
+/// see [`Entry::visit`]. Each callback is given the entry's path and how
+/// many levels below the start of the visit it is.
+pub trait Visitor {
+    /// Called for a directory before anything in it.
+    fn enter(&mut self, _path: &Path, _depth: usize, _entry: &Entry) -> Visit {
+        Visit::Continue
+    }
+
+    /// Called for a directory after everything in it.
+    fn leave(&mut self, _path: &Path, _depth: usize, _entry: &Entry) -> Visit {
+        Visit::Continue
+    }
+
+    /// Called for every entry that is not a directory.
+    fn leaf(&mut self, _path: &Path, _depth: usize, _entry: &Entry) -> Visit {
+        Visit::Continue
+    }
+}
+
+/// Like [`Visitor`], but able to change the entries it is given, see
+/// [`Entry::visit_mut`]. Changes made to a directory in `enter`, such as
+/// removing children with [`Entry::children_mut`], decide what is visited
+/// inside it. Directory sizes are left as they were.
+pub trait VisitorMut {
+    fn enter(&mut self, _path: &Path, _depth: usize, _entry: &mut Entry) -> Visit {
+        Visit::Continue
+    }
+
+    fn leave(&mut self, _path: &Path, _depth: usize, _entry: &mut Entry) -> Visit {
+        Visit::Continue
+    }
+
+    fn leaf(&mut self, _path: &Path, _depth: usize, _entry: &mut Entry) -> Visit {
+        Visit::Continue
+    }
+}
+
+/// The entries of a tree with their paths and depths, each directory
+/// followed by everything in it, see [`Entry::depth_first`].
+///
+/// There is no mutable counterpart, since a directory and the entries in it
+/// cannot be handed out mutably at the same time; use [`VisitorMut`].
+pub struct DepthFirst<'a> {
+    /// Entries still to be yielded, the next one last.
+    stack: Vec<(PathBuf, usize, &'a Entry)>,
+    /// The children of the directory yielded last, added to `stack` only
+    /// once the next entry is asked for so they can be skipped.
+    expand: Option<(PathBuf, usize, &'a [Entry])>,
+}
+
+/// The entries of a tree with their paths and depths, level by level, see
+/// [`Entry::breadth_first`].
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<(PathBuf, usize, &'a Entry)>,
+    expand: Option<(PathBuf, usize, &'a [Entry])>,
+}
+
+impl<'a> DepthFirst<'a> {
+    pub(crate) fn new(path: PathBuf, entry: &'a Entry) -> Self {
+        DepthFirst {
+            stack: vec![(path, 0, entry)],
+            expand: None,
+        }
+    }
+
+    /// Leaves out everything in the directory yielded last.
+    pub fn skip_subtree(&mut self) {
+        self.expand = None;
+    }
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = (PathBuf, usize, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, depth, children)) = self.expand.take() {
+            let children = children.iter().rev();
+            self.stack
+                .extend(children.map(|child| (path.join(&child.name), depth + 1, child)));
+        }
+        let (path, depth, entry) = self.stack.pop()?;
+        if let Some(children) = entry.children() {
+            self.expand = Some((path.clone(), depth, children));
+        }
+        Some((path, depth, entry))
+    }
+}
+
+impl<'a> BreadthFirst<'a> {
+    pub(crate) fn new(path: PathBuf, entry: &'a Entry) -> Self {
+        BreadthFirst {
+            queue: VecDeque::from([(path, 0, entry)]),
+            expand: None,
+        }
+    }
+
+    /// Leaves out everything in the directory yielded last.
+    pub fn skip_subtree(&mut self) {
+        self.expand = None;
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = (PathBuf, usize, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, depth, children)) = self.expand.take() {
+            self.queue.extend(
+                children
+                    .iter()
+                    .map(|child| (path.join(&child.name), depth + 1, child)),
+            );
+        }
+        let (path, depth, entry) = self.queue.pop_front()?;
+        if let Some(children) = entry.children() {
+            self.expand = Some((path.clone(), depth, children));
+        }
+        Some((path, depth, entry))
+    }
+}
+
+/// Passes `entry`, which is at `path`, and everything below it to `visitor`.
+/// Directories being visited are kept on a stack rather than the call stack,
+/// so that a tree of any depth can be visited.
+pub(crate) fn visit(entry: &Entry, mut path: PathBuf, visitor: &mut impl Visitor) {
+    // Each directory entered, with the length of `path` without its name and
+    // the entries in it still to visit.
+    let mut stack: Vec<(usize, &Entry, slice::Iter<Entry>)> = vec![];
+    let mut next = Some((path.as_os_str().len(), entry));
+    loop {
+        if let Some((len, entry)) = next.take() {
+            let depth = stack.len();
+            let visit = match entry.children() {
+                Some(children) => {
+                    let visit = visitor.enter(&path, depth, entry);
+                    let children = if visit == Visit::Continue {
+                        children
+                    } else {
+                        &[]
+                    };
+                    stack.push((len, entry, children.iter()));
+                    visit
+                }
+                None => {
+                    let visit = visitor.leaf(&path, depth, entry);
+                    truncate(&mut path, len);
+                    visit
+                }
+            };
+            if visit == Visit::Stop {
+                return;
+            }
+        }
+
+        let (_, _, children) = match stack.last_mut() {
+            Some(top) => top,
+            None => return,
+        };
+        match children.next() {
+            Some(child) => next = Some((push(&mut path, &child.name), child)),
+            None => {
+                let (len, entry, _) = stack.pop().unwrap();
+                if visitor.leave(&path, stack.len(), entry) == Visit::Stop {
+                    return;
+                }
+                truncate(&mut path, len);
+            }
+        }
+    }
+}
+
+/// An entry being visited mutably: the one the visit began at, or one
+/// taken out of its directory until the directory is left.
+enum Held<'a> {
+    Borrowed(&'a mut Entry),
+    Owned(Entry),
+}
+
+/// A directory entered by a mutable visit.
+struct Entered<'a> {
+    /// The length of the path without the directory's name.
+    len: usize,
+    dir: Held<'a>,
+    /// The entries in it still to visit, unless it is skipped.
+    pending: Option<vec::IntoIter<Entry>>,
+    visited: Vec<Entry>,
+}
+
+/// Passes `entry`, which is at `path`, and everything below it to `visitor`
+/// to change. Each directory's entries are taken out of it while they are
+/// visited, and put back before it is left.
+pub(crate) fn visit_mut(entry: &mut Entry, mut path: PathBuf, visitor: &mut impl VisitorMut) {
+    let mut stack: Vec<Entered> = vec![];
+    let mut next = Some((path.as_os_str().len(), Held::Borrowed(entry)));
+    loop {
+        if let Some((len, mut held)) = next.take() {
+            let depth = stack.len();
+            let entry = held.get();
+            let visit = if entry.is_dir() {
+                let visit = visitor.enter(&path, depth, entry);
+                let pending = match &mut entry.data {
+                    EntryData::Directory(children) if visit == Visit::Continue => {
+                        Some(mem::take(children).into_iter())
+                    }
+                    _ => None,
+                };
+                stack.push(Entered {
+                    len,
+                    dir: held,
+                    pending,
+                    visited: vec![],
+                });
+                visit
+            } else {
+                let visit = visitor.leaf(&path, depth, entry);
+                held.put_back(&mut stack);
+                truncate(&mut path, len);
+                visit
+            };
+            if visit == Visit::Stop {
+                return unwind(stack);
+            }
+        }
+
+        let top = match stack.last_mut() {
+            Some(top) => top,
+            None => return,
+        };
+        match top.pending.as_mut().and_then(Iterator::next) {
+            Some(child) => next = Some((push(&mut path, &child.name), Held::Owned(child))),
+            None => {
+                let mut entered = stack.pop().unwrap();
+                entered.restore();
+                let visit = visitor.leave(&path, stack.len(), entered.dir.get());
+                entered.dir.put_back(&mut stack);
+                if visit == Visit::Stop {
+                    return unwind(stack);
+                }
+                truncate(&mut path, entered.len);
+            }
+        }
+    }
+}
+
+/// Adds `name` to the end of `path`, returning how long it was before.
+pub(crate) fn push(path: &mut PathBuf, name: &OsStr) -> usize {
+    let len = path.as_os_str().len();
+    path.push(name);
+    len
+}
+
+/// Cuts `path` back to its first `len` bytes, undoing [`push`]. Popping a
+/// component would not, for names that are empty.
+pub(crate) fn truncate(path: &mut PathBuf, len: usize) {
+    let mut bytes = mem::take(path).into_os_string().into_vec();
+    bytes.truncate(len);
+    *path = PathBuf::from(OsString::from_vec(bytes));
+}
+
+/// Puts every directory of a mutable visit that was stopped back together.
+fn unwind(mut stack: Vec<Entered>) {
+    while let Some(mut entered) = stack.pop() {
+        entered.restore();
+        entered.dir.put_back(&mut stack);
+    }
+}
+
+impl Held<'_> {
+    fn get(&mut self) -> &mut Entry {
+        match self {
+            Held::Borrowed(entry) => entry,
+            Held::Owned(entry) => entry,
+        }
+    }
+
+    /// Returns a visited entry to the directory it was taken out of.
+    fn put_back(self, stack: &mut [Entered]) {
+        if let (Held::Owned(entry), Some(parent)) = (self, stack.last_mut()) {
+            parent.visited.push(entry);
+        }
+    }
+}
+
+impl Entered<'_> {
+    /// Puts the entries taken out of the directory back, followed by any
+    /// that were not visited.
+    fn restore(&mut self) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut children = mem::take(&mut self.visited);
+        children.extend(pending);
+        if let EntryData::Directory(slot) = &mut self.dir.get().data {
+            *slot = children;
+        }
+    }
+}
@@ -0,0 +1,1268 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::iter::Peekable;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io, mem, vec};
+
+use crate::dir::{Backend, Dir, DirEntry, Stat};
+use crate::entry::{Device, Entry, EntryData, EntryKind};
+use crate::error::{Context, Error, Operation, Result};
+use crate::filter::Filter;
+use crate::hash;
+use crate::ignore::IgnoreStack;
+use crate::link::{self, Link, LinkStatus};
+use crate::metadata::Metadata;
+use crate::pool::{Pool, Task, Worker};
+use crate::sort::SortOrder;
+
+/// Controls which entries a scan visits.
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    /// How many levels below the root to list. Directories at the limit are
+    /// recorded but not read. `None` means no limit.
+    pub max_depth: Option<usize>,
+    /// Whether to include entries whose name starts with a dot.
+    pub show_hidden: bool,
+    /// Whether to skip descending into directories on a different device
+    /// than the root.
+    pub one_file_system: bool,
+    /// Directories below the root with fewer entries than this are recorded
+    /// but not read.
+    pub min_entries: Option<usize>,
+    /// Directories below the root with more entries than this are recorded
+    /// but not read.
+    pub max_entries: Option<usize>,
+    /// How to order the entries of each directory. `None` keeps the order
+    /// they are visited in, which is byte-wise by name.
+    pub sort: Option<SortOrder>,
+    /// Which entries to keep.
+    pub filter: Filter,
+    /// Whether directory sizes include entries dropped by the filter, giving
+    /// their full size on disk rather than the size of what was kept.
+    pub count_filtered: bool,
+    /// Whether to leave out entries matched by `.gitignore` and `.ignore`
+    /// files, `.git/info/exclude` and the file named by `core.excludesFile`.
+    /// An ignore file that cannot be read is skipped, and its error recorded
+    /// as an [`EntryData::Error`] in the directory it is in, or in the root
+    /// for those outside the scan.
+    pub git_ignore: bool,
+    /// Whether to keep ignored entries, marked as such, instead of leaving
+    /// them out. Has no effect unless `git_ignore` is set.
+    pub show_ignored: bool,
+    /// Whether to record entries that could not be read as
+    /// [`EntryData::Error`] and carry on, instead of failing the whole scan.
+    pub keep_going: bool,
+    /// How to count files with more than one hard link.
+    pub hard_links: HardLinks,
+    /// Whether to record what symlinks point to instead of the links
+    /// themselves, descending into linked directories. A link to a directory
+    /// that is already being walked is recorded as [`EntryData::Loop`].
+    pub follow_symlinks: bool,
+    /// Whether to record each entry's [`Metadata`].
+    pub metadata: bool,
+    /// Whether to read every regular file and record a hash of its contents,
+    /// which lets diffs recognise moved files without the files at hand.
+    pub hash_contents: bool,
+    /// How many threads to read directories with, each taking whole
+    /// subdirectories. The tree is the same as a scan on one thread gives.
+    /// Values below 2 scan on the calling thread alone, as do streaming
+    /// scans.
+    pub threads: usize,
+    /// How to read directories and the entries in them.
+    pub backend: Backend,
+    /// How many directories each thread of a scan keeps open at once with
+    /// [`Backend::Openat`] or [`Backend::Getdents`]. Directories closed to
+    /// stay within this are opened again, relative to the nearest one still
+    /// open, once their subdirectories have been read. `None` means 64.
+    pub max_open_dirs: Option<usize>,
+}
+
+/// How sizes count a file that is reachable through several hard links.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HardLinks {
+    /// Count the file only at the first link the scan reaches, like `du`.
+    /// Later links have a size of zero.
+    #[default]
+    FirstSeen,
+    /// Give each link an equal share of the file's size, so totals add up
+    /// to the file's size once all of its links have been seen.
+    Split,
+    /// Count the full size at every link.
+    CountAll,
+}
+
+/// Something a streaming scan reports, see
+/// [`TreeBuilder::stream`](crate::TreeBuilder::stream).
+#[derive(Clone, Copy, Debug)]
+pub enum Event<'a> {
+    /// A directory is about to be read. Everything reported until the
+    /// matching `ExitDir` is inside it.
+    EnterDir { path: &'a Path, depth: usize },
+    /// An entry that is not a directory that was read.
+    Entry {
+        path: &'a Path,
+        depth: usize,
+        entry: &'a Entry,
+    },
+    /// A directory has been read. Its entry has the total sizes of what it
+    /// contains but no children. It has kind [`EntryData::Error`] if reading
+    /// it failed and the scan keeps going.
+    ExitDir {
+        path: &'a Path,
+        depth: usize,
+        entry: &'a Entry,
+    },
+}
+
+/// Receives the events of a streaming scan. An error stops the scan.
+pub(crate) type EventSink<'a> = dyn FnMut(Event<'_>) -> io::Result<()> + 'a;
+
+pub(crate) struct Walker<'a> {
+    options: &'a WalkOptions,
+    root_path: &'a Path,
+    root_dev: u64,
+    canonical_root: Option<PathBuf>,
+    ignores: Option<IgnoreStack>,
+    in_ignored: bool,
+    seen_links: HashSet<(u64, u64)>,
+    ancestors: HashSet<(u64, u64)>,
+    events: Option<&'a mut EventSink<'a>>,
+    /// Set while building entries that are only counted, not reported.
+    silent: bool,
+    /// Whether the last entry built was a directory that was read, and so
+    /// has been reported already.
+    walked: bool,
+    /// Set when scanning on a pool, to hand subdirectories to other threads.
+    parallel: Option<(&'a Arc<Shared>, &'a Worker<'a>)>,
+}
+
+/// What the threads of a parallel scan share.
+struct Shared {
+    options: WalkOptions,
+    root_path: PathBuf,
+    root_dev: u64,
+    canonical_root: Option<PathBuf>,
+    /// Set when hard links are counted once the walk is done, in the order
+    /// a scan on one thread would have reached them.
+    deferred: Option<Mutex<Deferred>>,
+}
+
+/// What is needed to count hard links after a parallel walk.
+#[derive(Default)]
+struct Deferred {
+    /// Entries built but not kept, by the path of their directory.
+    dropped: HashMap<PathBuf, Vec<Entry>>,
+    /// The device and inode of each entry with more than one link, by path.
+    links: HashMap<PathBuf, (u64, u64)>,
+}
+
+/// An entry of a directory being read, built here or on another thread.
+enum Pending {
+    Here(DirEntry),
+    Forked(OsString, Task<Result<Option<(Entry, bool)>>>),
+}
+
+/// How deeply tasks may run inside each other on one thread before a
+/// parallel scan stops forking and reads subdirectories where it is.
+const MAX_NESTED_TASKS: usize = 16;
+
+/// How many directories each walk keeps open unless told otherwise.
+const DEFAULT_MAX_OPEN_DIRS: usize = 64;
+
+/// An entry being built: finished, or a directory still to be read.
+enum Step {
+    Done(Entry),
+    Read(Box<Frame>),
+}
+
+/// A directory being read, with what its entry is built from once
+/// everything in it has been.
+struct Frame {
+    name: OsString,
+    path: PathBuf,
+    relative_dir: PathBuf,
+    depth: usize,
+    stat: Stat,
+    /// The symlink the directory was reached through, when following them.
+    followed: Option<Link>,
+    /// What has been read of the directory, or why reading it failed.
+    listing: Result<Listing>,
+    /// How the directory was started as an entry of its parent. `None` for
+    /// the directory the walk began at.
+    child: Option<Started>,
+}
+
+/// The entries of a directory being read.
+struct Listing {
+    /// The directory, while entries are still to be reached through it.
+    dir: Dir,
+    pending: vec::IntoIter<Pending>,
+    /// How many of `pending` are to be built here.
+    here: usize,
+    /// How many ignore files reading the directory added.
+    ignore_files: usize,
+    entries: Vec<Entry>,
+    /// Entries built but not kept, when hard links are counted afterwards.
+    dropped: Vec<Entry>,
+    totals: Totals,
+}
+
+/// What an entry of a directory was started with, to finish it with.
+struct Started {
+    name: OsString,
+    relative_path: PathBuf,
+    ignored: bool,
+    excluded: bool,
+    /// The walker's `in_ignored` and `silent` from before the entry.
+    in_ignored: bool,
+    silent: bool,
+}
+
+/// Keeps the directories a walk holds open within
+/// [`WalkOptions::max_open_dirs`].
+struct OpenDirs {
+    count: usize,
+    max: usize,
+    /// Every frame between the bottom of the stack and this one is closed.
+    lowest: usize,
+}
+
+/// A directory whose hard links are being settled after a parallel walk.
+struct Settling {
+    entry: Entry,
+    path: PathBuf,
+    own_disk_usage: u64,
+    was_dropped: bool,
+    kept: Peekable<vec::IntoIter<Entry>>,
+    dropped: Peekable<vec::IntoIter<Entry>>,
+    settled_kept: Vec<Entry>,
+    settled_dropped: Vec<Entry>,
+}
+
+impl<'a> Walker<'a> {
+    pub(crate) fn new(options: &'a WalkOptions, root_path: &'a Path) -> Result<Self> {
+        let root_dev = fs::metadata(root_path)
+            .context(Operation::Stat, root_path, 0)?
+            .dev();
+        let ignores = if options.git_ignore {
+            Some(IgnoreStack::new(root_path)?)
+        } else {
+            None
+        };
+        Ok(Walker {
+            options,
+            root_path,
+            root_dev,
+            canonical_root: fs::canonicalize(root_path).ok(),
+            ignores,
+            in_ignored: false,
+            seen_links: HashSet::new(),
+            ancestors: HashSet::new(),
+            events: None,
+            silent: false,
+            walked: false,
+            parallel: None,
+        })
+    }
+
+    /// A walker for one of a parallel scan's tasks, continuing with the
+    /// state the walk had where the task was forked.
+    fn forked(
+        shared: &'a Arc<Shared>,
+        worker: &'a Worker<'a>,
+        ignores: Option<IgnoreStack>,
+        ancestors: HashSet<(u64, u64)>,
+        in_ignored: bool,
+    ) -> Self {
+        Walker {
+            options: &shared.options,
+            root_path: &shared.root_path,
+            root_dev: shared.root_dev,
+            canonical_root: shared.canonical_root.clone(),
+            ignores,
+            in_ignored,
+            seen_links: HashSet::new(),
+            ancestors,
+            events: None,
+            silent: false,
+            walked: false,
+            parallel: Some((shared, worker)),
+        }
+    }
+
+    fn deferred(&self) -> Option<&'a Mutex<Deferred>> {
+        self.parallel
+            .and_then(|(shared, _)| shared.deferred.as_ref())
+    }
+
+    /// Reports entries to `events` as they are read, instead of collecting
+    /// them into their directories.
+    pub(crate) fn stream(mut self, events: &'a mut EventSink<'a>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn emit(&mut self, event: Event) -> Result<()> {
+        match &mut self.events {
+            Some(events) if !self.silent => {
+                let (path, depth) = match event {
+                    Event::EnterDir { path, depth }
+                    | Event::Entry { path, depth, .. }
+                    | Event::ExitDir { path, depth, .. } => (path, depth),
+                };
+                events(event).context(Operation::WriteEvent, path, depth)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a kept entry to its directory's entries, or reports it when
+    /// streaming.
+    fn keep(
+        &mut self,
+        entries: &mut Vec<Entry>,
+        entry: Entry,
+        path: &Path,
+        depth: usize,
+    ) -> Result<()> {
+        if self.events.is_none() {
+            entries.push(entry);
+        } else if !mem::take(&mut self.walked) {
+            self.emit(Event::Entry {
+                path,
+                depth,
+                entry: &entry,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Builds the entry for the scanned root itself.
+    pub(crate) fn root(&mut self) -> Result<Entry> {
+        if self.options.threads > 1 && self.events.is_none() && self.parallel.is_none() {
+            return self.root_parallel();
+        }
+        let path = self.root_path;
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        let metadata = fs::metadata(path).context(Operation::Stat, path, 0)?;
+        let stat = Stat::from_metadata(&metadata);
+        let step = self.start_entry(None, name, path.to_path_buf(), stat, None, 0)?;
+        self.finish(step)
+    }
+
+    /// Builds the root entry on a pool of `options.threads` threads.
+    fn root_parallel(&mut self) -> Result<Entry> {
+        let options = self.options;
+        let shared = Arc::new(Shared {
+            options: options.clone(),
+            root_path: self.root_path.to_path_buf(),
+            root_dev: self.root_dev,
+            canonical_root: self.canonical_root.clone(),
+            deferred: if options.hard_links == HardLinks::CountAll {
+                None
+            } else {
+                Some(Mutex::default())
+            },
+        });
+        let ignores = self.ignores.clone();
+        let mut entry = Pool::run(options.threads, |worker| {
+            Walker::forked(&shared, worker, ignores, HashSet::new(), false).root()
+        })?;
+
+        if let Some(deferred) = &shared.deferred {
+            let mut deferred = mem::take(&mut *deferred.lock().unwrap());
+            entry = self.settle(entry, self.root_path.to_path_buf(), &mut deferred);
+            if let Some(order) = &options.sort {
+                entry.sort(order);
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Works out the share of each hard link below `entry`, which is at
+    /// `path`, visiting entries in the same order as a scan on one thread,
+    /// and adds directory sizes up again to match.
+    fn settle(&mut self, entry: Entry, path: PathBuf, deferred: &mut Deferred) -> Entry {
+        let mut stack: Vec<Settling> = vec![];
+        let mut next = Some((entry, path, false));
+        loop {
+            if let Some((mut entry, path, was_dropped)) = next.take() {
+                if let Some(id) = deferred.links.remove(&path) {
+                    let share = self.hard_link_share(id, entry.links, Totals::of(&entry));
+                    entry.size = share.size;
+                    entry.disk_usage = share.disk_usage;
+                }
+                if let EntryData::Directory(children) = &mut entry.data {
+                    let kept = mem::take(children);
+                    let dropped = deferred.dropped.remove(&path).unwrap_or_default();
+                    let own_disk_usage = entry
+                        .disk_usage
+                        .saturating_sub(self.counted(&kept, &dropped).disk_usage);
+                    stack.push(Settling {
+                        entry,
+                        path,
+                        own_disk_usage,
+                        was_dropped,
+                        kept: kept.into_iter().peekable(),
+                        dropped: dropped.into_iter().peekable(),
+                        settled_kept: vec![],
+                        settled_dropped: vec![],
+                    });
+                } else {
+                    match stack.last_mut() {
+                        Some(parent) => parent.settled(entry, was_dropped),
+                        None => return entry,
+                    }
+                }
+            }
+
+            // Kept and dropped entries are each in name order, so merging
+            // them gives the order they were visited in.
+            let top = stack.last_mut().unwrap();
+            let from_dropped = match (top.kept.peek(), top.dropped.peek()) {
+                (Some(kept), Some(dropped)) => dropped.name < kept.name,
+                (None, dropped) => dropped.is_some(),
+                (Some(_), None) => false,
+            };
+            let child = if from_dropped {
+                top.dropped.next()
+            } else {
+                top.kept.next()
+            };
+            if let Some(child) = child {
+                let child_path = top.path.join(&child.name);
+                next = Some((child, child_path, from_dropped));
+                continue;
+            }
+
+            let settling = stack.pop().unwrap();
+            let totals = self.counted(&settling.settled_kept, &settling.settled_dropped);
+            let mut entry = settling.entry;
+            entry.size = totals.size;
+            entry.disk_usage = settling.own_disk_usage + totals.disk_usage;
+            entry.data = EntryData::Directory(settling.settled_kept);
+            match stack.last_mut() {
+                Some(parent) => parent.settled(entry, settling.was_dropped),
+                None => return entry,
+            }
+        }
+    }
+
+    /// The sizes a directory with these kept and dropped entries adds up.
+    fn counted(&self, kept: &[Entry], dropped: &[Entry]) -> Totals {
+        let mut totals = Totals::default();
+        for entry in kept
+            .iter()
+            .chain(dropped.iter().filter(|_| self.options.count_filtered))
+        {
+            totals.add(Totals::of(entry));
+        }
+        totals
+    }
+
+    /// Finishes building the entry `step` was started for.
+    fn finish(&mut self, step: Step) -> Result<Entry> {
+        match step {
+            Step::Done(entry) => Ok(entry),
+            Step::Read(frame) => self.walk(*frame),
+        }
+    }
+
+    /// Reads the directory of `frame` and everything below it. Directories
+    /// being read are kept on a stack rather than the call stack, so that a
+    /// tree of any depth can be walked.
+    fn walk(&mut self, frame: Frame) -> Result<Entry> {
+        let mut stack = vec![frame];
+        let mut open_dirs = OpenDirs::new(
+            self.options.max_open_dirs.unwrap_or(DEFAULT_MAX_OPEN_DIRS),
+            &stack,
+        );
+        loop {
+            let top = stack.last_mut().unwrap();
+            let pending = match &mut top.listing {
+                Ok(listing) => listing.pending.next(),
+                Err(_) => None,
+            };
+            let (name, result) = match pending {
+                Some(Pending::Here(dir_entry)) => {
+                    let dir = match open_dirs.take(&mut stack, self.options.follow_symlinks) {
+                        Ok(dir) => dir,
+                        Err(error) => {
+                            self.give_up(stack.last_mut().unwrap(), error);
+                            continue;
+                        }
+                    };
+                    let top = stack.last().unwrap();
+                    let name = dir_entry.name.clone();
+                    let path = top.path.join(&name);
+                    let relative_path = top.relative_dir.join(&name);
+                    let depth = top.depth + 1;
+                    match self.start_child(&dir, dir_entry, path, &relative_path, depth) {
+                        Ok(None) => continue,
+                        Ok(Some((Step::Read(mut frame), started))) => {
+                            frame.child = Some(started);
+                            stack.push(*frame);
+                            open_dirs.pushed(&mut stack);
+                            continue;
+                        }
+                        Ok(Some((Step::Done(entry), started))) => {
+                            (name, self.finish_child(started, Ok(entry)))
+                        }
+                        Err(error) => (name, Err(error)),
+                    }
+                }
+                Some(Pending::Forked(name, task)) => (name, self.parallel.unwrap().1.wait(task)),
+                None => {
+                    let frame = stack.pop().unwrap();
+                    open_dirs.popped(&frame, stack.len());
+                    match self.finish_dir(frame) {
+                        (Some(started), result) => {
+                            (started.name.clone(), self.finish_child(started, result))
+                        }
+                        (None, result) => return result,
+                    }
+                }
+            };
+
+            let top = stack.last_mut().unwrap();
+            if let Err(error) = self.add_child(top, name, result) {
+                self.give_up(top, error);
+            }
+        }
+    }
+
+    /// Stops reading the directory of `frame`, recording `error` as why;
+    /// what is left of it is not read.
+    fn give_up(&mut self, frame: &mut Frame, error: Error) {
+        if let Ok(listing) = mem::replace(&mut frame.listing, Err(error)) {
+            self.leave(listing.ignore_files);
+        }
+    }
+
+    /// Opens directory `name` of `parent`, or the root if there is no
+    /// parent, and lists the entries to read. The directory is at `path`,
+    /// which is `relative_dir` below the root, sits `depth` levels below it
+    /// and has device and inode `id`.
+    fn open_dir(
+        &mut self,
+        parent: Option<&Dir>,
+        name: &OsStr,
+        path: &Path,
+        relative_dir: &Path,
+        depth: usize,
+        id: (u64, u64),
+    ) -> Result<Listing> {
+        let mut listing = Listing {
+            dir: Dir::Closed,
+            pending: vec![].into_iter(),
+            here: 0,
+            ignore_files: 0,
+            entries: vec![],
+            dropped: vec![],
+            totals: Totals::default(),
+        };
+        if self.options.max_depth.is_some_and(|max| depth >= max) {
+            return Ok(listing);
+        }
+
+        let dir = match parent {
+            Some(parent) => parent.open(name, self.options.follow_symlinks, Some(id)),
+            None => Dir::open_root(self.options.backend, self.options.metadata, path, Some(id)),
+        }
+        .context(Operation::ReadDir, path, depth)?;
+        let mut dir_entries = vec![];
+        for dir_entry in dir.list(path).context(Operation::ReadDir, path, depth)? {
+            match dir_entry.context(Operation::ReadDir, path, depth) {
+                Ok(dir_entry) => {
+                    if self.options.show_hidden || !is_hidden(&dir_entry) {
+                        dir_entries.push(dir_entry);
+                    }
+                }
+                Err(error) if self.options.keep_going => {
+                    self.walked = false;
+                    self.keep(
+                        &mut listing.entries,
+                        Entry::error(OsString::new(), error),
+                        path,
+                        depth + 1,
+                    )?;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        // Visit entries in name order so that anything depending on the order
+        // of the walk, such as which hard link is seen first, is repeatable.
+        dir_entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let count = dir_entries.len();
+        let outside_limits = self.options.min_entries.is_some_and(|min| count < min)
+            || self.options.max_entries.is_some_and(|max| count > max);
+        if depth > 0 && outside_limits {
+            return Ok(listing);
+        }
+
+        if let Some(ignores) = &mut self.ignores {
+            listing.ignore_files = ignores.enter_dir(&dir, path, relative_dir, depth);
+            // Ignore files that could not be read are noted in the directory
+            // they were found for, and the scan goes on without them.
+            for error in ignores.take_unread() {
+                self.walked = false;
+                self.keep(
+                    &mut listing.entries,
+                    Entry::error(OsString::new(), error),
+                    path,
+                    depth + 1,
+                )?;
+            }
+        }
+        let pending = self.fork(&dir, dir_entries, path, relative_dir, depth);
+        listing.here = pending
+            .iter()
+            .filter(|pending| matches!(pending, Pending::Here(_)))
+            .count();
+        if listing.here > 0 {
+            listing.dir = dir;
+        }
+        listing.pending = pending.into_iter();
+        Ok(listing)
+    }
+
+    /// Hands the subdirectories among `dir_entries`, which are in `dir` at
+    /// `path`, `relative_dir` below the root, to other threads when scanning
+    /// in parallel.
+    fn fork(
+        &self,
+        dir: &Dir,
+        dir_entries: Vec<DirEntry>,
+        path: &Path,
+        relative_dir: &Path,
+        depth: usize,
+    ) -> Vec<Pending> {
+        let (shared, worker) = match self.parallel {
+            // A thread waiting on a task runs other tasks on top of its
+            // stack, so past a point a deep chain of them is walked here.
+            Some(parallel) if parallel.1.nesting() < MAX_NESTED_TASKS => parallel,
+            _ => return dir_entries.into_iter().map(Pending::Here).collect(),
+        };
+        dir_entries
+            .into_iter()
+            .map(|dir_entry| {
+                let walks = match dir_entry.kind {
+                    Some(EntryKind::Directory) => true,
+                    Some(EntryKind::Symlink) => self.options.follow_symlinks,
+                    _ => false,
+                };
+                if !walks {
+                    return Pending::Here(dir_entry);
+                }
+                let name = dir_entry.name.clone();
+                let child_path = path.join(&name);
+                let relative_path = relative_dir.join(&name);
+                let dir = dir.clone();
+                let shared = Arc::clone(shared);
+                let ignores = self.ignores.clone();
+                let ancestors = self.ancestors.clone();
+                let in_ignored = self.in_ignored;
+                let task = worker.spawn(move |worker| {
+                    Walker::forked(&shared, worker, ignores, ancestors, in_ignored).child(
+                        &dir,
+                        dir_entry,
+                        child_path,
+                        &relative_path,
+                        depth + 1,
+                    )
+                });
+                Pending::Forked(name, task)
+            })
+            .collect()
+    }
+
+    /// Adds what building entry `name` of `frame`'s directory gave to the
+    /// directory. An error is returned if the directory cannot carry on.
+    fn add_child(
+        &mut self,
+        frame: &mut Frame,
+        name: OsString,
+        result: Result<Option<(Entry, bool)>>,
+    ) -> Result<()> {
+        let listing = match &mut frame.listing {
+            Ok(listing) => listing,
+            Err(_) => return Ok(()),
+        };
+        let child_path = frame.path.join(&name);
+        let (entry, kept) = match result {
+            Ok(Some(child)) => child,
+            Ok(None) => return Ok(()),
+            Err(error) if error.operation() == Operation::WriteEvent => return Err(error),
+            Err(error) if self.options.keep_going => {
+                self.walked = false;
+                (Entry::error(name, error), true)
+            }
+            Err(error) => return Err(error),
+        };
+
+        if kept || self.options.count_filtered {
+            listing.totals.add(Totals::of(&entry));
+        }
+        if kept {
+            self.keep(&mut listing.entries, entry, &child_path, frame.depth + 1)?;
+        } else if self.deferred().is_some() {
+            listing.dropped.push(entry);
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self, ignore_files: usize) {
+        if let Some(ignores) = &mut self.ignores {
+            ignores.leave_dir(ignore_files);
+        }
+    }
+
+    /// Finishes the entry of a directory once it has been read, returning it
+    /// with what it was started with as a child of its parent, if it was.
+    fn finish_dir(&mut self, frame: Frame) -> (Option<Started>, Result<Entry>) {
+        let Frame {
+            name,
+            path,
+            depth,
+            stat,
+            followed,
+            listing,
+            child,
+            ..
+        } = frame;
+        self.ancestors.remove(&stat.id());
+        let mut totals = Totals {
+            size: 0,
+            disk_usage: stat.blocks * 512,
+        };
+        let data = match listing {
+            Ok(mut listing) => {
+                self.leave(listing.ignore_files);
+                match self.deferred() {
+                    Some(deferred) => {
+                        if !listing.dropped.is_empty() {
+                            let mut deferred = deferred.lock().unwrap();
+                            deferred.dropped.insert(path.clone(), listing.dropped);
+                        }
+                    }
+                    None => {
+                        if let Some(order) = &self.options.sort {
+                            order.sort(&mut listing.entries);
+                        }
+                    }
+                }
+                totals.add(listing.totals);
+                EntryData::Directory(listing.entries)
+            }
+            Err(error) if error.operation() == Operation::WriteEvent => return (child, Err(error)),
+            Err(error) if self.options.keep_going => EntryData::Error(error),
+            Err(error) => return (child, Err(error)),
+        };
+
+        let mut entry = self.new_entry(name, &stat, totals, None, None, data);
+        let exited = self.emit(Event::ExitDir {
+            path: &path,
+            depth,
+            entry: &entry,
+        });
+        self.walked = true;
+        if let Some(link) = followed {
+            entry.link = Some(Box::new(link));
+        }
+        (child, exited.map(|()| entry))
+    }
+
+    /// Builds the entry for `dir_entry`, which is in `dir` at `path`, unless
+    /// it is left out entirely, returning it and whether the filter keeps it.
+    /// Entries the filter drops are still built when their size counts
+    /// towards their parent's.
+    fn child(
+        &mut self,
+        dir: &Dir,
+        dir_entry: DirEntry,
+        path: PathBuf,
+        relative_path: &Path,
+        depth: usize,
+    ) -> Result<Option<(Entry, bool)>> {
+        match self.start_child(dir, dir_entry, path, relative_path, depth)? {
+            Some((step, started)) => {
+                let result = self.finish(step);
+                self.finish_child(started, result)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Starts the entry for `dir_entry` as [`child`](Walker::child) builds
+    /// it, unless it is left out entirely.
+    fn start_child(
+        &mut self,
+        dir: &Dir,
+        dir_entry: DirEntry,
+        path: PathBuf,
+        relative_path: &Path,
+        depth: usize,
+    ) -> Result<Option<(Step, Started)>> {
+        let name = dir_entry.name;
+        let ignored = self.in_ignored
+            || match &self.ignores {
+                Some(ignores) => {
+                    let kind = match dir_entry.kind {
+                        Some(kind) => kind,
+                        None => {
+                            dir.kind(&name, &path, false)
+                                .context(Operation::Stat, &path, depth)?
+                        }
+                    };
+                    ignores.is_ignored(relative_path, kind == EntryKind::Directory)
+                }
+                None => false,
+            };
+        if ignored && !self.options.show_ignored {
+            return Ok(None);
+        }
+        let filter = &self.options.filter;
+        let excluded = filter.is_excluded(&name, relative_path);
+        if excluded && !self.options.count_filtered {
+            return Ok(None);
+        }
+        // Nothing is read about an entry the filter drops when its type is
+        // known from the listing, its size does not count and no other link
+        // can have its size. Only directories, and symlinks followed to one,
+        // hold entries that could be kept.
+        if let Some(kind) = dir_entry.kind {
+            let holds_entries = kind == EntryKind::Directory
+                || kind == EntryKind::Symlink && self.options.follow_symlinks;
+            if !holds_entries
+                && !self.options.count_filtered
+                && self.options.hard_links == HardLinks::CountAll
+                && !filter.is_included(&name, relative_path)
+            {
+                return Ok(None);
+            }
+        }
+
+        let started = Started {
+            name: name.clone(),
+            relative_path: relative_path.to_path_buf(),
+            ignored,
+            excluded,
+            in_ignored: mem::replace(&mut self.in_ignored, ignored),
+            silent: self.silent,
+        };
+        self.silent |= excluded;
+        match self.start_dir_entry(dir, name, path, depth) {
+            Ok(step) => Ok(Some((step, started))),
+            Err(error) => {
+                self.in_ignored = started.in_ignored;
+                self.silent = started.silent;
+                Err(error)
+            }
+        }
+    }
+
+    /// Finishes an entry started by [`start_child`](Walker::start_child).
+    fn finish_child(
+        &mut self,
+        started: Started,
+        entry: Result<Entry>,
+    ) -> Result<Option<(Entry, bool)>> {
+        self.in_ignored = started.in_ignored;
+        self.silent = started.silent;
+        let mut entry = entry?;
+        entry.ignored = started.ignored;
+
+        let filter = &self.options.filter;
+        let kept = !started.excluded
+            && match &entry.data {
+                EntryData::Directory(children) => !filter.has_includes() || !children.is_empty(),
+                EntryData::Error(..) => true,
+                _ => filter.is_included(&started.name, &started.relative_path),
+            };
+        Ok(Some((entry, kept)))
+    }
+
+    /// Starts the entry for `name` of `dir`, which is at `path`.
+    fn start_dir_entry(
+        &mut self,
+        dir: &Dir,
+        name: OsString,
+        path: PathBuf,
+        depth: usize,
+    ) -> Result<Step> {
+        let stat = dir
+            .stat(&name, &path, false)
+            .context(Operation::Stat, &path, depth)?;
+        if self.options.follow_symlinks && stat.kind() == EntryKind::Symlink {
+            match dir.stat(&name, &path, true) {
+                Ok(target_stat) => {
+                    let link = self.link(dir, &name, &path, depth)?;
+                    return self.start_entry(Some(dir), name, path, target_stat, Some(link), depth);
+                }
+                // Dangling links are recorded as links.
+                Err(error)
+                    if error.kind() == io::ErrorKind::NotFound
+                        || error.raw_os_error() == Some(libc::ELOOP) => {}
+                Err(error) => return Err(error).context(Operation::Stat, &path, depth),
+            }
+        }
+        self.start_entry(Some(dir), name, path, stat, None, depth)
+    }
+
+    /// Builds the entry at `path`, which is `name` of `parent` or the root if
+    /// there is no parent, or starts it if it is a directory to read.
+    /// `followed` is the symlink it was reached through, if any.
+    fn start_entry(
+        &mut self,
+        parent: Option<&Dir>,
+        name: OsString,
+        path: PathBuf,
+        stat: Stat,
+        followed: Option<Link>,
+        depth: usize,
+    ) -> Result<Step> {
+        let kind = stat.kind();
+        let dir = parent.unwrap_or(&Dir::Path);
+        let mut totals = Totals {
+            size: 0,
+            disk_usage: stat.blocks * 512,
+        };
+        let mut link = None;
+        let mut content_hash = None;
+        let data = match kind {
+            EntryKind::Directory => {
+                let id = stat.id();
+                if self.options.one_file_system && stat.dev != self.root_dev {
+                    EntryData::Directory(vec![])
+                } else if !self.ancestors.insert(id) {
+                    EntryData::Loop
+                } else {
+                    if let Err(error) = self.emit(Event::EnterDir { path: &path, depth }) {
+                        self.ancestors.remove(&id);
+                        return Err(error);
+                    }
+                    let relative_dir = path.strip_prefix(self.root_path).unwrap_or(&path);
+                    let relative_dir = relative_dir.to_path_buf();
+                    let listing = self.open_dir(parent, &name, &path, &relative_dir, depth, id);
+                    return Ok(Step::Read(Box::new(Frame {
+                        name,
+                        path,
+                        relative_dir,
+                        depth,
+                        stat,
+                        followed,
+                        listing,
+                        child: None,
+                    })));
+                }
+            }
+            EntryKind::Symlink => {
+                let target = self.link(dir, &name, &path, depth)?;
+                let data = EntryData::Symlink(target.target.clone());
+                link = Some(Box::new(target));
+                data
+            }
+            EntryKind::File => {
+                totals.size = stat.size;
+                if self.options.hash_contents {
+                    let hash = dir
+                        .open_file(&name, &path, followed.is_some())
+                        .and_then(hash::hash_reader);
+                    content_hash = Some(hash.context(Operation::ReadFile, &path, depth)?);
+                }
+                EntryData::File
+            }
+            EntryKind::Fifo => EntryData::Fifo,
+            EntryKind::Socket => EntryData::Socket,
+            EntryKind::BlockDevice => EntryData::BlockDevice(Device::from_rdev(stat.rdev)),
+            EntryKind::CharDevice => EntryData::CharDevice(Device::from_rdev(stat.rdev)),
+            _ => EntryData::Unknown,
+        };
+        if kind != EntryKind::Directory && stat.nlink > 1 {
+            let id = stat.id();
+            match self.deferred() {
+                Some(deferred) => {
+                    deferred.lock().unwrap().links.insert(path.clone(), id);
+                }
+                None => totals = self.hard_link_share(id, stat.nlink, totals),
+            }
+        }
+
+        let mut entry = self.new_entry(name, &stat, totals, link, content_hash, data);
+        self.walked = false;
+        if let Some(followed) = followed {
+            entry.link = Some(Box::new(followed));
+        }
+        Ok(Step::Done(entry))
+    }
+
+    fn new_entry(
+        &self,
+        name: OsString,
+        stat: &Stat,
+        totals: Totals,
+        link: Option<Box<Link>>,
+        content_hash: Option<u64>,
+        data: EntryData,
+    ) -> Entry {
+        Entry {
+            name,
+            size: totals.size,
+            disk_usage: totals.disk_usage,
+            links: stat.nlink,
+            modified: stat.modified,
+            ignored: false,
+            link,
+            metadata: if self.options.metadata {
+                Some(Box::new(Metadata::new(stat)))
+            } else {
+                None
+            },
+            content_hash,
+            data,
+        }
+    }
+
+    /// Reads symlink `name` of `dir`, which is at `path`, and works out where
+    /// it leads.
+    fn link(&self, dir: &Dir, name: &OsStr, path: &Path, depth: usize) -> Result<Link> {
+        let target = dir
+            .read_link(name, path)
+            .context(Operation::ReadLink, path, depth)?;
+        let followed = || match dir.kind(name, path, true) {
+            Ok(kind) => match kind {
+                EntryKind::Directory => LinkStatus::Directory,
+                EntryKind::File => LinkStatus::File,
+                _ => LinkStatus::Other,
+            },
+            Err(error) if error.raw_os_error() == Some(libc::ELOOP) => LinkStatus::Loop,
+            Err(_) => LinkStatus::Dangling,
+        };
+        let (resolved, status) = match fs::canonicalize(path) {
+            Ok(resolved) => (Some(resolved), followed()),
+            Err(error) if error.raw_os_error() == Some(libc::ELOOP) => (None, LinkStatus::Loop),
+            // Past `PATH_MAX` only the directory's descriptor can tell.
+            Err(error) if error.raw_os_error() == Some(libc::ENAMETOOLONG) => (None, followed()),
+            Err(_) => (None, LinkStatus::Dangling),
+        };
+
+        // Broken links can still be placed by resolving the directory they
+        // are in and applying the target to it as written.
+        let outside_root = match &self.canonical_root {
+            Some(root) => {
+                let lands_at = resolved.clone().or_else(|| {
+                    let parent = fs::canonicalize(path.parent()?).ok()?;
+                    Some(link::normalize(&parent.join(&target)))
+                });
+                lands_at.is_some_and(|lands_at| !lands_at.starts_with(root))
+            }
+            None => false,
+        };
+
+        Ok(Link {
+            target,
+            resolved: if status.is_broken() { None } else { resolved },
+            status,
+            outside_root,
+        })
+    }
+
+    /// The part of the sizes of file `id`, which has `links` links, that
+    /// this link counts.
+    fn hard_link_share(&mut self, id: (u64, u64), links: u64, totals: Totals) -> Totals {
+        let first = self.options.hard_links == HardLinks::CountAll || self.seen_links.insert(id);
+        let share = |bytes: u64| match self.options.hard_links {
+            HardLinks::CountAll => bytes,
+            HardLinks::FirstSeen if first => bytes,
+            HardLinks::FirstSeen => 0,
+            // The first link also takes the remainder, so the shares add up
+            // exactly.
+            HardLinks::Split if first => bytes / links + bytes % links,
+            HardLinks::Split => bytes / links,
+        };
+        Totals {
+            size: share(totals.size),
+            disk_usage: share(totals.disk_usage),
+        }
+    }
+}
+
+/// The sizes of a directory's entries, added up.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Totals {
+    size: u64,
+    disk_usage: u64,
+}
+
+impl Settling {
+    fn settled(&mut self, entry: Entry, was_dropped: bool) {
+        if was_dropped {
+            self.settled_dropped.push(entry);
+        } else {
+            self.settled_kept.push(entry);
+        }
+    }
+}
+
+impl OpenDirs {
+    /// Starts counting the directories held open by `stack`, which has only
+    /// the frame a walk began at.
+    fn new(max: usize, stack: &[Frame]) -> Self {
+        OpenDirs {
+            count: stack.iter().filter(|frame| frame.is_open()).count(),
+            max,
+            lowest: 1,
+        }
+    }
+
+    /// Counts the directory of the frame just pushed onto `stack`, closing
+    /// the shallowest ones open if that is too many. The bottom frame is
+    /// kept open, so that there is always one to open the others from.
+    fn pushed(&mut self, stack: &mut [Frame]) {
+        if !stack.last().unwrap().is_open() {
+            return;
+        }
+        self.count += 1;
+        let top = stack.len() - 1;
+        while self.count > self.max && self.lowest < top {
+            if let Ok(listing) = &mut stack[self.lowest].listing {
+                if listing.dir.is_fd() {
+                    listing.dir = Dir::Closed;
+                    self.count -= 1;
+                }
+            }
+            self.lowest += 1;
+        }
+    }
+
+    /// Stops counting the directory of `frame`, just popped off a stack
+    /// that now holds `len` frames.
+    fn popped(&mut self, frame: &Frame, len: usize) {
+        if frame.is_open() {
+            self.count -= 1;
+        }
+        self.lowest = self.lowest.min(len.max(1));
+    }
+
+    /// The directory to reach the next entry of the top frame of `stack`
+    /// through, opened again if it was closed. The frame lets go of it once
+    /// no more entries are to be reached through it.
+    fn take(&mut self, stack: &mut [Frame], follow: bool) -> Result<Dir> {
+        let top = stack.len() - 1;
+        if let Ok(listing) = &stack[top].listing {
+            if matches!(listing.dir, Dir::Closed) && listing.here > 0 {
+                let dir = reopen(stack, follow)?;
+                if let Ok(listing) = &mut stack[top].listing {
+                    listing.dir = dir;
+                }
+                self.lowest = self.lowest.min(top.max(1));
+                self.pushed(stack);
+            }
+        }
+        let listing = match &mut stack[top].listing {
+            Ok(listing) => listing,
+            Err(_) => unreachable!("entries are only taken from directories being read"),
+        };
+        listing.here -= 1;
+        if listing.here > 0 || top == 0 {
+            return Ok(listing.dir.clone());
+        }
+        let dir = mem::replace(&mut listing.dir, Dir::Closed);
+        if dir.is_fd() {
+            self.count -= 1;
+        }
+        Ok(dir)
+    }
+}
+
+/// Opens the directory of the top frame of `stack` again, from the nearest
+/// frame below it that is still open.
+fn reopen(stack: &[Frame], follow: bool) -> Result<Dir> {
+    let open = stack.iter().rposition(Frame::is_open).unwrap_or(0);
+    let mut dir = match &stack[open].listing {
+        Ok(listing) => listing.dir.clone(),
+        Err(_) => Dir::Closed,
+    };
+    for frame in &stack[open + 1..] {
+        dir = dir
+            .open(&frame.name, follow, Some(frame.stat.id()))
+            .context(Operation::ReadDir, &frame.path, frame.depth)?;
+    }
+    Ok(dir)
+}
+
+impl Frame {
+    fn is_open(&self) -> bool {
+        self.listing
+            .as_ref()
+            .is_ok_and(|listing| listing.dir.is_fd())
+    }
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.size += other.size;
+        self.disk_usage += other.disk_usage;
+    }
+
+    fn of(entry: &Entry) -> Self {
+        Totals {
+            size: entry.size,
+            disk_usage: entry.disk_usage,
+        }
+    }
+}
+
+fn is_hidden(dir_entry: &DirEntry) -> bool {
+    dir_entry.name.as_bytes().starts_with(b".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, fs, process};
+
+    use super::{HardLinks, WalkOptions};
+    use crate::dir::{Backend, STATS};
+    use crate::filter::{Filter, Pattern};
+    use crate::tree::TreeBuilder;
+
+    /// Scans `root` with `options`, returning the tree as JSON and how many
+    /// entries had their metadata read.
+    fn scan(root: &Path, options: WalkOptions) -> (String, usize) {
+        STATS.with(|stats| stats.set(0));
+        let tree = TreeBuilder::new(root).options(options).build().unwrap();
+        (tree.to_json(), STATS.with(|stats| stats.get()))
+    }
+
+    #[test]
+    fn skips_reading_entries_the_filter_drops() {
+        let root = env::temp_dir().join(format!("file_tree_skipped_stats_{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for name in &["a.rs", "b.txt", "c.txt", "sub/d.txt", "sub/e.rs"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+
+        for &backend in &[Backend::Paths, Backend::Openat, Backend::Getdents] {
+            let options = |hard_links| WalkOptions {
+                filter: Filter::new().include(Pattern::glob("*.rs").unwrap()),
+                hard_links,
+                backend,
+                ..WalkOptions::default()
+            };
+            // Only `a.rs`, `sub` and `sub/e.rs` are read.
+            let (skipped, stats) = scan(&root, options(HardLinks::CountAll));
+            assert_eq!(stats, 3, "{:?}", backend);
+            // Dropped entries may hold the only counted link to a file.
+            let (read, stats) = scan(&root, options(HardLinks::FirstSeen));
+            assert_eq!(stats, 6, "{:?}", backend);
+            assert_eq!(skipped, read, "{:?}", backend);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! Scans that read directories through file descriptors.
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use file_tree::{Backend, Entry, FileTree, TreeBuilder};
+
+use crate::common::{make_long_path, temp_dir};
+
+const BACKENDS: [Backend; 2] = [Backend::Openat, Backend::Getdents];
+
+/// Fills `dir` with a few levels of directories, files and symlinks.
+fn make_tree(dir: &Path, levels: usize) {
+    fs::create_dir_all(dir).unwrap();
+    for i in 0..3 {
+        fs::write(dir.join(format!("file{}", i)), vec![b'x'; i * 10 + levels]).unwrap();
+    }
+    symlink("file0", dir.join("link")).unwrap();
+    symlink("missing", dir.join("dangling")).unwrap();
+    if levels > 0 {
+        for i in 0..3 {
+            make_tree(&dir.join(format!("dir{}", i)), levels - 1);
+        }
+        symlink("dir0", dir.join("dir_link")).unwrap();
+    }
+}
+
+fn scan(root: &Path, backend: Backend, max_open_dirs: usize, threads: usize) -> FileTree {
+    TreeBuilder::new(root)
+        .backend(backend)
+        .max_open_dirs(max_open_dirs)
+        .threads(threads)
+        .hash_contents(true)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn scans_past_path_max() {
+    let root = temp_dir("long_path");
+    let levels = 200;
+    make_long_path(&root, &"d".repeat(40), levels);
+    for (backend, threads) in BACKENDS
+        .iter()
+        .flat_map(|&backend| [(backend, 1), (backend, 4)])
+    {
+        let tree = scan(&root, backend, 4, threads);
+        let mut entry = tree.root();
+        let mut depth = 0;
+        while let Some(children) = entry.children() {
+            entry = &children[0];
+            depth += 1;
+        }
+        assert_eq!(depth, levels + 1);
+        assert_eq!(entry.name(), "leaf");
+        assert_eq!(tree.root().size(), 5);
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn gives_the_same_tree_as_paths() {
+    let root = temp_dir("backends");
+    make_tree(&root, 3);
+    let expected = scan(&root, Backend::Paths, 64, 1).to_json();
+    for (backend, max_open_dirs, threads) in BACKENDS.iter().flat_map(|&backend| {
+        [
+            (backend, 64, 1),
+            (backend, 1, 1),
+            (backend, 0, 1),
+            (backend, 1, 4),
+        ]
+    }) {
+        let tree = scan(&root, backend, max_open_dirs, threads);
+        assert_eq!(tree.to_json(), expected);
+        let tree = TreeBuilder::new(&root)
+            .backend(backend)
+            .max_open_dirs(max_open_dirs)
+            .threads(threads)
+            .follow_symlinks(true)
+            .build()
+            .unwrap();
+        let paths = TreeBuilder::new(&root)
+            .follow_symlinks(true)
+            .build()
+            .unwrap();
+        assert_eq!(tree.to_json(), paths.to_json());
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Checks that `a` and `b` have the same metadata, other than access times,
+/// which reading the tree may change, and the same for their children.
+fn assert_same_metadata(a: &Entry, b: &Entry) {
+    let (mut a_metadata, mut b_metadata) =
+        (a.metadata().unwrap().clone(), b.metadata().unwrap().clone());
+    a_metadata.accessed = None;
+    b_metadata.accessed = None;
+    assert_eq!(a_metadata, b_metadata, "{:?}", a.name());
+    if let (Some(a), Some(b)) = (a.children(), b.children()) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_same_metadata(a, b);
+        }
+    }
+}
+
+#[test]
+fn records_the_same_metadata_as_paths() {
+    let root = temp_dir("metadata");
+    make_tree(&root, 2);
+    let metadata = |backend| {
+        TreeBuilder::new(&root)
+            .backend(backend)
+            .metadata(true)
+            .build()
+            .unwrap()
+    };
+    let paths = metadata(Backend::Paths);
+    let getdents = metadata(Backend::Getdents);
+    assert_same_metadata(paths.root(), getdents.root());
+    fs::remove_dir_all(&root).unwrap();
+}
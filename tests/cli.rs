@@ -0,0 +1,283 @@
+//! The `file_tree` command, run as a user would.
+
+mod common;
+
+use std::os::unix::fs::symlink;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output};
+use std::{fs, io};
+
+use crate::common::{make_deep_tree, make_long_path, temp_dir};
+
+/// Runs the command with `args`.
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_file_tree"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn refuses_to_save_a_stream() {
+    let root = temp_dir("cli_stream_save");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file"), "file").unwrap();
+    let snapshot = root.with_extension("snap");
+
+    let output = run(&[
+        root.to_str().unwrap(),
+        "--format",
+        "ndjson",
+        "--save",
+        snapshot.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--save"), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert!(!snapshot.exists());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn diff_exits_with_whether_anything_changed() {
+    let root = temp_dir("cli_diff");
+    let (old, new) = (root.join("old"), root.join("new"));
+    for dir in &[&old, &new] {
+        fs::create_dir_all(dir.join("dir")).unwrap();
+        fs::write(dir.join("dir").join("file"), "file").unwrap();
+    }
+    // Options go before the subcommand.
+    let diff = |options: &[&str]| {
+        let mut args = options.to_vec();
+        args.extend_from_slice(&["diff", old.to_str().unwrap(), new.to_str().unwrap()]);
+        run(&args)
+    };
+
+    let output = run(&["diff", old.to_str().unwrap(), old.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stderr(&output).is_empty());
+
+    fs::write(new.join("dir").join("added"), "added").unwrap();
+    let output = diff(&[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("added"));
+    let output = diff(&["--format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["summary"]["added"], 1);
+
+    let missing = root.join("missing");
+    let output = run(&["diff", old.to_str().unwrap(), missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("missing"), "{}", stderr(&output));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn counts_sizes_in_blocks_of_the_size_given() {
+    let root = temp_dir("cli_block_size");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file"), vec![0; 1025]).unwrap();
+    let path = root.to_str().unwrap();
+
+    let output = run(&[path, "-s", "-B", "1K"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("2  total\n"));
+    for size in &["0", "0K", "K0"] {
+        let output = run(&[path, "-s", "-B", size]);
+        assert!(!output.status.success(), "{}", size);
+        assert!(
+            stderr(&output).contains("invalid block size"),
+            "{}",
+            stderr(&output)
+        );
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The names on the lines of a drawn tree below its root, in order.
+fn listed(output: &Output) -> Vec<String> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.rsplit(&['\u{251c}', '\u{2514}'][..]).next())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn sorts_in_the_order_asked_for() {
+    let root = temp_dir("cli_sort");
+    fs::create_dir_all(root.join("m")).unwrap();
+    for (name, size) in &[("z", 2), ("a", 3), ("b", 1)] {
+        fs::write(root.join(name), name.repeat(*size)).unwrap();
+    }
+    let sorted = |options: &[&str]| {
+        let mut args = vec![root.to_str().unwrap()];
+        args.extend_from_slice(options);
+        let output = run(&args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        listed(&output)
+    };
+
+    assert_eq!(sorted(&[]), ["a", "b", "m", "z"]);
+    assert_eq!(sorted(&["-r"]), ["z", "m", "b", "a"]);
+    assert_eq!(sorted(&["--dirsfirst"]), ["m", "a", "b", "z"]);
+    assert_eq!(sorted(&["-r", "--dirsfirst"]), ["m", "z", "b", "a"]);
+    assert_eq!(sorted(&["--sort", "size"]), ["a", "z", "b", "m"]);
+    assert_eq!(sorted(&["--sort", "size", "-r"]), ["m", "b", "z", "a"]);
+    // Unsorted entries are left in the order they were visited in.
+    assert_eq!(sorted(&["-U"]), ["a", "b", "m", "z"]);
+    let output = run(&[root.to_str().unwrap(), "-U", "-r"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn reports_broken_links_and_fails_if_there_are_any() {
+    let root = temp_dir("cli_broken_links");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub").join("file"), "file").unwrap();
+    symlink("sub", root.join("ok")).unwrap();
+    symlink("file", root.join("sub").join("ok")).unwrap();
+    let root_str = root.to_str().unwrap();
+
+    let output = run(&[root_str, "--broken-links"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert!(stderr(&output).is_empty());
+
+    symlink("missing", root.join("sub").join("dangling")).unwrap();
+    symlink("self", root.join("self")).unwrap();
+    let output = run(&[root_str, "--broken-links"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "{0}/self -> self (link loop)\n{0}/sub/dangling -> missing (dangling)\n",
+            root_str
+        )
+    );
+    assert_eq!(stderr(&output), "file_tree: 2 broken links\n");
+
+    // Broken links in what is left out are not reported.
+    let output = run(&[root_str, "--broken-links", "-I", "sub"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "file_tree: 1 broken links\n");
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn streams_events_as_directories_are_entered_and_left() {
+    let root = temp_dir("cli_ndjson");
+    fs::create_dir_all(root.join("a").join("b")).unwrap();
+    fs::write(root.join("a").join("f"), "f").unwrap();
+    fs::write(root.join("z"), "zz").unwrap();
+    let root_str = root.to_str().unwrap();
+
+    let output = run(&[root_str, "--format", "ndjson"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let listed: Vec<_> = events
+        .iter()
+        .map(|event| {
+            let path = event["path"].as_str().unwrap();
+            format!(
+                "{} {} {}",
+                event["event"].as_str().unwrap(),
+                event["depth"],
+                &path[root_str.len()..]
+            )
+        })
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            "enter_dir 0 ",
+            "enter_dir 1 /a",
+            "enter_dir 2 /a/b",
+            "exit_dir 2 /a/b",
+            "entry 2 /a/f",
+            "exit_dir 1 /a",
+            "entry 1 /z",
+            "exit_dir 0 ",
+        ]
+    );
+    // Directories are left with the totals of what is in them.
+    assert_eq!(events[5]["size"], 1);
+    assert_eq!(events[7]["size"], 1 + 2);
+    assert_eq!(events[7]["kind"], "directory");
+    assert!(events[7].get("children").is_none());
+    assert_eq!(events[6]["name"], "z");
+    assert_eq!(events[6]["size"], 2);
+
+    // Entries that cannot be read are streamed as errors, and fail the run.
+    make_long_path(&root.join("long"), &"d".repeat(200), 30);
+    let output = run(&[root_str, "--format", "ndjson", "-k"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "file_tree: 1 entries could not be read\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let errors: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|event: &serde_json::Value| event["kind"] == "error")
+        .collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["error"]["operation"], "stat");
+    let last: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    assert_eq!(last["event"], "exit_dir");
+    assert_eq!(last["depth"], 0);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn scans_deep_trees_with_few_descriptors() {
+    let root = temp_dir("cli_descriptors");
+    let levels = 1500;
+    make_deep_tree(&root, levels);
+    for backend in &["paths", "openat", "getdents"] {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_file_tree"));
+        command.args([
+            root.to_str().unwrap(),
+            "--backend",
+            backend,
+            "-s",
+            "--units",
+            "bytes",
+        ]);
+        // Far fewer descriptors than there are levels.
+        // SAFETY: `setrlimit` is safe to call between `fork` and `exec`.
+        unsafe {
+            command.pre_exec(|| {
+                let limit = libc::rlimit {
+                    rlim_cur: 256,
+                    rlim_max: 256,
+                };
+                if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{}: {}", backend, stderr(&output));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.ends_with(&format!("{}  total\n", levels + 1)),
+            "{}",
+            backend
+        );
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
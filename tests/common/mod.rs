@@ -0,0 +1,102 @@
+//! Helpers and fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use file_tree::{FileTree, TreeBuilder};
+
+/// A directory named after `name` and this process, for one test to use.
+pub fn temp_dir(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("file_tree_{}_{}", name, process::id()))
+}
+
+/// The name of a file in [`scanned_tree`] that is not valid UTF-8.
+pub const NON_UTF8_NAME: &[u8] = b"caf\xe9";
+
+/// Fills temporary directory `name` with a file of every kind a scan
+/// records, a non-UTF-8 name, a hard link and symlinks that are dangling,
+/// lead to a directory and lead outside, and scans it with metadata and
+/// content hashes. The directory is left for the caller to remove.
+pub fn scanned_tree(name: &str) -> (PathBuf, FileTree) {
+    let root = temp_dir(name);
+    fs::create_dir_all(root.join("dir")).unwrap();
+    let name = OsStr::from_bytes(NON_UTF8_NAME);
+    fs::write(root.join("dir").join(name), "bytes").unwrap();
+    fs::write(root.join("file"), "file").unwrap();
+    fs::hard_link(root.join("file"), root.join("hard_link")).unwrap();
+    symlink(Path::new(name), root.join("dangling")).unwrap();
+    symlink("dir", root.join("dir_link")).unwrap();
+    symlink("/", root.join("outside")).unwrap();
+    let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+    // SAFETY: `fifo` is a valid C string.
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    let tree = TreeBuilder::new(&root)
+        .metadata(true)
+        .hash_contents(true)
+        .build()
+        .unwrap();
+    let children = tree.root().children().unwrap();
+    let hard_link = children
+        .iter()
+        .find(|entry| entry.name() == "hard_link")
+        .unwrap();
+    assert_eq!(hard_link.links(), 2);
+    assert!(hard_link.content_hash().is_some());
+    (root, tree)
+}
+
+/// Makes `levels` directories named `name` nested in `root`, with a file
+/// at the bottom, going through descriptors so the path to it can be
+/// longer than `PATH_MAX`.
+pub fn make_long_path(root: &Path, name: &str, levels: usize) {
+    fs::create_dir_all(root).unwrap();
+    let name = CString::new(name).unwrap();
+    let root = CString::new(root.to_str().unwrap()).unwrap();
+    // SAFETY: the strings are valid C strings and each descriptor is closed
+    // once the next one has been opened from it.
+    unsafe {
+        let mut fd = libc::open(root.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY);
+        assert!(fd >= 0);
+        for _ in 0..levels {
+            assert_eq!(libc::mkdirat(fd, name.as_ptr(), 0o755), 0);
+            let next = libc::openat(fd, name.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY);
+            assert!(next >= 0);
+            libc::close(fd);
+            fd = next;
+        }
+        let leaf = CString::new("leaf").unwrap();
+        let file = libc::openat(fd, leaf.as_ptr(), libc::O_WRONLY | libc::O_CREAT, 0o644);
+        assert_eq!(libc::write(file, b"leaf\n".as_ptr().cast(), 5), 5);
+        libc::close(file);
+        libc::close(fd);
+    }
+}
+
+/// Makes `levels` directories named `d` nested in `root`, with a file named
+/// `f` beside each of them and at the bottom.
+pub fn make_deep_tree(root: &Path, levels: usize) {
+    let mut dir = root.to_path_buf();
+    for _ in 0..levels {
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("f"), "f").unwrap();
+        dir.push("d");
+    }
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("f"), "f").unwrap();
+}
+
+/// A tree of devices, errors and a loop, which no scan can be relied on to
+/// find, as JSON.
+pub const DEVICES_AND_ERRORS: &str = r#"{"version":1,"root_path":"/r","root":{"name":"r","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[
+    {"name":"sda","kind":"block_device","size":0,"disk_usage":0,"links":1,"device":{"major":8,"minor":0}},
+    {"name":"tty","kind":"char_device","size":0,"disk_usage":0,"links":1,"device":{"major":4294967295,"minor":1}},
+    {"name":"denied","kind":"error","size":0,"disk_usage":0,"links":0,"error":{"operation":"read_dir","path":"/r/denied","depth":1,"message":"Permission denied (os error 13)","os_error":13}},
+    {"name":"odd","kind":"error","size":0,"disk_usage":0,"links":0,"error":{"operation":"stat","path":"/r/odd","depth":1,"message":"something odd"}},
+    {"name":"loop","kind":"loop","size":0,"disk_usage":0,"links":1,"modified":{"secs":-1,"nanos":5}}
+]}}"#;
@@ -0,0 +1,349 @@
+//! Trees far deeper than the call stack could hold one frame per level of.
+
+mod common;
+
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, thread};
+
+use file_tree::{
+    Backend,
+    Change,
+    DiffEntry,
+    DiffOptions,
+    Entry,
+    EntryKind,
+    FileTree,
+    Filter,
+    Pattern,
+    RenderOptions,
+    SizeFormat,
+    SizeUnits,
+    Snapshot,
+    TreeBuilder,
+    Visit,
+    Visitor,
+    VisitorMut,
+    WalkOptions,
+};
+
+use crate::common::{make_deep_tree, temp_dir};
+
+const DEPTH: usize = 100_000;
+
+/// The JSON of a tree with `depth` directories named `d` nested below its
+/// root and a file named `leaf` at the bottom.
+fn deep_json(depth: usize) -> String {
+    let dir = r#"{"name":"d","kind":"directory","size":5,"disk_usage":0,"links":1,"children":["#;
+    let mut json = String::from(r#"{"version":1,"root_path":"/deep","root":"#);
+    for _ in 0..=depth {
+        json.push_str(dir);
+    }
+    json.push_str(r#"{"name":"leaf","kind":"file","size":5,"disk_usage":0,"links":1}"#);
+    for _ in 0..=depth {
+        json.push_str("]}");
+    }
+    json.push('}');
+    json
+}
+
+/// Counts what is written without keeping it, since drawing a tree this
+/// deep takes gigabytes. Newlines are written on their own or at the end of
+/// a piece, so lines are counted by piece.
+#[derive(Default)]
+struct Sink {
+    bytes: usize,
+    lines: usize,
+    line_len: usize,
+    longest_line: usize,
+    head: String,
+}
+
+impl Write for Sink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.lines < 3 {
+            self.head.push_str(s);
+        }
+        self.bytes += s.len();
+        self.line_len += s.len();
+        if s.ends_with('\n') {
+            self.lines += 1;
+            self.longest_line = self.longest_line.max(self.line_len - 1);
+            self.line_len = 0;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn builds_and_renders_a_deep_tree() {
+    let tree = FileTree::from_json(&deep_json(DEPTH)).unwrap();
+
+    let mut entry = tree.root();
+    let mut depth = 0;
+    while let Some(children) = entry.children() {
+        assert_eq!(children.len(), 1);
+        entry = &children[0];
+        depth += 1;
+    }
+    assert_eq!(depth, DEPTH + 1);
+    assert_eq!(entry.kind(), EntryKind::File);
+    assert_eq!(entry.name(), "leaf");
+    assert!(tree.errors().is_empty());
+    assert!(tree.broken_links().is_empty());
+
+    let mut sink = Sink::default();
+    write!(sink, "{}", tree.render(&RenderOptions::default())).unwrap();
+    // A line for every entry, and a blank one after each directory's.
+    assert_eq!(sink.lines, (DEPTH + 2) + (DEPTH + 1));
+    assert_eq!(
+        sink.head,
+        "d\n \u{2502} \u{2514}d\n \u{2502} \u{2502} \u{2514}d\n"
+    );
+    // The leaf's line: a bar for every level above it, then the corner.
+    assert_eq!(sink.longest_line, (DEPTH + 1) * 4 + 4 + "leaf".len());
+
+    let options = RenderOptions {
+        sizes: Some(SizeFormat::new(SizeUnits::Bytes)),
+        ..RenderOptions::default()
+    };
+    let mut sink = Sink::default();
+    write!(sink, "{}", tree.render(&options)).unwrap();
+    assert_eq!(sink.lines, (DEPTH + 2) + (DEPTH + 1) + 1);
+}
+
+#[test]
+fn round_trips_a_deep_tree_through_json() {
+    let json = deep_json(DEPTH);
+    let tree = FileTree::from_json(&json).unwrap();
+    let written = tree.to_json();
+    assert_eq!(FileTree::from_json(&written).unwrap().to_json(), written);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn round_trips_a_deep_tree_through_serde() {
+    let tree = FileTree::from_json(&deep_json(DEPTH)).unwrap();
+    let json = tree.to_json();
+    let text = serde_json::to_string(&tree).unwrap();
+    let from_json: FileTree = serde_json::from_str(&text).unwrap();
+    assert_eq!(from_json.to_json(), json);
+    let bytes = bincode::serialize(&tree).unwrap();
+    let from_bincode: FileTree = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(from_bincode.to_json(), json);
+}
+
+#[test]
+fn round_trips_a_deep_tree_through_a_snapshot() {
+    let tree = FileTree::from_json(&deep_json(DEPTH)).unwrap();
+    let json = tree.to_json();
+    let mut bytes = vec![];
+    Snapshot::new(tree, WalkOptions::default())
+        .write(&mut bytes)
+        .unwrap();
+    let snapshot = Snapshot::read(&bytes[..]).unwrap();
+    assert_eq!(snapshot.tree().to_json(), json);
+}
+
+#[test]
+fn filters_a_deep_tree() {
+    let mut tree = FileTree::from_json(&deep_json(DEPTH)).unwrap();
+    tree.filter(&Filter::new().include(Pattern::glob("leaf").unwrap()));
+    assert_eq!(tree.depth_first().count(), DEPTH + 2);
+    assert_eq!(tree.root().size(), 5);
+
+    // With nothing left at the bottom, every directory above it goes too.
+    tree.filter(&Filter::new().include(Pattern::glob("*.rs").unwrap()));
+    assert_eq!(tree.root().children().map(<[Entry]>::len), Some(0));
+    assert_eq!(tree.root().size(), 0);
+}
+
+#[test]
+fn diffs_a_deep_tree() {
+    let leaf = r#"{"name":"leaf","kind":"file","size":5,"disk_usage":0,"links":1}"#;
+    let hashed = |name: &str| {
+        format!(
+            r#"{{"name":"{}","kind":"file","size":5,"disk_usage":0,"links":1,"content_hash":"00000000000000ff"}}"#,
+            name
+        )
+    };
+    let old = FileTree::from_json(&deep_json(DEPTH).replace(leaf, &hashed("leaf"))).unwrap();
+    let new = FileTree::from_json(&deep_json(DEPTH).replace(leaf, &hashed("moved"))).unwrap();
+
+    // The directories all match up, leaving the leaves below the deepest.
+    let leaves = |root: &DiffEntry| {
+        let mut entry = root;
+        while let [child] = entry.children() {
+            entry = child;
+        }
+        let leaves = entry.children().iter();
+        leaves
+            .map(|child| (child.name().to_str().unwrap().to_string(), child.change()))
+            .collect::<Vec<_>>()
+    };
+
+    let diff = old.diff(&new);
+    assert_eq!(
+        leaves(diff.root()),
+        [
+            ("leaf".to_string(), Change::Removed),
+            ("moved".to_string(), Change::Added)
+        ]
+    );
+    let summary = diff.summary();
+    assert_eq!(
+        (summary.added, summary.removed, summary.modified),
+        (1, 1, 0)
+    );
+    let mut sink = Sink::default();
+    write!(sink, "{}", diff.render(&RenderOptions::default())).unwrap();
+    // A line for every directory, both leaves and the total.
+    assert_eq!(sink.lines, (DEPTH + 1) + 2 + 1);
+    let json = diff.to_json();
+    assert!(json.contains(r#""name":"leaf","change":"removed""#));
+
+    let options = DiffOptions {
+        detect_moves: true,
+        ..DiffOptions::default()
+    };
+    let diff = old.diff_with(&new, &options);
+    assert_eq!(
+        leaves(diff.root()),
+        [
+            ("leaf".to_string(), Change::Removed),
+            ("moved".to_string(), Change::Moved)
+        ]
+    );
+    assert_eq!(diff.summary().moved, 1);
+}
+
+#[test]
+fn detects_a_deep_directory_moved() {
+    // Finding what a directory is most like takes time in proportion to how
+    // much is below it, so this gets a shallower tree.
+    let depth = DEPTH / 50;
+    let old = deep_json(depth);
+    let name = r#""name":"d""#;
+    let (at, _) = old.match_indices(name).nth(1).unwrap();
+    let new = format!(r#"{}"name":"moved"{}"#, &old[..at], &old[at + name.len()..]);
+    let old = FileTree::from_json(&old).unwrap();
+    let new = FileTree::from_json(&new).unwrap();
+
+    let options = DiffOptions {
+        detect_moves: true,
+        ..DiffOptions::default()
+    };
+    let diff = old.diff_with(&new, &options);
+    let changes = diff
+        .root()
+        .children()
+        .iter()
+        .map(|child| (child.name().to_str().unwrap(), child.change()))
+        .collect::<Vec<_>>();
+    assert_eq!(changes, [("d", Change::Removed), ("moved", Change::Moved)]);
+    let moved = diff.root().children()[1].moved().unwrap();
+    assert_eq!((moved.path(), moved.similarity()), (Path::new("d"), 1.0));
+    let summary = diff.summary();
+    assert_eq!((summary.moved, summary.added, summary.removed), (1, 0, 0));
+}
+
+/// Counts the entries it is given and notes the deepest.
+#[derive(Default)]
+struct Counter {
+    entries: usize,
+    deepest: usize,
+}
+
+impl Counter {
+    fn count(&mut self, depth: usize) -> Visit {
+        self.entries += 1;
+        self.deepest = self.deepest.max(depth);
+        Visit::Continue
+    }
+}
+
+impl Visitor for Counter {
+    fn enter(&mut self, _path: &Path, depth: usize, _entry: &Entry) -> Visit {
+        self.count(depth)
+    }
+
+    fn leaf(&mut self, _path: &Path, depth: usize, _entry: &Entry) -> Visit {
+        self.count(depth)
+    }
+}
+
+impl VisitorMut for Counter {
+    fn enter(&mut self, _path: &Path, depth: usize, _entry: &mut Entry) -> Visit {
+        self.count(depth)
+    }
+
+    fn leaf(&mut self, _path: &Path, depth: usize, _entry: &mut Entry) -> Visit {
+        self.count(depth)
+    }
+}
+
+#[test]
+fn visits_a_deep_tree() {
+    // Iterators give each entry its own path, which takes time in
+    // proportion to the depth, so they get a shallower tree.
+    let depth = DEPTH / 10;
+    let tree = FileTree::from_json(&deep_json(depth)).unwrap();
+    let (path, leaf_depth, leaf) = tree.depth_first().last().unwrap();
+    assert_eq!(
+        (leaf_depth, leaf.name().to_str()),
+        (depth + 1, Some("leaf"))
+    );
+    assert_eq!(path.components().count(), depth + 3);
+    assert_eq!(tree.breadth_first().count(), depth + 2);
+
+    let mut tree = FileTree::from_json(&deep_json(DEPTH)).unwrap();
+    let mut counter = Counter::default();
+    tree.visit(&mut counter);
+    assert_eq!((counter.entries, counter.deepest), (DEPTH + 2, DEPTH + 1));
+    let mut counter = Counter::default();
+    tree.visit_mut(&mut counter);
+    assert_eq!((counter.entries, counter.deepest), (DEPTH + 2, DEPTH + 1));
+    assert_eq!(tree.depth_first().count(), DEPTH + 2);
+}
+
+/// Scans `root` on a thread with a stack far too small for a frame per
+/// level, returning how deep the file at the bottom was found.
+fn scan_on_small_stack(root: PathBuf, backend: Backend, threads: usize) -> usize {
+    thread::Builder::new()
+        .stack_size(512 * 1024)
+        .spawn(move || {
+            let tree = TreeBuilder::new(&root)
+                .backend(backend)
+                .threads(threads)
+                .build()
+                .unwrap();
+            let mut entry = tree.root();
+            let mut depth = 0;
+            while let Some(children) = entry.children() {
+                entry = &children[0];
+                depth += 1;
+            }
+            assert_eq!(entry.name(), "f");
+            assert_eq!(tree.root().size() as usize, depth);
+            depth
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn scans_a_deep_directory() {
+    // Each level adds two bytes to the path, which must stay under
+    // PATH_MAX.
+    let levels = 1800;
+    let root = temp_dir("deep");
+    make_deep_tree(&root, levels);
+    for &backend in &[Backend::Paths, Backend::Openat, Backend::Getdents] {
+        for &threads in &[1, 4] {
+            let depth = scan_on_small_stack(root.clone(), backend, threads);
+            assert_eq!(depth, levels + 1, "{:?} {}", backend, threads);
+        }
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,278 @@
+//! Comparing two scans of a directory.
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use file_tree::{Change, Changes, DiffEntry, DiffOptions, FileTree, TreeBuilder, TreeDiff};
+
+use crate::common::temp_dir;
+
+/// Writes each of `files` below `root`, making the directories they are in.
+fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+fn scan(root: &Path) -> FileTree {
+    TreeBuilder::new(root).hash_contents(true).build().unwrap()
+}
+
+/// Every entry below the root of `diff` that changed, by path, in name
+/// order.
+fn changed(diff: &TreeDiff) -> Vec<(PathBuf, &DiffEntry)> {
+    let mut changed = vec![];
+    let mut stack = vec![(PathBuf::new(), diff.root())];
+    while let Some((path, entry)) = stack.pop() {
+        for child in entry.children().iter().rev() {
+            stack.push((path.join(child.name()), child));
+        }
+        if entry.change() != Change::Unchanged && path != Path::new("") {
+            changed.push((path, entry));
+        }
+    }
+    changed
+}
+
+/// The paths and changes of every entry below the root of `diff` that
+/// changed.
+fn changes(diff: &TreeDiff) -> Vec<(String, Change)> {
+    changed(diff)
+        .into_iter()
+        .map(|(path, entry)| (path.to_str().unwrap().to_string(), entry.change()))
+        .collect()
+}
+
+#[test]
+fn classifies_added_removed_and_modified_entries() {
+    let root = temp_dir("diff_classify");
+    write_files(
+        &root,
+        &[
+            ("grows", "one"),
+            ("same", "same"),
+            ("dir/kept", "kept"),
+            ("dir/gone", "gone"),
+            ("becomes_dir", "file"),
+        ],
+    );
+    symlink("grows", root.join("link")).unwrap();
+    let old = scan(&root);
+    assert!(old.diff(&old).is_empty());
+    assert!(changes(&old.diff(&old)).is_empty());
+
+    fs::write(root.join("grows"), "one more").unwrap();
+    fs::remove_file(root.join("dir/gone")).unwrap();
+    write_files(&root, &[("dir/sub/new", "new")]);
+    fs::remove_file(root.join("becomes_dir")).unwrap();
+    fs::create_dir(root.join("becomes_dir")).unwrap();
+    fs::remove_file(root.join("link")).unwrap();
+    symlink("same", root.join("link")).unwrap();
+    let new = scan(&root);
+
+    let diff = old.diff(&new);
+    assert!(!diff.is_empty());
+    assert_eq!(
+        changes(&diff),
+        [
+            ("becomes_dir".to_string(), Change::Modified),
+            ("dir".to_string(), Change::Modified),
+            ("dir/gone".to_string(), Change::Removed),
+            ("dir/sub".to_string(), Change::Added),
+            ("dir/sub/new".to_string(), Change::Added),
+            ("grows".to_string(), Change::Modified),
+            ("link".to_string(), Change::Modified),
+        ]
+    );
+    let changed = changed(&diff);
+    let changes_of = |path: &str| {
+        let (_, entry) = changed.iter().find(|(p, _)| p == Path::new(path)).unwrap();
+        entry.changes()
+    };
+    assert!(changes_of("becomes_dir").kind);
+    assert_eq!(changes_of("dir"), Changes::default());
+    assert!(changes_of("grows").size);
+    assert!(!changes_of("grows").kind);
+    assert!(changes_of("link").symlink_target);
+    let (_, grows) = changed
+        .iter()
+        .find(|(p, _)| p == Path::new("grows"))
+        .unwrap();
+    assert_eq!(grows.before().unwrap().size, 3);
+    assert_eq!(grows.after().unwrap().size, 8);
+
+    let summary = diff.summary();
+    assert_eq!(
+        (
+            summary.added,
+            summary.removed,
+            summary.modified,
+            summary.moved
+        ),
+        (2, 1, 3, 0)
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn writes_changed_entries_as_json() {
+    let root = temp_dir("diff_json");
+    write_files(&root, &[("dir/same", "same"), ("dir/grows", "one")]);
+    let old = scan(&root);
+    write_files(&root, &[("dir/grows", "one more"), ("added", "added")]);
+    let new = scan(&root);
+
+    let json: serde_json::Value = serde_json::from_str(&old.diff(&new).to_json()).unwrap();
+    assert_eq!(json["old_root"], root.to_str().unwrap());
+    assert_eq!(
+        json["summary"],
+        serde_json::json!({"added": 1, "removed": 0, "modified": 1, "moved": 0})
+    );
+    let root_entry = &json["root"];
+    assert_eq!(root_entry["change"], "modified");
+    assert_eq!(root_entry["size_delta"], 5 + 5);
+
+    // Unchanged entries are only counted.
+    let children = root_entry["children"].as_array().unwrap();
+    assert_eq!(children.len(), 2);
+    assert_eq!(children[0]["name"], "added");
+    assert_eq!(children[0]["change"], "added");
+    assert!(children[0].get("before").is_none());
+    assert_eq!(children[0]["after"]["size"], 5);
+    let dir = &children[1];
+    assert_eq!(dir["unchanged"], 1);
+    let grows = &dir["children"][0];
+    assert_eq!(grows["name"], "grows");
+    assert_eq!(grows["change"], "modified");
+    assert!(grows["changes"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!("size")));
+    assert_eq!(grows["before"]["size"], 3);
+    assert_eq!(grows["after"]["size"], 8);
+    assert_eq!(grows["size_delta"], 5);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The diff of `old` and `new` with moves detected, directories counting as
+/// moved when at least `min_similarity` of them is alike.
+fn diff_moves(old: &FileTree, new: &FileTree, min_similarity: f64) -> TreeDiff {
+    let options = DiffOptions {
+        detect_moves: true,
+        read_files: false,
+        min_similarity,
+    };
+    old.diff_with(new, &options)
+}
+
+#[test]
+fn detects_moved_files_by_their_contents() {
+    let root = temp_dir("diff_moved_files");
+    write_files(
+        &root,
+        &[
+            ("a/moved", "moved contents"),
+            ("a/renamed", "renamed contents"),
+            ("a/other", "other"),
+        ],
+    );
+    let old = scan(&root);
+    fs::create_dir(root.join("b")).unwrap();
+    fs::rename(root.join("a/moved"), root.join("b/moved")).unwrap();
+    fs::rename(root.join("a/renamed"), root.join("a/new_name")).unwrap();
+    // The same size as `other`, but not the same contents.
+    fs::remove_file(root.join("a/other")).unwrap();
+    write_files(&root, &[("b/lookalike", "OTHER")]);
+    let new = scan(&root);
+
+    // Without detecting moves, every one is a removal and an addition.
+    let plain = old.diff(&new);
+    assert_eq!(plain.summary().moved, 0);
+
+    let diff = diff_moves(&old, &new, 0.5);
+    assert_eq!(
+        changes(&diff),
+        [
+            ("a".to_string(), Change::Modified),
+            ("a/moved".to_string(), Change::Removed),
+            ("a/new_name".to_string(), Change::Moved),
+            ("a/other".to_string(), Change::Removed),
+            ("a/renamed".to_string(), Change::Removed),
+            ("b".to_string(), Change::Added),
+            ("b/lookalike".to_string(), Change::Added),
+            ("b/moved".to_string(), Change::Moved),
+        ]
+    );
+    let changed = changed(&diff);
+    let moved = |path: &str| {
+        let (_, entry) = changed.iter().find(|(p, _)| p == Path::new(path)).unwrap();
+        let moved = entry.moved()?;
+        Some((
+            moved.path().to_str().unwrap().to_string(),
+            moved.similarity(),
+        ))
+    };
+    assert_eq!(moved("b/moved"), Some(("a/moved".to_string(), 1.0)));
+    assert_eq!(moved("a/moved"), Some(("b/moved".to_string(), 1.0)));
+    assert_eq!(moved("a/new_name"), Some(("a/renamed".to_string(), 1.0)));
+    assert_eq!(moved("a/other"), None);
+    assert_eq!(moved("b/lookalike"), None);
+    assert_eq!(diff.summary().moved, 2);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn moves_directories_as_alike_as_the_threshold() {
+    let root = temp_dir("diff_moved_dirs");
+    write_files(
+        &root,
+        &[
+            ("photos/1", "one"),
+            ("photos/2", "two"),
+            ("photos/3", "three"),
+            ("photos/4", "four"),
+        ],
+    );
+    let old = scan(&root);
+    fs::rename(root.join("photos"), root.join("pictures")).unwrap();
+    write_files(&root, &[("pictures/5", "five")]);
+    let new = scan(&root);
+
+    // Four of the five files below either are in both.
+    let diff = diff_moves(&old, &new, 0.8);
+    let changed = changed(&diff);
+    let (_, pictures) = changed
+        .iter()
+        .find(|(path, _)| path == Path::new("pictures"))
+        .unwrap();
+    assert_eq!(pictures.change(), Change::Moved);
+    let moved = pictures.moved().unwrap();
+    assert_eq!(moved.path(), Path::new("photos"));
+    assert!(
+        (moved.similarity() - 0.8).abs() < 1e-9,
+        "{}",
+        moved.similarity()
+    );
+    // Below it, entries compare with those of the directory it moved from.
+    assert_eq!(
+        changes(&diff)
+            .into_iter()
+            .filter(|(path, _)| path.starts_with("pictures/"))
+            .collect::<Vec<_>>(),
+        [("pictures/5".to_string(), Change::Added)]
+    );
+
+    let diff = diff_moves(&old, &new, 0.9);
+    let changes = changes(&diff);
+    assert!(changes.contains(&("photos".to_string(), Change::Removed)));
+    assert!(changes.contains(&("pictures".to_string(), Change::Added)));
+    // The files in it are still paired one by one.
+    assert!(changes.contains(&("pictures/1".to_string(), Change::Moved)));
+    assert!(changes.contains(&("pictures/5".to_string(), Change::Added)));
+    fs::remove_dir_all(&root).unwrap();
+}
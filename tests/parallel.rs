@@ -0,0 +1,80 @@
+//! Scans spread over several threads, which must give the tree a scan on
+//! one thread does.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use file_tree::{Filter, HardLinks, Pattern, TreeBuilder, WalkOptions};
+
+use crate::common::temp_dir;
+
+/// Fills `root` with directories of files, some of them hard links to files
+/// in other directories, or in the same one.
+fn make_tree(root: &Path) {
+    for dir in 0..6 {
+        let dir = root.join(format!("dir{}", dir));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for file in 0..5 {
+            let contents = vec![b'x'; dir.as_os_str().len() * 100 + file * 7];
+            fs::write(dir.join(format!("file{}.rs", file)), &contents).unwrap();
+            fs::write(dir.join("sub").join(format!("file{}.txt", file)), &contents).unwrap();
+        }
+    }
+    for (from, to) in &[
+        ("dir0/file0.rs", "dir5/sub/link.rs"),
+        ("dir0/file0.rs", "dir3/link.txt"),
+        ("dir0/file0.rs", "dir0/sub/link.rs"),
+        ("dir2/sub/file1.txt", "dir1/link.rs"),
+        ("dir4/file4.rs", "dir4/sub/link.txt"),
+        ("dir4/file4.rs", "dir2/link.rs"),
+    ] {
+        fs::hard_link(root.join(from), root.join(to)).unwrap();
+    }
+}
+
+#[test]
+fn gives_the_same_tree_as_one_thread() {
+    let root = temp_dir("parallel");
+    make_tree(&root);
+    let policies = [HardLinks::FirstSeen, HardLinks::Split, HardLinks::CountAll];
+    let filters = [
+        Filter::new(),
+        Filter::new().include(Pattern::glob("*.rs").unwrap()),
+        Filter::new().exclude(Pattern::glob("sub").unwrap()),
+    ];
+    for &hard_links in &policies {
+        for filter in &filters {
+            for &count_filtered in &[false, true] {
+                let options = WalkOptions {
+                    hard_links,
+                    filter: filter.clone(),
+                    count_filtered,
+                    ..WalkOptions::default()
+                };
+                let serial = TreeBuilder::new(&root)
+                    .options(options.clone())
+                    .build()
+                    .unwrap();
+                for threads in [2, 4, 8] {
+                    let parallel = TreeBuilder::new(&root)
+                        .options(options.clone())
+                        .threads(threads)
+                        .build()
+                        .unwrap();
+                    assert_eq!(
+                        parallel.to_json(),
+                        serial.to_json(),
+                        "{:?} {:?} {} {}",
+                        hard_links,
+                        filter,
+                        count_filtered,
+                        threads
+                    );
+                }
+            }
+        }
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
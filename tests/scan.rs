@@ -0,0 +1,572 @@
+//! Scanning directories with the options a scan takes.
+
+mod common;
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use std::{fs, io};
+
+use file_tree::{
+    get_file_tree,
+    Column,
+    Device,
+    Entry,
+    EntryData,
+    EntryKind,
+    FileTree,
+    HardLinks,
+    Operation,
+    RenderOptions,
+    SizeKind,
+    SortKey,
+    SortOrder,
+    TreeBuilder,
+};
+
+use crate::common::{make_long_path, temp_dir, DEVICES_AND_ERRORS};
+
+/// Writes each of `files` below `root`, making the directories they are in.
+fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+/// The names of the entries of directory `entry`, in order.
+fn names(entry: &Entry) -> Vec<&str> {
+    let children = entry.children().unwrap();
+    children
+        .iter()
+        .map(|child| child.name().to_str().unwrap())
+        .collect()
+}
+
+/// The entry below `entry` at `path`.
+fn find<'a>(entry: &'a Entry, path: &str) -> &'a Entry {
+    path.split('/').fold(entry, |entry, name| {
+        let children = entry.children().unwrap();
+        children.iter().find(|child| child.name() == name).unwrap()
+    })
+}
+
+#[test]
+fn scans_a_directory_into_a_tree() {
+    let root = temp_dir("scan_tree");
+    write_files(&root, &[("b", "bb"), ("a/x", "xxx"), ("a/y/z", "z")]);
+
+    let tree = get_file_tree(&root).unwrap();
+    assert_eq!(tree.root_path(), root);
+    assert_eq!(tree.root().name(), root.file_name().unwrap());
+    assert!(tree.root().is_dir());
+    assert_eq!(tree.root().size(), 2 + 3 + 1);
+    assert_eq!(names(tree.root()), ["a", "b"]);
+    let a = find(tree.root(), "a");
+    assert_eq!(a.kind(), EntryKind::Directory);
+    assert_eq!(a.size(), 3 + 1);
+    assert_eq!(names(a), ["x", "y"]);
+    let z = find(tree.root(), "a/y/z");
+    assert_eq!(z.kind(), EntryKind::File);
+    assert!(!z.is_dir());
+    assert!(z.children().is_none());
+    assert!(tree.errors().is_empty());
+
+    let built = FileTree::builder(&root).build().unwrap();
+    assert_eq!(built.to_json(), tree.to_json());
+    assert_eq!(tree.into_root().size(), 6);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn limits_depth_hidden_files_and_entry_counts() {
+    let root = temp_dir("scan_limits");
+    write_files(
+        &root,
+        &[
+            (".hidden", "h"),
+            ("a/b/c/d", "d"),
+            ("few/one", "1"),
+            ("many/1", "1"),
+            ("many/2", "2"),
+            ("many/3", "3"),
+            ("more/1", "1"),
+            ("more/2", "2"),
+            ("more/3", "3"),
+            ("more/4", "4"),
+        ],
+    );
+
+    let tree = TreeBuilder::new(&root).build().unwrap();
+    assert_eq!(names(tree.root()), ["a", "few", "many", "more"]);
+    assert_eq!(names(find(tree.root(), "a/b/c")), ["d"]);
+    let tree = TreeBuilder::new(&root).show_hidden(true).build().unwrap();
+    assert_eq!(names(tree.root()), [".hidden", "a", "few", "many", "more"]);
+
+    // Directories at the limit are listed, but empty.
+    let tree = TreeBuilder::new(&root).max_depth(2).build().unwrap();
+    let b = find(tree.root(), "a/b");
+    assert!(b.is_dir());
+    assert!(names(b).is_empty());
+    assert_eq!(b.size(), 0);
+    let tree = TreeBuilder::new(&root).max_depth(0).build().unwrap();
+    assert!(names(tree.root()).is_empty());
+
+    let tree = TreeBuilder::new(&root)
+        .min_entries(2)
+        .max_entries(3)
+        .build()
+        .unwrap();
+    assert!(names(find(tree.root(), "a")).is_empty());
+    assert!(names(find(tree.root(), "few")).is_empty());
+    assert_eq!(names(find(tree.root(), "many")), ["1", "2", "3"]);
+    assert!(names(find(tree.root(), "more")).is_empty());
+    assert_eq!(tree.root().size(), 3);
+
+    // Everything is on one file system here.
+    let tree = TreeBuilder::new(&root)
+        .one_file_system(true)
+        .build()
+        .unwrap();
+    assert_eq!(tree.root().size(), 1 + 1 + 3 + 4);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The names of the entries of `root`, scanned in `order`.
+fn sorted(root: &Path, order: SortOrder) -> Vec<String> {
+    let tree = TreeBuilder::new(root).sort(order).build().unwrap();
+    names(tree.root()).into_iter().map(String::from).collect()
+}
+
+#[test]
+fn sorts_by_each_key() {
+    let root = temp_dir("scan_sort");
+    let files = [
+        ("a.rs", 30, 1000),
+        ("B.txt", 10, 2000),
+        ("dir/x", 1, 3000),
+        ("file10", 5, 4000),
+        ("file2", 20, 5000),
+    ];
+    for &(path, size, _) in &files {
+        write_files(&root, &[(path, &"x".repeat(size))]);
+    }
+    for &(path, _, secs) in &files {
+        let path = root.join(path.split('/').next().unwrap());
+        let file = fs::File::open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    let order = SortOrder::new;
+    let by_name = ["B.txt", "a.rs", "dir", "file10", "file2"];
+    assert_eq!(sorted(&root, order(SortKey::Name)), by_name);
+    assert_eq!(
+        sorted(&root, order(SortKey::NameIgnoreCase)),
+        ["a.rs", "B.txt", "dir", "file10", "file2"]
+    );
+    assert_eq!(
+        sorted(&root, order(SortKey::Version)),
+        ["B.txt", "a.rs", "dir", "file2", "file10"]
+    );
+    // Directories count what is in them.
+    assert_eq!(
+        sorted(&root, order(SortKey::Size)),
+        ["a.rs", "file2", "B.txt", "file10", "dir"]
+    );
+    assert_eq!(
+        sorted(&root, order(SortKey::Modified)),
+        ["file2", "file10", "dir", "B.txt", "a.rs"]
+    );
+    assert_eq!(
+        sorted(&root, order(SortKey::Extension)),
+        ["dir", "file10", "file2", "a.rs", "B.txt"]
+    );
+
+    assert_eq!(
+        sorted(&root, order(SortKey::Name).reverse(true)),
+        ["file2", "file10", "dir", "a.rs", "B.txt"]
+    );
+    assert_eq!(
+        sorted(&root, order(SortKey::Size).dirs_first(true)),
+        ["dir", "a.rs", "file2", "B.txt", "file10"]
+    );
+    // Directories stay first when the order is reversed.
+    assert_eq!(
+        sorted(&root, order(SortKey::Name).reverse(true).dirs_first(true)),
+        ["dir", "file2", "file10", "a.rs", "B.txt"]
+    );
+    let tree = TreeBuilder::new(&root).build().unwrap();
+    assert_eq!(names(tree.root()), by_name);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The deepest entry below `entry`, following the first entry of each
+/// directory, and how deep it is.
+fn deepest(entry: &Entry) -> (&Entry, usize) {
+    let mut entry = entry;
+    let mut depth = 0;
+    while let Some([child, ..]) = entry.children() {
+        entry = child;
+        depth += 1;
+    }
+    (entry, depth)
+}
+
+#[test]
+fn keeps_going_past_entries_it_cannot_read() {
+    let root = temp_dir("scan_keep_going");
+    // Too long a path to reach the bottom by.
+    make_long_path(&root.join("long"), &"d".repeat(200), 30);
+    write_files(&root, &[("short", "short")]);
+
+    assert!(TreeBuilder::new(&root).build().is_err());
+    let tree = TreeBuilder::new(&root).keep_going(true).build().unwrap();
+    assert_eq!(names(tree.root()), ["long", "short"]);
+    assert_eq!(find(tree.root(), "short").size(), 5);
+    let (error, depth) = deepest(tree.root());
+    assert_eq!(error.kind(), EntryKind::Error);
+    assert_eq!(error.size(), 0);
+    assert!(depth > 1 && depth < 30, "{}", depth);
+    assert_eq!(tree.errors().len(), 1);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn reports_the_path_operation_and_depth_that_failed() {
+    let root = temp_dir("scan_error");
+    let missing = root.join("missing");
+    let error = get_file_tree(&missing).err().unwrap();
+    assert_eq!(error.operation(), Operation::Stat);
+    assert_eq!(error.path(), missing);
+    assert_eq!(error.depth(), 0);
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "cannot stat {}: {}",
+            missing.display(),
+            io::Error::from_raw_os_error(libc::ENOENT)
+        )
+    );
+
+    make_long_path(&root, &"d".repeat(200), 30);
+    let error = get_file_tree(&root).err().unwrap();
+    let tree = TreeBuilder::new(&root).keep_going(true).build().unwrap();
+    let (_, depth) = deepest(tree.root());
+    let recorded = tree.errors()[0];
+    for error in &[&error, recorded] {
+        assert_eq!(error.operation(), Operation::Stat);
+        assert_eq!(error.depth(), depth);
+        assert_eq!(
+            error.path().components().count(),
+            root.components().count() + depth
+        );
+        assert_eq!(error.io_error().raw_os_error(), Some(libc::ENAMETOOLONG));
+    }
+
+    // A receiver that fails stops the scan, as the write of an event.
+    let error = TreeBuilder::new(&root)
+        .stream(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")))
+        .err()
+        .unwrap();
+    assert_eq!(error.operation(), Operation::WriteEvent);
+    assert_eq!(error.path(), root);
+    assert_eq!(error.depth(), 0);
+    assert_eq!(error.into_io_error().kind(), io::ErrorKind::BrokenPipe);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn skips_ignore_files_it_cannot_read() {
+    let root = temp_dir("scan_unreadable_ignore");
+    write_files(
+        &root,
+        &[
+            (".gitignore", "*.o"),
+            ("sub/.gitignore/inside", "inside"),
+            ("sub/keep", "keep"),
+            ("sub/main.o", "main"),
+        ],
+    );
+
+    for &keep_going in &[false, true] {
+        for &threads in &[1, 4] {
+            let tree = TreeBuilder::new(&root)
+                .git_ignore(true)
+                .keep_going(keep_going)
+                .threads(threads)
+                .build()
+                .unwrap();
+            // The rules above still apply to the directory.
+            let sub = find(tree.root(), "sub");
+            assert_eq!(names(sub), ["", "keep"]);
+            assert_eq!(sub.children().unwrap()[0].kind(), EntryKind::Error);
+            let errors = tree.errors();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].operation(), Operation::ReadFile);
+            assert_eq!(errors[0].path(), root.join("sub/.gitignore"));
+            assert_eq!(errors[0].depth(), 1);
+            assert_eq!(errors[0].io_error().raw_os_error(), Some(libc::EISDIR));
+        }
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// The bytes allocated for what is at `path` itself.
+fn allocated(path: &Path) -> u64 {
+    fs::symlink_metadata(path).unwrap().blocks() * 512
+}
+
+#[test]
+fn counts_allocated_space_apart_from_length() {
+    let root = temp_dir("scan_disk_usage");
+    write_files(&root, &[("dir/small", "small")]);
+    // A sparse file, far longer than what is allocated for it.
+    let sparse = fs::File::create(root.join("sparse")).unwrap();
+    sparse.set_len(1 << 24).unwrap();
+
+    let tree = TreeBuilder::new(&root).build().unwrap();
+    let small = find(tree.root(), "dir/small");
+    assert_eq!(small.size(), 5);
+    assert_eq!(small.disk_usage(), allocated(&root.join("dir/small")));
+    let sparse = find(tree.root(), "sparse");
+    assert_eq!(sparse.size(), 1 << 24);
+    assert_eq!(sparse.disk_usage(), allocated(&root.join("sparse")));
+    assert!(sparse.disk_usage() < sparse.size());
+    assert_eq!(sparse.size_of(SizeKind::Apparent), sparse.size());
+    assert_eq!(sparse.size_of(SizeKind::Allocated), sparse.disk_usage());
+
+    // Directories count their own allocation, but not their own length.
+    let dir = find(tree.root(), "dir");
+    assert_eq!(dir.size(), 5);
+    assert_eq!(
+        dir.disk_usage(),
+        allocated(&root.join("dir")) + small.disk_usage()
+    );
+    assert_eq!(tree.root().size(), 5 + (1 << 24));
+    assert_eq!(
+        tree.root().disk_usage(),
+        allocated(&root) + dir.disk_usage() + sparse.disk_usage()
+    );
+
+    let by_size = |size_kind| {
+        let order = SortOrder::new(SortKey::Size).size_kind(size_kind);
+        let tree = TreeBuilder::new(&root).sort(order).build().unwrap();
+        names(tree.root()).join(" ")
+    };
+    assert_eq!(by_size(SizeKind::Apparent), "sparse dir");
+    assert_eq!(by_size(SizeKind::Allocated), "dir sparse");
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn counts_hard_links_by_policy() {
+    let root = temp_dir("scan_hard_links");
+    write_files(&root, &[("a/file", &"x".repeat(31)), ("b/other", "other")]);
+    fs::hard_link(root.join("a/file"), root.join("b/link")).unwrap();
+    fs::create_dir(root.join("c")).unwrap();
+    fs::hard_link(root.join("a/file"), root.join("c/link")).unwrap();
+    let file_usage = allocated(&root.join("a/file"));
+
+    let sizes = |root: &Path, hard_links| {
+        let tree = TreeBuilder::new(root)
+            .hard_links(hard_links)
+            .build()
+            .unwrap();
+        let links: Vec<_> = tree
+            .depth_first()
+            .filter(|(_, _, entry)| entry.links() == 3)
+            .map(|(_, _, entry)| (entry.size(), entry.disk_usage()))
+            .collect();
+        (tree.root().size(), links)
+    };
+
+    // The first link reached, in name order, counts the whole file.
+    assert_eq!(
+        sizes(&root, HardLinks::FirstSeen),
+        (31 + 5, vec![(31, file_usage), (0, 0), (0, 0)])
+    );
+    // The first also takes what does not divide evenly.
+    let (share, rest) = (file_usage / 3, file_usage % 3);
+    assert_eq!(
+        sizes(&root, HardLinks::Split),
+        (31 + 5, vec![(11, share + rest), (10, share), (10, share)])
+    );
+    assert_eq!(
+        sizes(&root, HardLinks::CountAll),
+        (3 * 31 + 5, vec![(31, file_usage); 3])
+    );
+
+    // Links outside the directory scanned are not seen.
+    let b = root.join("b");
+    assert_eq!(
+        sizes(&b, HardLinks::FirstSeen),
+        (31 + 5, vec![(31, file_usage)])
+    );
+    assert_eq!(
+        sizes(&b, HardLinks::Split),
+        (11 + 5, vec![(11, share + rest)])
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn follows_symlinks_without_looping() {
+    let root = temp_dir("scan_follow");
+    write_files(&root, &[("real/file", "file")]);
+    symlink("real", root.join("link")).unwrap();
+    symlink("..", root.join("real/up")).unwrap();
+    symlink("real/file", root.join("file_link")).unwrap();
+    symlink("missing", root.join("dangling")).unwrap();
+    let kinds = |tree: &FileTree| {
+        let entries = tree.depth_first().skip(1);
+        entries
+            .map(|(path, _, entry)| {
+                let path = path.strip_prefix(&root).unwrap();
+                (path.to_str().unwrap().to_string(), entry.kind())
+            })
+            .collect::<Vec<_>>()
+    };
+    let kind = |path: &str, kind| (path.to_string(), kind);
+
+    let tree = TreeBuilder::new(&root).build().unwrap();
+    assert_eq!(
+        kinds(&tree),
+        [
+            kind("dangling", EntryKind::Symlink),
+            kind("file_link", EntryKind::Symlink),
+            kind("link", EntryKind::Symlink),
+            kind("real", EntryKind::Directory),
+            kind("real/file", EntryKind::File),
+            kind("real/up", EntryKind::Symlink),
+        ]
+    );
+    assert_eq!(tree.root().size(), 4);
+
+    // Links to directories being walked are not walked again.
+    let tree = TreeBuilder::new(&root)
+        .follow_symlinks(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        kinds(&tree),
+        [
+            kind("dangling", EntryKind::Symlink),
+            kind("file_link", EntryKind::File),
+            kind("link", EntryKind::Directory),
+            kind("link/file", EntryKind::File),
+            kind("link/up", EntryKind::Loop),
+            kind("real", EntryKind::Directory),
+            kind("real/file", EntryKind::File),
+            kind("real/up", EntryKind::Loop),
+        ]
+    );
+    assert_eq!(tree.root().size(), 3 * 4);
+    // Followed links still say what they were reached through.
+    let link = find(tree.root(), "link");
+    assert_eq!(link.symlink_target(), Some(Path::new("real")));
+    assert_eq!(link.link().unwrap().resolved(), Some(&*root.join("real")));
+    let up = find(tree.root(), "link/up");
+    assert_eq!(up.link().unwrap().resolved(), Some(&*root));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn tells_devices_pipes_and_sockets_apart() {
+    let root = temp_dir("scan_special");
+    fs::create_dir_all(&root).unwrap();
+    let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+    // SAFETY: `fifo` is a valid C string.
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    let _socket = UnixListener::bind(root.join("socket")).unwrap();
+    // Followed, the link is recorded as the device it leads to.
+    symlink("/dev/null", root.join("null")).unwrap();
+
+    let tree = TreeBuilder::new(&root)
+        .follow_symlinks(true)
+        .build()
+        .unwrap();
+    assert_eq!(find(tree.root(), "fifo").kind(), EntryKind::Fifo);
+    assert_eq!(find(tree.root(), "socket").kind(), EntryKind::Socket);
+    let null = match find(tree.root(), "null").data() {
+        EntryData::CharDevice(device) => *device,
+        _ => panic!("expected a character device"),
+    };
+    if cfg!(target_os = "linux") {
+        assert_eq!(null, Device { major: 1, minor: 3 });
+    }
+    let rendered = tree.render(&RenderOptions::default()).to_string();
+    assert!(rendered.contains("fifo [fifo]\n"), "{}", rendered);
+    assert!(rendered.contains("socket [socket]\n"), "{}", rendered);
+    assert!(
+        rendered.contains(&format!(
+            "null -> /dev/null [other] [outside root] [char {}:{}]\n",
+            null.major, null.minor
+        )),
+        "{}",
+        rendered
+    );
+    fs::remove_dir_all(&root).unwrap();
+
+    // Device numbers are written in full, whichever their size.
+    let tree = FileTree::from_json(DEVICES_AND_ERRORS).unwrap();
+    let rendered = tree.render(&RenderOptions::default()).to_string();
+    assert!(rendered.contains("sda [block 8:0]\n"), "{}", rendered);
+    assert!(
+        rendered.contains("tty [char 4294967295:1]\n"),
+        "{}",
+        rendered
+    );
+}
+
+#[test]
+fn records_metadata_when_asked_to() {
+    let root = temp_dir("scan_metadata");
+    write_files(&root, &[("dir/file", "file")]);
+    let file_path = root.join("dir/file");
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o4750)).unwrap();
+    fs::set_permissions(root.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+    let modified = UNIX_EPOCH + Duration::new(1_000_000, 500);
+    fs::File::open(&file_path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    let tree = TreeBuilder::new(&root).build().unwrap();
+    assert!(find(tree.root(), "dir/file").metadata().is_none());
+
+    let tree = TreeBuilder::new(&root).metadata(true).build().unwrap();
+    let file = find(tree.root(), "dir/file").metadata().unwrap();
+    let expected = fs::metadata(&file_path).unwrap();
+    assert_eq!(file.mode, expected.mode());
+    assert_eq!(file.mode_string(), "-rwsr-x---");
+    assert_eq!(
+        (file.uid, file.gid, file.inode, file.dev, file.nlink),
+        (
+            expected.uid(),
+            expected.gid(),
+            expected.ino(),
+            expected.dev(),
+            1
+        )
+    );
+    assert_eq!(file.modified, Some(modified));
+    assert_eq!(file.accessed, expected.accessed().ok());
+    let dir = find(tree.root(), "dir").metadata().unwrap();
+    assert_eq!(dir.mode_string(), "drwxr-xr-x");
+    assert_eq!(dir.nlink, 2);
+
+    let options = RenderOptions {
+        columns: vec![Column::Mode, Column::Inode],
+        ..RenderOptions::default()
+    };
+    let rendered = tree.render(&options).to_string();
+    let line = format!("-rwsr-x--- {}  ", file.inode);
+    assert!(rendered.contains(&line), "{}", rendered);
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,112 @@
+//! Trees written and read back through serde, with the `serde` feature.
+
+#![cfg(feature = "serde")]
+
+mod common;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use file_tree::{EntryData, FileTree};
+
+use crate::common::{scanned_tree, DEVICES_AND_ERRORS, NON_UTF8_NAME};
+
+/// Checks that `tree` comes back the same from JSON and from bincode, which
+/// unlike JSON is not human readable.
+fn assert_round_trips(tree: &FileTree) {
+    let json = serde_json::to_string(tree).unwrap();
+    let from_json: FileTree = serde_json::from_str(&json).unwrap();
+    assert_eq!(from_json.to_json(), tree.to_json());
+
+    let bytes = bincode::serialize(tree).unwrap();
+    let from_bincode: FileTree = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(from_bincode.to_json(), tree.to_json());
+}
+
+#[test]
+fn round_trips_a_scanned_tree() {
+    let (root, tree) = scanned_tree("serde");
+    assert_round_trips(&tree);
+
+    let json = serde_json::to_string(&tree).unwrap();
+    let tree: FileTree = serde_json::from_str(&json).unwrap();
+    let children = tree.root().children().unwrap();
+    // Non-UTF-8 names and targets come back byte for byte.
+    let name = OsStr::from_bytes(NON_UTF8_NAME);
+    let dangling = children.iter().find(|e| e.name() == "dangling").unwrap();
+    assert_eq!(dangling.link().unwrap().target(), Path::new(name));
+    let dir = children.iter().find(|e| e.name() == "dir").unwrap();
+    assert_eq!(dir.children().unwrap()[0].name(), name);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn round_trips_devices_and_errors() {
+    let tree = FileTree::from_json(DEVICES_AND_ERRORS).unwrap();
+    assert_round_trips(&tree);
+
+    let json = serde_json::to_string(&tree).unwrap();
+    let tree: FileTree = serde_json::from_str(&json).unwrap();
+    let children = tree.root().children().unwrap();
+    match children[2].data() {
+        EntryData::Error(error) => {
+            assert_eq!(error.io_error().raw_os_error(), Some(13));
+            assert_eq!(error.path(), Path::new("/r/denied"));
+        }
+        _ => panic!("expected an error entry"),
+    }
+    match children[3].data() {
+        EntryData::Error(error) => assert_eq!(error.io_error().to_string(), "something odd"),
+        _ => panic!("expected an error entry"),
+    }
+}
+
+#[test]
+fn writes_utf8_names_as_strings() {
+    let tree = FileTree::from_json(
+        r#"{"version":1,"root_path":"/r","root":{"name":"r","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[]}}"#,
+    )
+    .unwrap();
+    let json = serde_json::to_value(&tree).unwrap();
+    assert_eq!(json["root_path"], "/r");
+    assert_eq!(json["root"][0]["name"], "r");
+}
+
+#[test]
+fn writes_entries_in_sequence() {
+    let tree = FileTree::from_json(
+        r#"{"version":1,"root_path":"/r","root":{"name":"r","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[
+            {"name":"a","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[
+                {"name":"x","kind":"file","size":0,"disk_usage":0,"links":1}
+            ]},
+            {"name":"b","kind":"file","size":0,"disk_usage":0,"links":1}
+        ]}}"#,
+    )
+    .unwrap();
+    let json = serde_json::to_value(&tree).unwrap();
+    let entries = json["root"].as_array().unwrap();
+    let listed: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry["name"].as_str().unwrap(), &entry["data"]))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            ("r", &serde_json::json!({"Directory": 2})),
+            ("a", &serde_json::json!({"Directory": 1})),
+            ("x", &serde_json::json!("File")),
+            ("b", &serde_json::json!("File")),
+        ]
+    );
+
+    // Sequences that end too soon, or go on too long, are not trees.
+    let mut json = json;
+    json["root"].as_array_mut().unwrap().pop();
+    assert!(serde_json::from_value::<FileTree>(json.clone()).is_err());
+    let root = json["root"][0].clone();
+    json["root"].as_array_mut().unwrap().push(root.clone());
+    json["root"].as_array_mut().unwrap().push(root);
+    assert!(serde_json::from_value::<FileTree>(json).is_err());
+}
@@ -0,0 +1,273 @@
+//! Saving trees to snapshots and reading them back.
+
+mod common;
+
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use std::{fs, io};
+
+use file_tree::{
+    EntryData,
+    FileTree,
+    Filter,
+    HardLinks,
+    Pattern,
+    SizeKind,
+    Snapshot,
+    SortKey,
+    SortOrder,
+    TreeBuilder,
+    WalkOptions,
+};
+
+use crate::common::{scanned_tree, temp_dir, DEVICES_AND_ERRORS};
+
+fn options() -> WalkOptions {
+    WalkOptions {
+        max_depth: Some(7),
+        min_entries: Some(1),
+        sort: Some(
+            SortOrder::new(SortKey::Size)
+                .reverse(true)
+                .dirs_first(true)
+                .size_kind(SizeKind::Allocated),
+        ),
+        filter: Filter::new()
+            .include(Pattern::regex("^[a-z]").unwrap().on_path(true))
+            .exclude(Pattern::glob("*.tmp").unwrap()),
+        keep_going: true,
+        hard_links: HardLinks::Split,
+        metadata: true,
+        hash_contents: true,
+        ..WalkOptions::default()
+    }
+}
+
+fn write(snapshot: &Snapshot) -> Vec<u8> {
+    let mut bytes = vec![];
+    snapshot.write(&mut bytes).unwrap();
+    bytes
+}
+
+/// Checks that `tree` and `options` come back the same from a snapshot.
+fn assert_round_trips(tree: FileTree, options: WalkOptions) {
+    let json = tree.to_json();
+    let snapshot = Snapshot::new(tree, options.clone());
+    let read = Snapshot::read(&write(&snapshot)[..]).unwrap();
+    assert_eq!(read.tree().to_json(), json);
+    assert_eq!(read.scanned_at(), snapshot.scanned_at());
+    assert_eq!(format!("{:?}", read.options()), format!("{:?}", options));
+}
+
+#[test]
+fn round_trips_a_scanned_tree() {
+    let (root, tree) = scanned_tree("snapshot");
+    assert_round_trips(tree, options());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn round_trips_devices_and_errors() {
+    let tree = FileTree::from_json(DEVICES_AND_ERRORS).unwrap();
+    assert_round_trips(tree, WalkOptions::default());
+}
+
+/// Writes snapshots the way version 1 did, before content hashes.
+#[derive(Default)]
+struct V1(Vec<u8>);
+
+impl V1 {
+    fn uint(&mut self, mut n: u64) -> &mut Self {
+        while n >= 0x80 {
+            self.0.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.0.push(n as u8);
+        self
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.uint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn flags(&mut self, flags: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(flags);
+        self
+    }
+}
+
+#[test]
+fn reads_version_1() {
+    let mut v1 = V1::default();
+    v1.flags(b"FTSNAP\n\0").uint(1).bytes(b"/r");
+    // Scanned at 1000.5 seconds.
+    v1.uint(2000).uint(500_000_000);
+    // Options: no max depth, hidden files, no one file system, no entry
+    // limits or sort, no patterns, nothing else set, first-seen hard links.
+    v1.flags(&[0, 1, 0, 0, 0, 0]).uint(0).uint(0).flags(&[0; 4]);
+    v1.flags(&[0, 0, 0]);
+    // The root, a directory of two entries, without a modification time,
+    // link or metadata.
+    v1.flags(&[2])
+        .bytes(b"r")
+        .uint(3)
+        .uint(0)
+        .uint(1)
+        .flags(&[0, 0, 0, 0]);
+    v1.uint(2);
+    v1.flags(&[0])
+        .bytes(b"f")
+        .uint(3)
+        .uint(0)
+        .uint(1)
+        .flags(&[0, 0, 0, 0]);
+    // A link to `f`.
+    v1.flags(&[1])
+        .bytes(b"l")
+        .uint(0)
+        .uint(0)
+        .uint(1)
+        .flags(&[0, 0, 1]);
+    v1.bytes(b"f").flags(&[1]).bytes(b"/r/f").flags(&[0, 0, 0]);
+
+    let snapshot = Snapshot::read(&v1.0[..]).unwrap();
+    assert_eq!(
+        snapshot.scanned_at(),
+        UNIX_EPOCH + Duration::from_millis(1_000_500)
+    );
+    assert!(snapshot.options().show_hidden);
+    assert!(!snapshot.options().hash_contents);
+    let tree = snapshot.tree();
+    assert_eq!(tree.root_path(), Path::new("/r"));
+    let children = tree.root().children().unwrap();
+    assert_eq!(tree.root().size(), 3);
+    assert_eq!(children[0].name(), "f");
+    assert_eq!(children[0].content_hash(), None);
+    match children[1].data() {
+        EntryData::Symlink(target) => assert_eq!(target, Path::new("f")),
+        _ => panic!("expected a symlink"),
+    }
+    assert_eq!(
+        children[1].link().unwrap().resolved(),
+        Some(Path::new("/r/f"))
+    );
+}
+
+fn read_error(bytes: &[u8]) -> io::Error {
+    match Snapshot::read(bytes) {
+        Ok(_) => panic!("read {:?}", bytes),
+        Err(error) => error,
+    }
+}
+
+#[test]
+fn fails_on_truncated_input() {
+    let root = temp_dir("snapshot_truncated");
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::write(root.join("dir").join("file"), "file").unwrap();
+    symlink("dir", root.join("link")).unwrap();
+    let tree = TreeBuilder::new(&root)
+        .metadata(true)
+        .hash_contents(true)
+        .build()
+        .unwrap();
+    let bytes = write(&Snapshot::new(tree, options()));
+    for len in 0..bytes.len() {
+        let error = read_error(&bytes[..len]);
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{}", len);
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn fails_on_corrupt_input() {
+    let tree = FileTree::from_json(
+        r#"{"version":1,"root_path":"/r","root":{"name":"r","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[
+            {"name":"f","kind":"file","size":1,"disk_usage":0,"links":1,"modified":{"secs":1,"nanos":2}}
+        ]}}"#,
+    )
+    .unwrap();
+    let bytes = write(&Snapshot::new(tree, options()));
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    assert_eq!(read_error(&magic).kind(), io::ErrorKind::InvalidData);
+    let mut version = bytes.clone();
+    version[8] = 99;
+    assert_eq!(read_error(&version).kind(), io::ErrorKind::InvalidData);
+    let mut too_long = bytes[..9].to_vec();
+    too_long.extend_from_slice(&[0xff; 11]);
+    assert_eq!(read_error(&too_long).kind(), io::ErrorKind::InvalidData);
+    // The length of the root path: u64::MAX, which there are not that many
+    // bytes for, and then one past it.
+    let mut longest = bytes[..9].to_vec();
+    longest.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    assert_eq!(read_error(&longest).kind(), io::ErrorKind::UnexpectedEof);
+    let last = longest.len() - 1;
+    longest[last] = 0x02;
+    let error = read_error(&longest);
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("too large"), "{}", error);
+
+    // Directories nested far deeper than the call stack could follow,
+    // ending in an entry of an unknown kind.
+    let mut nested = V1::default();
+    nested.flags(b"FTSNAP\n\0").uint(1).bytes(b"/r");
+    nested.uint(0).uint(0);
+    nested.flags(&[0; 6]).uint(0).uint(0).flags(&[0; 7]);
+    for _ in 0..100_000 {
+        nested
+            .flags(&[2])
+            .bytes(b"d")
+            .uint(0)
+            .uint(0)
+            .uint(1)
+            .flags(&[0; 4])
+            .uint(1);
+    }
+    nested
+        .flags(&[0xff])
+        .bytes(b"x")
+        .uint(0)
+        .uint(0)
+        .uint(1)
+        .flags(&[0; 4]);
+    assert_eq!(read_error(&nested.0).kind(), io::ErrorKind::InvalidData);
+    let eof = &nested.0[..nested.0.len() / 2];
+    assert_eq!(read_error(eof).kind(), io::ErrorKind::UnexpectedEof);
+
+    // An error code past what an `i32` holds.
+    let errors = write(&Snapshot::new(
+        FileTree::from_json(DEVICES_AND_ERRORS).unwrap(),
+        WalkOptions::default(),
+    ));
+    let path = b"/r/denied";
+    let at = errors.windows(path.len()).position(|w| w == path).unwrap() + path.len();
+    // The depth, that there is a code, and 13 as a zigzag varint.
+    assert_eq!(errors[at..at + 3], [1, 1, 26]);
+    let with_code = |code: i64| {
+        let mut corrupt = errors[..at + 2].to_vec();
+        corrupt.extend_from_slice(&V1::default().uint(((code << 1) ^ (code >> 63)) as u64).0);
+        corrupt.extend_from_slice(&errors[at + 3..]);
+        corrupt
+    };
+    let snapshot = Snapshot::read(&with_code(i32::MIN.into())[..]).unwrap();
+    let error = snapshot.tree().errors()[0].io_error().raw_os_error();
+    assert_eq!(error, Some(i32::MIN));
+    let error = read_error(&with_code(i64::from(i32::MAX) + 1));
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("error code"), "{}", error);
+
+    // Every other corruption either reads as some tree or fails, but never
+    // panics.
+    for i in 0..bytes.len() {
+        for &byte in &[0, 1, 2, 0x7f, 0x80, 0xff] {
+            let mut corrupt = bytes.clone();
+            corrupt[i] = byte;
+            let _ = Snapshot::read(&corrupt[..]);
+        }
+    }
+}
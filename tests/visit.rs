@@ -0,0 +1,235 @@
+//! Walking a tree that has been scanned, with iterators and visitors.
+
+use std::path::{Path, PathBuf};
+
+use file_tree::{Entry, FileTree, Visit, Visitor, VisitorMut};
+
+fn dir(name: &str, children: &[String]) -> String {
+    format!(
+        r#"{{"name":"{}","kind":"directory","size":0,"disk_usage":0,"links":1,"children":[{}]}}"#,
+        name,
+        children.join(",")
+    )
+}
+
+fn file(name: &str) -> String {
+    format!(
+        r#"{{"name":"{}","kind":"file","size":1,"disk_usage":0,"links":1}}"#,
+        name
+    )
+}
+
+/// A tree at `/t` holding `a/x`, `a/y/z`, `b` and an empty `c`.
+fn tree() -> FileTree {
+    let y = dir("y", &[file("z")]);
+    let a = dir("a", &[file("x"), y]);
+    let root = dir("t", &[a, file("b"), dir("c", &[])]);
+    FileTree::from_json(&format!(
+        r#"{{"version":1,"root_path":"/t","root":{}}}"#,
+        root
+    ))
+    .unwrap()
+}
+
+fn listed<'a>(entries: impl Iterator<Item = (PathBuf, usize, &'a Entry)>) -> Vec<String> {
+    entries
+        .map(|(path, depth, _)| format!("{} {}", depth, path.display()))
+        .collect()
+}
+
+#[test]
+fn iterates_depth_first() {
+    let tree = tree();
+    assert_eq!(
+        listed(tree.depth_first()),
+        [
+            "0 /t",
+            "1 /t/a",
+            "2 /t/a/x",
+            "2 /t/a/y",
+            "3 /t/a/y/z",
+            "1 /t/b",
+            "1 /t/c"
+        ]
+    );
+    let a = &tree.root().children().unwrap()[0];
+    assert_eq!(listed(a.depth_first()), ["0 ", "1 x", "1 y", "2 y/z"]);
+
+    let mut entries = tree.depth_first();
+    let mut seen = vec![];
+    while let Some((path, _, entry)) = entries.next() {
+        if entry.name() == "a" {
+            entries.skip_subtree();
+        }
+        seen.push(path);
+    }
+    assert_eq!(seen, ["/t", "/t/a", "/t/b", "/t/c"].map(PathBuf::from));
+}
+
+#[test]
+fn iterates_breadth_first() {
+    let tree = tree();
+    assert_eq!(
+        listed(tree.breadth_first()),
+        [
+            "0 /t",
+            "1 /t/a",
+            "1 /t/b",
+            "1 /t/c",
+            "2 /t/a/x",
+            "2 /t/a/y",
+            "3 /t/a/y/z"
+        ]
+    );
+
+    let mut entries = tree.breadth_first();
+    let mut seen = vec![];
+    while let Some((path, _, entry)) = entries.next() {
+        if entry.name() == "y" {
+            entries.skip_subtree();
+        }
+        seen.push(path);
+    }
+    assert_eq!(seen.last().unwrap(), Path::new("/t/a/y"));
+}
+
+/// Records every callback, skipping and stopping at the given names.
+#[derive(Default)]
+struct Recorder {
+    calls: Vec<String>,
+    skip: &'static str,
+    stop: &'static str,
+}
+
+impl Recorder {
+    fn record(&mut self, call: &str, path: &Path, depth: usize, entry: &Entry) -> Visit {
+        self.calls
+            .push(format!("{} {} {}", call, depth, path.display()));
+        if entry.name() == self.stop {
+            Visit::Stop
+        } else if entry.name() == self.skip {
+            Visit::SkipSubtree
+        } else {
+            Visit::Continue
+        }
+    }
+}
+
+impl Visitor for Recorder {
+    fn enter(&mut self, path: &Path, depth: usize, entry: &Entry) -> Visit {
+        self.record("enter", path, depth, entry)
+    }
+
+    fn leave(&mut self, path: &Path, depth: usize, entry: &Entry) -> Visit {
+        self.record("leave", path, depth, entry)
+    }
+
+    fn leaf(&mut self, path: &Path, depth: usize, entry: &Entry) -> Visit {
+        self.record("leaf", path, depth, entry)
+    }
+}
+
+#[test]
+fn visits_with_enter_leave_and_leaf() {
+    let tree = tree();
+    let mut recorder = Recorder::default();
+    tree.visit(&mut recorder);
+    assert_eq!(
+        recorder.calls,
+        [
+            "enter 0 /t",
+            "enter 1 /t/a",
+            "leaf 2 /t/a/x",
+            "enter 2 /t/a/y",
+            "leaf 3 /t/a/y/z",
+            "leave 2 /t/a/y",
+            "leave 1 /t/a",
+            "leaf 1 /t/b",
+            "enter 1 /t/c",
+            "leave 1 /t/c",
+            "leave 0 /t"
+        ]
+    );
+
+    let mut recorder = Recorder {
+        skip: "a",
+        stop: "b",
+        ..Recorder::default()
+    };
+    tree.visit(&mut recorder);
+    assert_eq!(
+        recorder.calls,
+        ["enter 0 /t", "enter 1 /t/a", "leave 1 /t/a", "leaf 1 /t/b"]
+    );
+}
+
+/// Drops the entries named `drop` from each directory it enters, and
+/// records the rest.
+struct Pruner {
+    drop: &'static str,
+    recorder: Recorder,
+}
+
+impl VisitorMut for Pruner {
+    fn enter(&mut self, path: &Path, depth: usize, entry: &mut Entry) -> Visit {
+        let drop = self.drop;
+        entry
+            .children_mut()
+            .unwrap()
+            .retain(|child| child.name() != drop);
+        self.recorder.record("enter", path, depth, entry)
+    }
+
+    fn leave(&mut self, path: &Path, depth: usize, entry: &mut Entry) -> Visit {
+        self.recorder.record("leave", path, depth, entry)
+    }
+
+    fn leaf(&mut self, path: &Path, depth: usize, entry: &mut Entry) -> Visit {
+        self.recorder.record("leaf", path, depth, entry)
+    }
+}
+
+#[test]
+fn changes_entries_while_visiting() {
+    let mut tree = tree();
+    let mut pruner = Pruner {
+        drop: "y",
+        recorder: Recorder::default(),
+    };
+    tree.visit_mut(&mut pruner);
+    assert_eq!(
+        pruner.recorder.calls,
+        [
+            "enter 0 /t",
+            "enter 1 /t/a",
+            "leaf 2 /t/a/x",
+            "leave 1 /t/a",
+            "leaf 1 /t/b",
+            "enter 1 /t/c",
+            "leave 1 /t/c",
+            "leave 0 /t"
+        ]
+    );
+    assert_eq!(
+        listed(tree.depth_first()),
+        ["0 /t", "1 /t/a", "2 /t/a/x", "1 /t/b", "1 /t/c"]
+    );
+}
+
+#[test]
+fn leaves_the_tree_whole_when_stopped() {
+    let mut tree = tree();
+    let before = tree.to_json();
+    for (skip, stop) in [("", "x"), ("", "z"), ("a", "b"), ("", "c"), ("", "t")] {
+        let mut pruner = Pruner {
+            drop: "",
+            recorder: Recorder {
+                skip,
+                stop,
+                ..Recorder::default()
+            },
+        };
+        tree.visit_mut(&mut pruner);
+        assert_eq!(tree.to_json(), before);
+    }
+}